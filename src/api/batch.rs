@@ -0,0 +1,572 @@
+use log::*; // logging
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+use crate::api::openai::ChatRequest;
+
+/// Endpoint every request in a batch is sent to
+pub const BATCH_ENDPOINT: &str = "/v1/chat/completions";
+
+/// # Batch Job
+///
+/// A job submitted to the provider's Batch API, as returned by `GET /batches/{id}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BatchJob {
+    pub id: String,
+    pub status: String,
+    #[serde(default)]
+    pub input_file_id: Option<String>,
+    #[serde(default)]
+    pub output_file_id: Option<String>,
+    #[serde(default)]
+    pub error_file_id: Option<String>,
+    #[serde(default)]
+    pub request_counts: Option<RequestCounts>,
+    #[serde(default)]
+    pub errors: Option<BatchErrors>,
+}
+
+/// Progress through the requests of a [BatchJob]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RequestCounts {
+    pub total: u64,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+/// Why a [BatchJob] failed as a whole, such as input lines that didn't validate
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BatchErrors {
+    #[serde(default)]
+    pub data: Vec<BatchError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BatchError {
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub line: Option<u64>,
+}
+
+impl BatchJob {
+    /// Whether the job has stopped, successfully or not. Expired and cancelled jobs may still
+    /// have results for some requests.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status.as_str(),
+            "completed" | "failed" | "expired" | "cancelled"
+        )
+    }
+
+    /// # Missing Result
+    ///
+    /// The error recorded for a request the job returned no result for. Unless the job
+    /// completed, its errors are added to say why.
+    pub fn missing_result(&self) -> String {
+        let mut message = format!("no result returned by batch {} ({})", self.id, self.status);
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .flat_map(|errors| &errors.data)
+            .map(|error| match error.line {
+                Some(line) => format!("line {}: {}", line, error.message),
+                None => error.message.clone(),
+            })
+            .collect();
+        if self.status != "completed" && !errors.is_empty() {
+            message += &format!(": {}", errors.join("; "));
+        }
+        message
+    }
+}
+
+/// # Batch Input File
+///
+/// One JSONL line per request, with the id as `custom_id` so results can be matched back
+pub fn batch_input_jsonl(requests: &[(String, ChatRequest)]) -> String {
+    requests
+        .iter()
+        .map(|(id, request)| {
+            json!({
+                "custom_id": id,
+                "method": "POST",
+                "url": BATCH_ENDPOINT,
+                "body": request,
+            })
+            .to_string()
+                + "\n"
+        })
+        .collect()
+}
+
+/// Reads the body of a response as JSON, turning error statuses into the API's error message.
+/// An error body that isn't JSON, such as a proxy's 502 page, is kept as text.
+async fn json_or_error(response: reqwest::Response) -> Result<Value, String> {
+    let status = response.status();
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        let message = serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|body| body["error"]["message"].as_str().map(String::from))
+            .unwrap_or(text);
+        return Err(format!(
+            "the Batch API returned {}: {}",
+            status,
+            message.trim()
+        ));
+    }
+    serde_json::from_str(&text).map_err(|e| format!("the Batch API returned invalid JSON: {}", e))
+}
+
+/// # Submit Batch
+///
+/// Uploads the requests as a batch input file and creates a job for them, completed within
+/// 24 hours
+pub async fn submit_batch(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    requests: &[(String, ChatRequest)],
+) -> Result<BatchJob, String> {
+    let part = reqwest::multipart::Part::bytes(batch_input_jsonl(requests).into_bytes())
+        .file_name("batch.jsonl")
+        .mime_str("application/jsonl")
+        .map_err(|e| e.to_string())?;
+    let form = reqwest::multipart::Form::new()
+        .text("purpose", "batch")
+        .part("file", part);
+    let file = json_or_error(
+        http.post(format!("{}files", api_url))
+            .bearer_auth(api_key)
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?,
+    )
+    .await?;
+    let file_id = file["id"]
+        .as_str()
+        .ok_or("the Batch API did not return a file id")?;
+    debug!("uploaded batch input file {}", file_id);
+
+    let job = json_or_error(
+        http.post(format!("{}batches", api_url))
+            .bearer_auth(api_key)
+            .json(&json!({
+                "input_file_id": file_id,
+                "endpoint": BATCH_ENDPOINT,
+                "completion_window": "24h",
+            }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?,
+    )
+    .await?;
+    serde_json::from_value(job).map_err(|e| format!("unexpected batch job: {}", e))
+}
+
+/// # Get Batch
+///
+/// The current state of a job
+pub async fn get_batch(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    batch_id: &str,
+) -> Result<BatchJob, String> {
+    let job = json_or_error(
+        http.get(format!("{}batches/{}", api_url, batch_id))
+            .bearer_auth(api_key)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?,
+    )
+    .await?;
+    serde_json::from_value(job).map_err(|e| format!("unexpected batch job: {}", e))
+}
+
+/// Downloads the contents of a file, such as a job's output
+async fn download_file(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    file_id: &str,
+) -> Result<String, String> {
+    let response = http
+        .get(format!("{}files/{}/content", api_url, file_id))
+        .bearer_auth(api_key)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "could not download batch file {}: {}",
+            file_id,
+            response.status()
+        ));
+    }
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// # Parse Batch Output
+///
+/// Splits output or error file lines back into a result per `custom_id`: the reply text, or
+/// why that request failed
+pub fn parse_batch_output(jsonl: &str) -> Vec<(String, Result<String, String>)> {
+    jsonl
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|line| {
+            let id = line["custom_id"].as_str()?.to_string();
+            let response = &line["response"];
+            let result = if !line["error"].is_null() {
+                Err(line["error"]["message"]
+                    .as_str()
+                    .unwrap_or(&line["error"].to_string())
+                    .to_string())
+            } else if response["status_code"] != 200 {
+                Err(format!(
+                    "status {}: {}",
+                    response["status_code"],
+                    response["body"]["error"]["message"]
+                        .as_str()
+                        .unwrap_or("unknown error")
+                ))
+            } else {
+                response["body"]["choices"][0]["message"]["content"]
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| "the reply contained no text".to_string())
+            };
+            Some((id, result))
+        })
+        .collect()
+}
+
+/// # Batch Input Ids
+///
+/// The `custom_id` of every request in a job, read back from its input file
+pub async fn batch_input_ids(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    job: &BatchJob,
+) -> Result<Vec<String>, String> {
+    let file_id = job
+        .input_file_id
+        .as_deref()
+        .ok_or_else(|| format!("batch {} has no input file", job.id))?;
+    Ok(download_file(http, api_url, api_key, file_id)
+        .await?
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|line| line["custom_id"].as_str().map(String::from))
+        .collect())
+}
+
+/// # Add Missing Results
+///
+/// Adds an error for each id the job returned no result for, as a failed job returns none and
+/// an expired or cancelled one may leave some out
+pub fn add_missing_results(
+    job: &BatchJob,
+    ids: &[String],
+    mut results: Vec<(String, Result<String, String>)>,
+) -> Vec<(String, Result<String, String>)> {
+    let returned: HashSet<String> = results.iter().map(|(id, _)| id.clone()).collect();
+    for id in ids {
+        if !returned.contains(id) {
+            results.push((id.clone(), Err(job.missing_result())));
+        }
+    }
+    results
+}
+
+/// # Download Batch Results
+///
+/// Fetches the output and error files of a finished job and parses both
+pub async fn download_results(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    job: &BatchJob,
+) -> Result<Vec<(String, Result<String, String>)>, String> {
+    let mut results = vec![];
+    for file_id in [&job.output_file_id, &job.error_file_id]
+        .into_iter()
+        .flatten()
+    {
+        let jsonl = download_file(http, api_url, api_key, file_id).await?;
+        results.extend(parse_batch_output(&jsonl));
+    }
+    Ok(results)
+}
+
+#[cfg(any(test, doc))]
+pub mod tests {
+    use super::*;
+    use crate::api::openai::{Message, Role};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// # Mock Batch API
+    ///
+    /// Serves the files and batches endpoints on a local port. The uploaded requests are
+    /// answered in the output file, except ids starting with `bad` which go in the error file.
+    /// The job is `in_progress` for the first poll, then `completed`. Returns the API URL.
+    pub fn mock_batch_api() -> String {
+        mock_batch_api_ending("completed")
+    }
+
+    /// # Mock Batch API Ending In
+    ///
+    /// As [mock_batch_api], with the job finishing in `status` instead. A `failed` job has no
+    /// output or error file, only the job's errors, and an `expired` or `cancelled` one returns
+    /// nothing for ids starting with `late`.
+    pub fn mock_batch_api_ending(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}/v1/", listener.local_addr().unwrap());
+        let uploaded = Arc::new(Mutex::new(String::new()));
+        let polls = Arc::new(Mutex::new(0));
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(length) = header.to_lowercase().strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8_lossy(&body).to_string();
+
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                let reply = match path {
+                    "/v1/files" => {
+                        *uploaded.lock().unwrap() = body;
+                        json!({"id": "file-input"}).to_string()
+                    },
+                    "/v1/batches" => json!({"id": "batch_1", "status": "validating"}).to_string(),
+                    "/v1/batches/batch_1" => {
+                        let mut polls = polls.lock().unwrap();
+                        *polls += 1;
+                        if *polls == 1 {
+                            json!({"id": "batch_1", "status": "in_progress", "input_file_id": "file-input"}).to_string()
+                        } else if status == "failed" {
+                            json!({
+                                "id": "batch_1",
+                                "status": "failed",
+                                "input_file_id": "file-input",
+                                "errors": {"object": "list", "data": [{"code": "invalid_request", "message": "model is required", "line": 1}]}
+                            })
+                            .to_string()
+                        } else {
+                            json!({
+                                "id": "batch_1",
+                                "status": status,
+                                "input_file_id": "file-input",
+                                "output_file_id": "file-output",
+                                "error_file_id": "file-errors",
+                                "request_counts": {"total": 2, "completed": 1, "failed": 1}
+                            })
+                            .to_string()
+                        }
+                    },
+                    "/v1/files/file-input/content" => uploaded
+                        .lock()
+                        .unwrap()
+                        .lines()
+                        .filter(|line| line.starts_with('{'))
+                        .map(|line| line.to_string() + "\n")
+                        .collect(),
+                    "/v1/files/file-output/content" | "/v1/files/file-errors/content" => {
+                        let errors = path.contains("errors");
+                        uploaded
+                            .lock()
+                            .unwrap()
+                            .lines()
+                            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+                            .filter_map(|line| line["custom_id"].as_str().map(String::from))
+                            .filter(|id| id.starts_with("bad") == errors)
+                            .filter(|id| status == "completed" || !id.starts_with("late"))
+                            .map(|id| {
+                                let line = if errors {
+                                    json!({"custom_id": id, "response": {"status_code": 400, "body": {"error": {"message": "bad request"}}}, "error": null})
+                                } else {
+                                    json!({"custom_id": id, "response": {"status_code": 200, "body": {"choices": [{"message": {"role": "assistant", "content": format!("reply to {}", id)}}]}}, "error": null})
+                                };
+                                line.to_string() + "\n"
+                            })
+                            .collect()
+                    },
+                    // as a proxy in front of the API might answer
+                    "/v1/batches/batch_502" => {
+                        "<html><body>502 Bad Gateway</body></html>".to_string()
+                    },
+                    _ => json!({"error": {"message": "not found"}}).to_string(),
+                };
+                let (status, content_type) = if reply.starts_with("<html>") {
+                    ("502 Bad Gateway", "text/html")
+                } else if reply.contains("not found") {
+                    ("404 Not Found", "application/json")
+                } else {
+                    ("200 OK", "application/json")
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    reply.len(),
+                    reply
+                )
+                .unwrap();
+            }
+        });
+        api_url
+    }
+
+    fn request(input: &str) -> ChatRequest {
+        ChatRequest {
+            model: "gpt-4o".to_string(),
+            messages: vec![Message::new(Role::User, input)],
+            temperature: None,
+            top_p: None,
+            n: None,
+            stream: None,
+            stop: None,
+            max_tokens: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            user: None,
+            seed: None,
+            response_format: None,
+            tools: None,
+        }
+    }
+
+    /// Test that each request becomes a line with its id
+    #[cfg_attr(not(doc), test)]
+    fn test_batch_input_jsonl() {
+        let jsonl = batch_input_jsonl(&[("a.rs".to_string(), request("hello"))]);
+        let line: Value = serde_json::from_str(jsonl.trim()).unwrap();
+
+        assert_eq!(line["custom_id"], "a.rs");
+        assert_eq!(line["url"], BATCH_ENDPOINT);
+        assert_eq!(line["body"]["messages"][0]["content"], "hello");
+    }
+
+    /// Test that failed requests in the output are reported against their id
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_batch_output() {
+        let jsonl = concat!(
+            r#"{"custom_id": "a", "response": {"status_code": 200, "body": {"choices": [{"message": {"content": "ok"}}]}}, "error": null}"#,
+            "\n",
+            r#"{"custom_id": "b", "response": {"status_code": 429, "body": {"error": {"message": "rate limited"}}}, "error": null}"#,
+            "\n",
+            r#"{"custom_id": "c", "response": null, "error": {"code": "expired", "message": "batch expired"}}"#,
+        );
+        assert_eq!(
+            parse_batch_output(jsonl),
+            vec![
+                ("a".to_string(), Ok("ok".to_string())),
+                ("b".to_string(), Err("status 429: rate limited".to_string())),
+                ("c".to_string(), Err("batch expired".to_string())),
+            ]
+        );
+    }
+
+    /// Test that ids without a result get an error, naming the job's errors unless it completed
+    #[cfg_attr(not(doc), test)]
+    fn test_add_missing_results() {
+        let mut job: BatchJob = serde_json::from_value(json!({
+            "id": "batch_1",
+            "status": "completed",
+            "errors": {"data": [{"message": "quota exceeded"}]}
+        }))
+        .unwrap();
+        let ids = ["a".to_string(), "b".to_string()];
+        let results = vec![("a".to_string(), Ok("ok".to_string()))];
+
+        assert_eq!(
+            add_missing_results(&job, &ids, results.clone()),
+            vec![
+                ("a".to_string(), Ok("ok".to_string())),
+                (
+                    "b".to_string(),
+                    Err("no result returned by batch batch_1 (completed)".to_string())
+                ),
+            ]
+        );
+        job.status = "cancelled".to_string();
+        assert_eq!(
+            add_missing_results(&job, &ids, results)[1].1,
+            Err("no result returned by batch batch_1 (cancelled): quota exceeded".to_string())
+        );
+    }
+
+    /// Test submitting, polling and downloading against the mock Batch API
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_batch_round_trip() {
+        let api_url = mock_batch_api();
+        let http = reqwest::Client::new();
+        let requests = vec![
+            ("good".to_string(), request("one")),
+            ("bad".to_string(), request("two")),
+        ];
+
+        let job = submit_batch(&http, &api_url, "key", &requests)
+            .await
+            .unwrap();
+        assert_eq!(job.id, "batch_1");
+        assert!(!job.is_finished());
+
+        assert!(!get_batch(&http, &api_url, "key", "batch_1")
+            .await
+            .unwrap()
+            .is_finished());
+        let job = get_batch(&http, &api_url, "key", "batch_1").await.unwrap();
+        assert!(job.is_finished());
+        assert_eq!(job.request_counts.as_ref().unwrap().failed, 1);
+
+        let results = download_results(&http, &api_url, "key", &job)
+            .await
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                ("good".to_string(), Ok("reply to good".to_string())),
+                (
+                    "bad".to_string(),
+                    Err("status 400: bad request".to_string())
+                ),
+            ]
+        );
+
+        assert!(get_batch(&http, &api_url, "key", "missing")
+            .await
+            .unwrap_err()
+            .contains("not found"));
+        assert_eq!(
+            get_batch(&http, &api_url, "key", "batch_502")
+                .await
+                .unwrap_err(),
+            "the Batch API returned 502 Bad Gateway: <html><body>502 Bad Gateway</body></html>"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::fmt;
+
+use crate::api::embeddings::post_embeddings;
+use crate::api::openai::{
+    build_http_client, http_client, post_chat, ChatRequest, Message, ReplyMessage, ResponseFormat,
+    Role,
+};
+use crate::config::models::{load_config, AppConfig};
+
+/// # Library Error
+///
+/// Everything that can go wrong sending a request through [Client]
+#[derive(Debug)]
+pub enum Error {
+    /// The AI_API_KEY environment variable is not set
+    MissingApiKey,
+    /// A parameter is out of range, or the request has no messages
+    InvalidRequest(String),
+    /// The API could not be reached or returned an error status
+    Http(reqwest::Error),
+    /// The API replied without any text, for example because it asked for a tool call
+    EmptyReply,
+    /// The API's reply doesn't match the request, such as too few embeddings for the inputs
+    UnexpectedReply(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingApiKey => write!(f, "the AI_API_KEY environment variable is not set"),
+            Error::InvalidRequest(e) => write!(f, "invalid request: {}", e),
+            Error::Http(e) => write!(f, "request failed: {}", e),
+            Error::EmptyReply => write!(f, "the reply contained no text"),
+            Error::UnexpectedReply(e) => write!(f, "unexpected reply: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+/// # Client
+///
+/// The API endpoint, key and defaults used to send [Request]s. Cheap to share, as the
+/// underlying connection pool is reused between requests.
+#[derive(Debug, Clone)]
+pub struct Client {
+    config: AppConfig,
+    api_key: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// # Client From Config
+    ///
+    /// Uses `~/.config/pipe-gpt/config.yaml`, as the command line does, and the AI_API_KEY
+    /// environment variable. Clients made this way share one connection pool.
+    pub fn from_config() -> Result<Self, Error> {
+        let api_key = std::env::var("AI_API_KEY").map_err(|_| Error::MissingApiKey)?;
+        Ok(Client {
+            config: load_config(),
+            api_key,
+            http: http_client().clone(),
+        })
+    }
+
+    /// A client with the given config and API key, for when neither comes from the environment.
+    /// Timeouts are taken from the config.
+    pub fn new(config: AppConfig, api_key: impl Into<String>) -> Self {
+        Client {
+            http: build_http_client(&config),
+            config,
+            api_key: api_key.into(),
+        }
+    }
+
+    /// The config requests fall back to
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    /// # Send Chat Request
+    ///
+    /// Sends a request body as it is and returns the assistant message, which may ask for tool
+    /// calls instead of giving an answer
+    pub async fn chat(&self, body: &ChatRequest) -> Result<ReplyMessage, Error> {
+        post_chat(&self.http, &self.config.api_url, &self.api_key, body).await
+    }
+
+    /// # Embed Inputs
+    ///
+    /// Returns one vector per input from the configured `embedding_model`, in the same order
+    pub async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+        post_embeddings(
+            &self.http,
+            &self.config.api_url,
+            &self.api_key,
+            &self.config.embedding_model,
+            inputs,
+        )
+        .await
+    }
+}
+
+/// # Request
+///
+/// Builds a conversation and sends it, with sampling parameters defaulting to the client's config
+///
+/// ```no_run
+/// # async fn example() -> Result<(), pipe_gpt::Error> {
+/// use pipe_gpt::{Client, Request};
+///
+/// let client = Client::from_config()?;
+/// let reply = Request::new()
+///     .system("You are a terse assistant.")
+///     .user("Summarise this changelog: ...")
+///     .temperature(0.2)
+///     .send(&client)
+///     .await?;
+/// println!("{}", reply);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    messages: Vec<Message>,
+    model: Option<String>,
+    max_tokens: Option<i32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<i64>,
+    json: bool,
+}
+
+impl Request {
+    pub fn new() -> Self {
+        Request::default()
+    }
+
+    /// Adds a system message
+    pub fn system(mut self, content: impl Into<String>) -> Self {
+        self.messages
+            .push(Message::new(Role::System, content.into()));
+        self
+    }
+
+    /// Adds a user message
+    pub fn user(mut self, content: impl Into<String>) -> Self {
+        self.messages.push(Message::new(Role::User, content.into()));
+        self
+    }
+
+    /// Adds an earlier assistant reply, to continue a conversation
+    pub fn assistant(mut self, content: impl Into<String>) -> Self {
+        self.messages
+            .push(Message::new(Role::Assistant, content.into()));
+        self
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: i32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Asks for a JSON object. The conversation must mention JSON, as the API requires.
+    pub fn json(mut self) -> Self {
+        self.json = true;
+        self
+    }
+
+    /// # Build Chat Request
+    ///
+    /// The request body that [Request::send] posts, with the config filling in anything unset
+    pub fn build(&self, config: &AppConfig) -> Result<ChatRequest, Error> {
+        if self.messages.is_empty() {
+            return Err(Error::InvalidRequest(
+                "the request has no messages".to_string(),
+            ));
+        }
+        let chat_request = ChatRequest {
+            model: self.model.clone().unwrap_or_else(|| config.model.clone()),
+            messages: self.messages.clone(),
+            temperature: Some(self.temperature.unwrap_or(config.temperature)),
+            top_p: Some(self.top_p.unwrap_or(config.top_p)),
+            n: Some(1),
+            stream: Some(false),
+            stop: config.stop.clone(),
+            max_tokens: Some(self.max_tokens.unwrap_or(config.max_tokens)),
+            presence_penalty: config.presence_penalty,
+            frequency_penalty: config.frequency_penalty,
+            logit_bias: config.logit_bias.clone(),
+            user: config.user.clone(),
+            seed: self.seed.or(config.seed),
+            response_format: if self.json {
+                Some(ResponseFormat::JsonObject)
+            } else {
+                None
+            },
+            tools: None,
+        };
+        chat_request.validate().map_err(Error::InvalidRequest)?;
+        Ok(chat_request)
+    }
+
+    /// # Send Request
+    ///
+    /// Sends the conversation and returns the text of the reply
+    pub async fn send(&self, client: &Client) -> Result<String, Error> {
+        let chat_request = self.build(&client.config)?;
+        let reply = client.chat(&chat_request).await?;
+        reply.content.ok_or(Error::EmptyReply)
+    }
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// Test that the builder fills in the config and checks its parameters
+    #[cfg_attr(not(doc), test)]
+    fn test_request_build() {
+        let config = AppConfig::default();
+        let request = Request::new()
+            .system("Be brief.")
+            .user("Hi")
+            .assistant("Hello")
+            .user("Reply in JSON")
+            .seed(3)
+            .json()
+            .build(&config)
+            .unwrap();
+
+        assert_eq!(request.model, config.model);
+        assert_eq!(request.temperature, Some(config.temperature));
+        assert_eq!(request.seed, Some(3));
+        assert_eq!(request.response_format, Some(ResponseFormat::JsonObject));
+        assert_eq!(
+            request
+                .messages
+                .iter()
+                .map(|message| message.role)
+                .collect::<Vec<Role>>(),
+            vec![Role::System, Role::User, Role::Assistant, Role::User]
+        );
+
+        assert!(matches!(
+            Request::new().build(&config),
+            Err(Error::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            Request::new().user("Hi").temperature(3.0).build(&config),
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+use log::*; // logging
+use serde::{Deserialize, Serialize};
+
+use crate::api::client::{Client, Error};
+
+/// Most inputs sent in one embeddings request
+pub const EMBEDDING_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// # Post Embeddings Request
+///
+/// Sends the inputs to the embeddings endpoint under `api_url` and returns one vector per
+/// input, in the same order. A reply that doesn't have exactly one vector for each input is an
+/// error, as the vectors could no longer be matched to their inputs.
+pub async fn post_embeddings(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    model: &str,
+    inputs: &[String],
+) -> Result<Vec<Vec<f32>>, Error> {
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    for batch in inputs.chunks(EMBEDDING_BATCH_SIZE) {
+        let mut response: EmbeddingResponse = http
+            .post(format!("{}embeddings", api_url))
+            .bearer_auth(api_key)
+            .json(&EmbeddingRequest {
+                model,
+                input: batch,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        response.data.sort_by_key(|data| data.index);
+        debug!("received {} embedding(s)", response.data.len());
+        if response.data.len() != batch.len()
+            || response
+                .data
+                .iter()
+                .enumerate()
+                .any(|(position, data)| data.index != position)
+        {
+            return Err(Error::UnexpectedReply(format!(
+                "the embeddings endpoint returned {} vector(s) for {} input(s)",
+                response.data.len(),
+                batch.len()
+            )));
+        }
+        embeddings.extend(response.data.into_iter().map(|data| data.embedding));
+    }
+    Ok(embeddings)
+}
+
+/// # Send Embeddings Request
+///
+/// Embeds the inputs with a [Client] from the config and AI_API_KEY environment variable, using
+/// the configured `embedding_model`
+pub async fn send_embeddings(inputs: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+    Client::from_config()?.embed(inputs).await
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Serves one embeddings reply on a local port and returns the API URL
+    fn mock_embeddings_api(reply: serde_json::Value) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(length) = header.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+            }
+            reader.read_exact(&mut vec![0; content_length]).unwrap();
+            let reply = reply.to_string();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.len(),
+                reply
+            )
+            .unwrap();
+        });
+        api_url
+    }
+
+    /// Test that vectors come back in input order, and that a reply missing one is an error
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_post_embeddings() {
+        let http = reqwest::Client::new();
+        let inputs = ["a".to_string(), "b".to_string()];
+
+        let api_url = mock_embeddings_api(serde_json::json!({"data": [
+            {"index": 1, "embedding": [1.0]},
+            {"index": 0, "embedding": [0.0]},
+        ]}));
+        assert_eq!(
+            post_embeddings(&http, &api_url, "key", "model", &inputs)
+                .await
+                .unwrap(),
+            vec![vec![0.0], vec![1.0]]
+        );
+
+        for data in [
+            serde_json::json!([{"index": 0, "embedding": [0.0]}]),
+            serde_json::json!([{"index": 0, "embedding": [0.0]}, {"index": 0, "embedding": [0.0]}]),
+        ] {
+            let api_url = mock_embeddings_api(serde_json::json!({ "data": data }));
+            let error = post_embeddings(&http, &api_url, "key", "model", &inputs)
+                .await
+                .unwrap_err();
+            assert!(matches!(error, Error::UnexpectedReply(_)));
+        }
+    }
+}
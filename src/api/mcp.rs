@@ -0,0 +1,364 @@
+use log::*; // logging
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::models::McpServerConfig;
+
+/// MCP protocol version sent in the initialize handshake
+pub const MCP_PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How long to wait for a server to answer a request
+const MCP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// # MCP Tool
+///
+/// A tool listed by an MCP server. It is offered to the model as `server__tool` so tools from
+/// different servers can't clash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct McpTool {
+    pub server: String,
+    pub name: String,
+    pub qualified_name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+/// A resource listed by an MCP server
+#[derive(Debug, Clone, PartialEq)]
+pub struct McpResource {
+    pub uri: String,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// # MCP Client
+///
+/// A connection to a Model Context Protocol server started as a child process, speaking
+/// newline-delimited JSON-RPC over its stdin and stdout. The server is killed when the client
+/// is dropped.
+pub struct McpClient {
+    pub name: String,
+    child: Mutex<Child>,
+    stdin: Mutex<ChildStdin>,
+    messages: Mutex<Receiver<Value>>,
+    next_id: Mutex<u64>,
+    capabilities: Value,
+}
+
+impl McpClient {
+    /// # Connect To Server
+    ///
+    /// Starts the server and completes the initialize handshake
+    pub fn connect(config: &McpServerConfig) -> Result<Self, String> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("could not start MCP server '{}': {}", config.name, e))?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        // read on threads so a silent server can be timed out rather than hang the request
+        let (sender, receiver) = mpsc::channel();
+        let server = config.name.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                match serde_json::from_str::<Value>(&line) {
+                    Ok(message) => {
+                        if sender.send(message).is_err() {
+                            break;
+                        }
+                    },
+                    Err(_) => debug!("MCP server '{}' wrote non JSON: {}", server, line),
+                }
+            }
+        });
+        let server = config.name.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                debug!("MCP server '{}': {}", server, line);
+            }
+        });
+
+        let mut client = McpClient {
+            name: config.name.clone(),
+            child: Mutex::new(child),
+            stdin: Mutex::new(stdin),
+            messages: Mutex::new(receiver),
+            next_id: Mutex::new(1),
+            capabilities: Value::Null,
+        };
+
+        let result = client.request(
+            "initialize",
+            json!({
+                "protocolVersion": MCP_PROTOCOL_VERSION,
+                "capabilities": {},
+                "clientInfo": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")}
+            }),
+        )?;
+        client.capabilities = result["capabilities"].clone();
+        client.send(&json!({"jsonrpc": "2.0", "method": "notifications/initialized"}))?;
+        info!("connected to MCP server '{}'", client.name);
+        Ok(client)
+    }
+
+    fn send(&self, message: &Value) -> Result<(), String> {
+        let mut stdin = self.stdin.lock().expect("MCP stdin lock poisoned");
+        writeln!(stdin, "{}", message)
+            .and_then(|_| stdin.flush())
+            .map_err(|e| format!("MCP server '{}' closed its input: {}", self.name, e))
+    }
+
+    /// Sends a request and waits for its response, answering pings from the server meanwhile
+    fn request(&self, method: &str, params: Value) -> Result<Value, String> {
+        let id = {
+            let mut next_id = self.next_id.lock().expect("MCP id lock poisoned");
+            *next_id += 1;
+            *next_id - 1
+        };
+        debug!("MCP server '{}' <- {} {}", self.name, method, params);
+        self.send(&json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))?;
+
+        let messages = self.messages.lock().expect("MCP reader lock poisoned");
+        loop {
+            let message = match messages.recv_timeout(MCP_TIMEOUT) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(format!(
+                        "MCP server '{}' did not answer {} within {} seconds",
+                        self.name,
+                        method,
+                        MCP_TIMEOUT.as_secs()
+                    ))
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(format!(
+                        "MCP server '{}' exited during {}",
+                        self.name, method
+                    ))
+                },
+            };
+
+            if message["id"] == json!(id) && message.get("method").is_none() {
+                if let Some(error) = message.get("error") {
+                    return Err(format!(
+                        "MCP server '{}' returned an error for {}: {}",
+                        self.name,
+                        method,
+                        error["message"].as_str().unwrap_or(&error.to_string())
+                    ));
+                }
+                return Ok(message["result"].clone());
+            }
+            // requests from the server; only pings are supported, notifications are skipped
+            if message.get("id").is_some() && message.get("method").is_some() {
+                let reply = if message["method"] == "ping" {
+                    json!({"jsonrpc": "2.0", "id": message["id"], "result": {}})
+                } else {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": message["id"],
+                        "error": {"code": -32601, "message": "method not supported by pipe-gpt"}
+                    })
+                };
+                self.send(&reply)?;
+            }
+        }
+    }
+
+    /// Calls a list method, following `nextCursor` through every page
+    fn list(&self, method: &str, key: &str) -> Result<Vec<Value>, String> {
+        let mut items = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({"cursor": cursor}),
+                None => json!({}),
+            };
+            let result = self.request(method, params)?;
+            items.extend(result[key].as_array().cloned().unwrap_or_default());
+            match result["nextCursor"].as_str() {
+                Some(next) => cursor = Some(next.to_string()),
+                None => return Ok(items),
+            }
+        }
+    }
+
+    /// # List Tools
+    ///
+    /// The server's tools, or none if it doesn't offer any
+    pub fn list_tools(&self) -> Result<Vec<McpTool>, String> {
+        if self.capabilities.get("tools").is_none() {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .list("tools/list", "tools")?
+            .into_iter()
+            .filter_map(|tool| {
+                let name = tool["name"].as_str()?.to_string();
+                Some(McpTool {
+                    server: self.name.clone(),
+                    qualified_name: format!("{}__{}", self.name, name),
+                    name,
+                    description: tool["description"].as_str().unwrap_or_default().to_string(),
+                    input_schema: tool
+                        .get("inputSchema")
+                        .cloned()
+                        .unwrap_or_else(|| json!({"type": "object", "properties": {}})),
+                })
+            })
+            .collect())
+    }
+
+    /// # List Resources
+    ///
+    /// The server's resources, or none if it doesn't offer any
+    pub fn list_resources(&self) -> Result<Vec<McpResource>, String> {
+        if self.capabilities.get("resources").is_none() {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .list("resources/list", "resources")?
+            .into_iter()
+            .filter_map(|resource| {
+                Some(McpResource {
+                    uri: resource["uri"].as_str()?.to_string(),
+                    name: resource["name"].as_str().unwrap_or_default().to_string(),
+                    description: resource["description"].as_str().map(String::from),
+                })
+            })
+            .collect())
+    }
+
+    /// # Call Tool
+    ///
+    /// Calls a tool and joins the text parts of the result. A result flagged as an error is
+    /// returned as `Err` so the model is told the call failed.
+    pub fn call_tool(&self, name: &str, arguments: &Value) -> Result<String, String> {
+        let result = self.request("tools/call", json!({"name": name, "arguments": arguments}))?;
+        let text = content_text(&result["content"]);
+        if result["isError"].as_bool().unwrap_or(false) {
+            return Err(text);
+        }
+        Ok(text)
+    }
+
+    /// # Read Resource
+    ///
+    /// Reads a resource, joining the text of each part. Binary parts are described, not sent.
+    pub fn read_resource(&self, uri: &str) -> Result<String, String> {
+        let result = self.request("resources/read", json!({"uri": uri}))?;
+        Ok(content_text(&result["contents"]))
+    }
+}
+
+impl Drop for McpClient {
+    fn drop(&mut self) {
+        if let Ok(mut child) = self.child.lock() {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+/// Joins the text of MCP content parts, describing any that aren't text
+fn content_text(content: &Value) -> String {
+    content
+        .as_array()
+        .map(|parts| {
+            parts
+                .iter()
+                .map(|part| match part["text"].as_str() {
+                    Some(text) => text.to_string(),
+                    None => format!(
+                        "[{} content not shown]",
+                        part["mimeType"]
+                            .as_str()
+                            .or(part["type"].as_str())
+                            .unwrap_or("binary")
+                    ),
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// The fixture server in `tests/fixtures/mcp_server.py`
+    fn fixture_config() -> McpServerConfig {
+        McpServerConfig {
+            name: "fixture".to_string(),
+            command: "python3".to_string(),
+            args: vec![
+                concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mcp_server.py").to_string(),
+            ],
+            env: HashMap::new(),
+        }
+    }
+
+    /// Test the handshake, listing and calls against the fixture server
+    #[cfg_attr(not(doc), test)]
+    fn test_mcp_client() {
+        let client = McpClient::connect(&fixture_config()).unwrap();
+
+        let tools = client.list_tools().unwrap();
+        assert_eq!(
+            tools
+                .iter()
+                .map(|tool| tool.qualified_name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["fixture__echo", "fixture__fail", "fixture__files.search"]
+        );
+        assert_eq!(tools[0].input_schema["required"], json!(["text"]));
+
+        assert_eq!(
+            client.call_tool("echo", &json!({"text": "hello"})).unwrap(),
+            "echo: hello"
+        );
+        assert_eq!(
+            client.call_tool("fail", &json!({})).unwrap_err(),
+            "something went wrong"
+        );
+        assert!(client
+            .call_tool("missing", &json!({}))
+            .unwrap_err()
+            .contains("unknown tool"));
+
+        let resources = client.list_resources().unwrap();
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].uri, "file:///notes.txt");
+        assert_eq!(
+            client.read_resource("file:///notes.txt").unwrap(),
+            "remember the milk"
+        );
+    }
+
+    /// Test that a server which can't start is reported
+    #[cfg_attr(not(doc), test)]
+    fn test_mcp_client_missing_server() {
+        let config = McpServerConfig {
+            command: "pipe-gpt-no-such-server".to_string(),
+            ..fixture_config()
+        };
+        assert!(McpClient::connect(&config)
+            .err()
+            .unwrap()
+            .starts_with("could not start MCP server 'fixture'"));
+    }
+}
@@ -1 +1,7 @@
+pub mod batch;
+pub mod client;
+pub mod embeddings;
+pub mod mcp;
 pub mod openai;
+pub mod schema;
+pub mod tools;
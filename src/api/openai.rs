@@ -1,32 +1,332 @@
 use atty::Stream; // atty to determine if data is piped in or not
 use log::*; // logging
-use openai_api_rust::{
-    // openai api
-    chat::{ChatApi, ChatBody},
-    Auth,
-    Message,
-    OpenAI,
-    Role,
-};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-use crate::config::models::load_config;
+use crate::api::client::{Client, Error};
+use crate::config::models::{load_config, AppConfig};
 
 pub enum AssistantPurpose {
     CodeReviewer,
     Default,
+    Editor,
+    ShellCommand,
+    CommitMessage,
+    PullRequest,
+    DiffSummary,
+    TestTriage,
+    CompilerErrors,
+    Translator,
 }
 
-impl ToString for AssistantPurpose {
-    fn to_string(&self) -> String {
+impl fmt::Display for AssistantPurpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AssistantPurpose::Default => {
-                "You are a helpful assistant.".to_string()
-            }
+                write!(f, "You are a helpful assistant.")
+            },
             AssistantPurpose::CodeReviewer => {
-                "You are a helpful assistant. How would you improve this code? Include line numbers in your comments so I can tell where you mean. ".to_string()
+                write!(f, "You are a helpful assistant. How would you improve this code? Include line numbers in your comments so I can tell where you mean. ")
+            },
+            AssistantPurpose::Editor => {
+                write!(f, "You are a careful code editor. Reply with a single unified diff, in the format produced by `diff -u`, that makes the requested change to the file. Use the given file path in the --- and +++ headers, include three unchanged lines of context around each change and copy context lines exactly. Do not include any explanation.")
+            },
+            AssistantPurpose::ShellCommand => {
+                write!(f, "You are a careful shell expert. Reply with a single shell command, on one line, that does the requested task, a short explanation of what each part does, and how risky it is to run: low for read-only commands, medium for commands that change files or processes in a recoverable way, high for anything that deletes, overwrites or can't be undone. Prefer the safest command that does the task.")
+            },
+            AssistantPurpose::CommitMessage => {
+                write!(f, "You write git commit messages in the Conventional Commits style for the staged diff you are given. The first line is `type(scope): subject`, where type is one of feat, fix, docs, style, refactor, perf, test, build, ci, chore or revert, the scope is optional, and the subject is in the imperative mood with no full stop at the end. If the change needs explaining, add a blank line then a body wrapped at 72 columns saying what changed and why. Reply with only the commit message.")
+            },
+            AssistantPurpose::PullRequest => {
+                write!(f, "You write pull request descriptions from the commit log and changes you are given. Open with one or two plain sentences saying what the change does and why, then describe what changed for a reviewer. If a template is given, fill in its sections and keep its headings. Only mention testing that the commits or changes show. Reply in markdown with only the description.")
+            },
+            AssistantPurpose::DiffSummary => {
+                write!(f, "Summarise this diff of one file in a few short bullet points saying what changed and, where it is clear, why. Reply with only the bullet points.")
+            },
+            AssistantPurpose::TestTriage => {
+                write!(f, "You triage failing tests. For each failure you are given its name, message, trace and the source lines the trace points to. Give the most likely root causes, most likely first, with the evidence for each from the output and source. Say whether the test or the code under test looks wrong, group failures that share a cause, and suggest the quickest way to confirm or fix each one. Be concise.")
+            },
+            AssistantPurpose::CompilerErrors => {
+                write!(f, "You explain compiler errors. For each diagnostic you are given the compiler's message, code and notes, and the source lines it points to. Explain in plain words what is wrong and why the compiler rejects it, then propose a fix as a short code change to the lines shown. Where several diagnostics share one cause, say so and fix the cause once. Be concise.")
+            },
+            AssistantPurpose::Translator => {
+                write!(f, "You are a translator. You are given a JSON array of text segments from one document, in order, and the language to translate them into. Translate each segment on its own, keeping its meaning, tone and any markdown emphasis. Masks like ⟦0⟧ stand for code, links and placeholders: copy each one exactly once, moving it to where it belongs in the translated sentence. Keep the line breaks within a segment where they fit. Reply with one translation for each segment, in the same order.")
+            },
+        }
+    }
+}
+
+/// Rough token cost of one image part, used when checking the request against max_tokens
+pub const IMAGE_TOKEN_ESTIMATE: usize = 765;
+
+/// # Message Content
+///
+/// Plain text, or a list of parts for multimodal messages that include images
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+/// One part of a multimodal message
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// An image given by URL, including `data:` URLs with base64 encoded contents
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Content::Text(text.to_string())
+    }
+}
+
+impl PartialEq<&str> for Content {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, Content::Text(text) if text == other)
+    }
+}
+
+/// Who a message in the conversation is from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// The result of a tool call, answering the assistant message that made it
+    Tool,
+}
+
+/// # Chat Message
+///
+/// A message in the conversation sent to the chat completions endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: Content,
+    /// Tools the assistant asked to call, echoed back so the results can refer to them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The tool call a [Role::Tool] message is the result of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    /// A plain message with no tool calls
+    pub fn new(role: Role, content: impl Into<Content>) -> Self {
+        Message {
+            role,
+            content: content.into(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// The result of running a tool, sent back to the model
+    pub fn tool_result(tool_call_id: &str, content: impl Into<Content>) -> Self {
+        Message {
+            role: Role::Tool,
+            content: content.into(),
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id.to_string()),
+        }
+    }
+}
+
+/// # Tool Definition
+///
+/// A function the model may call, described by a JSON Schema for its arguments. Serialised as
+/// `{"type": "function", "function": {...}}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolDefinition {
+    Function { function: FunctionDefinition },
+}
+
+/// Name, description and parameter schema of a callable function
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    pub parameters: Value,
+}
+
+/// # Tool Call
+///
+/// A request from the model to call a function. `arguments` is a JSON string that may not be valid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+/// The function and arguments of a [ToolCall]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+/// # Reply Message
+///
+/// The assistant message in a chat completion, with either text or tool calls
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplyMessage {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+/// The parts of the chat completions response that are read
+#[derive(Debug, Deserialize)]
+struct ChatCompletion {
+    choices: Vec<Choice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: ReplyMessage,
+}
+
+/// # Response Format
+///
+/// Output format requested from the model. Serialised as `{"type": "..."}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+}
+
+impl FromStr for ResponseFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ResponseFormat::Text),
+            "json_object" => Ok(ResponseFormat::JsonObject),
+            other => Err(format!(
+                "unknown response_format '{}', expected 'text' or 'json_object'",
+                other
+            )),
+        }
+    }
+}
+
+/// # Chat Completion Request Body
+///
+/// The body sent to the chat completions endpoint. Covers the full set of sampling parameters,
+/// including `seed`, `response_format` and `tools`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<ToolDefinition>>,
+}
+
+/// Checks an optional value lies within an inclusive range
+fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> Result<(), String> {
+    match value {
+        Some(v) if !(min..=max).contains(&v) => Err(format!(
+            "{} must be between {} and {}, got {}",
+            name, min, max, v
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl ChatRequest {
+    /// # Validate Request Parameters
+    ///
+    /// Checks sampling parameters against the ranges accepted by the API, so bad values from the
+    /// command line or config file are reported clearly rather than forwarded.
+    pub fn validate(&self) -> Result<(), String> {
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens < 1 {
+                return Err(format!("max_tokens must be at least 1, got {}", max_tokens));
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > 4 {
+                return Err(format!(
+                    "at most 4 stop sequences are allowed, got {}",
+                    stop.len()
+                ));
+            }
+        }
+        if let Some(logit_bias) = &self.logit_bias {
+            for (token, bias) in logit_bias {
+                if token.parse::<u32>().is_err() {
+                    return Err(format!(
+                        "logit_bias keys must be token ids, got '{}'",
+                        token
+                    ));
+                }
+                if !(-100..=100).contains(bias) {
+                    return Err(format!(
+                        "logit_bias for token {} must be between -100 and 100, got {}",
+                        token, bias
+                    ));
+                }
             }
         }
+
+        Ok(())
     }
 }
 
@@ -46,9 +346,11 @@ impl ToString for AssistantPurpose {
 /// # Examples
 ///
 /// ```
+/// use pipe_gpt::api::openai::count_tokens;
+///
 /// let sample_text = "Hello, world! This is a test to count tokens.";
 /// let token_count = count_tokens(sample_text);
-/// assert_eq!(token_count, 11);
+/// assert_eq!(token_count, 12);
 /// ```
 pub fn count_tokens(text: &str) -> usize {
     let re = Regex::new(r"\w+|[^\w\s]").unwrap();
@@ -57,62 +359,108 @@ pub fn count_tokens(text: &str) -> usize {
 
 /// # Create Conversation Vector
 ///
-/// Add the prepend string if present. Add piped stream if present.
+/// Add the prepend string if present. Add the input if there is any, or if stdin is piped. Images,
+/// given as URLs, are sent as parts of the same message as the input.
 pub fn create_conversation(
     prepend: &str,
     input: &str,
+    images: &[String],
     purpose: &AssistantPurpose,
-) -> Vec<openai_api_rust::Message> {
-    let mut conversation_messages = vec![Message {
-        role: Role::System,
-        content: purpose.to_string(),
-    }];
+) -> Vec<Message> {
+    let mut conversation_messages = vec![Message::new(Role::System, purpose.to_string())];
     if !&prepend.is_empty() {
-        conversation_messages.push(Message {
-            role: Role::User,
-            content: prepend.to_string(),
-        });
+        conversation_messages.push(Message::new(Role::User, prepend));
     }
     // if data was piped into this application, add it to the conversation
     // This is useful even if the input is blank, as a form of debug, GPT will likely respond with ~"It looks like you forgot the data"
-    if !atty::is(Stream::Stdin) {
-        conversation_messages.push(Message {
-            role: Role::User,
-            content: input.to_string(),
-        });
+    if !images.is_empty() {
+        let mut parts = vec![];
+        if !input.is_empty() {
+            parts.push(ContentPart::Text {
+                text: input.to_string(),
+            });
+        }
+        parts.extend(images.iter().map(|url| ContentPart::ImageUrl {
+            image_url: ImageUrl { url: url.clone() },
+        }));
+        conversation_messages.push(Message::new(Role::User, Content::Parts(parts)));
+    } else if !input.is_empty() || !atty::is(Stream::Stdin) {
+        conversation_messages.push(Message::new(Role::User, input));
     }
 
     conversation_messages
 }
 
-/// # Send Request To Openai API
+/// # Build HTTP Client
+///
+/// A client with the connect, read and total timeouts from the config. A read timeout applies
+/// to each read of the response, so a stalled connection fails without cutting short a slow but
+/// progressing reply.
+pub fn build_http_client(config: &AppConfig) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(config.read_timeout_secs))
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+/// # Shared HTTP Client
 ///
-/// Loads the OPENAI_API_KEY environment variable, connects to OpenAI API, sends chat
-pub async fn send_to_gpt4(body: ChatBody) -> Result<String, reqwest::Error> {
+/// Built once from the config so every request reuses the same connection pool
+pub fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| build_http_client(&load_config()))
+}
+
+/// # Send Chat Request
+///
+/// Sends the chat with a [Client] from the config and AI_API_KEY environment variable, and
+/// returns the assistant message, which may ask for tool calls instead of giving an answer
+pub async fn send_chat(body: &ChatRequest) -> Result<ReplyMessage, Error> {
     // debug log
-    debug!("entered send_to_gpt4()");
-
-    let config = load_config();
-    let api_url = config.api_url;
-
-    let api_key = std::env::var("AI_API_KEY")
-        .map_err(|_| "Missing AI_API_KEY".to_string())
-        .expect("Failed to read auth from environment");
-
-    let auth = Auth::new(&api_key);
-    let openai = OpenAI::new(auth, &api_url);
-    let chat_completion = openai
-        .chat_completion_create(&body)
-        .expect("chat completion failed");
-    let choice = chat_completion.choices;
-    let message = &choice[0]
-        .message
-        .as_ref()
-        .expect("Failed to read message from API");
+    debug!("entered send_chat()");
+
+    Client::from_config()?.chat(body).await
+}
+
+/// # Post Chat Request
+///
+/// Sends the chat to the chat completions endpoint under `api_url` and returns the assistant
+/// message
+pub async fn post_chat(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    body: &ChatRequest,
+) -> Result<ReplyMessage, Error> {
+    let chat_completion: ChatCompletion = http
+        .post(format!("{}chat/completions", api_url))
+        .bearer_auth(api_key)
+        .json(body)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let message = chat_completion
+        .choices
+        .into_iter()
+        .next()
+        .ok_or(Error::EmptyReply)?
+        .message;
     // debug log
     debug!("message recieved {:?}", message);
 
-    Ok(message.content.to_string())
+    Ok(message)
+}
+
+/// # Send Request To Openai API
+///
+/// Sends the chat and returns the text of the reply
+pub async fn send_to_gpt4(body: &ChatRequest) -> Result<String, Error> {
+    let message = send_chat(body).await?;
+    Ok(message.content.unwrap_or_default())
 }
 #[cfg(any(test, doc))]
 mod tests {
@@ -128,7 +476,7 @@ mod tests {
         let input = "This is the piped input. It won't be piped as part of the test".to_string();
         let purpose = AssistantPurpose::Default;
 
-        let conversation = create_conversation(prepend, &input, &purpose);
+        let conversation = create_conversation(prepend, &input, &[], &purpose);
         //TODO: Investigate why this is 3 != 2 in github actions but 2 == 2 when run locally
         //assert_eq!(conversation.len(), 2); // then len is only two instead of three because piping isn't active here
         assert_eq!(conversation[1].content, p_text);
@@ -139,11 +487,11 @@ mod tests {
     async fn test_send_to_gpt4() {
         let config = load_config();
         // Note: This test requires a valid API key set in the environment
-        let body = ChatBody {
+        let body = ChatRequest {
             model: config.model,
             max_tokens: Some(config.max_tokens),
             temperature: Some(config.temperature),
-            top_p: Some(0.95),
+            top_p: Some(config.top_p),
             n: Some(1),
             stream: Some(false),
             stop: None,
@@ -151,14 +499,210 @@ mod tests {
             frequency_penalty: None,
             logit_bias: None,
             user: None,
-            messages: vec![Message {
-                role: Role::User,
-                content: "Translate this to English please.".to_string(),
-            }],
+            seed: None,
+            response_format: None,
+            tools: None,
+            messages: vec![Message::new(
+                Role::User,
+                "Translate this to English please.",
+            )],
         };
 
-        let result = send_to_gpt4(body).await;
+        let result = send_to_gpt4(&body).await;
 
         assert!(result.is_ok());
     }
+
+    fn request_with_defaults() -> ChatRequest {
+        ChatRequest {
+            model: "gpt-4o".to_string(),
+            max_tokens: Some(100),
+            temperature: Some(0.6),
+            top_p: Some(0.95),
+            n: Some(1),
+            stream: Some(false),
+            stop: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            user: None,
+            seed: None,
+            response_format: None,
+            tools: None,
+            messages: vec![],
+        }
+    }
+
+    /// Test that out of range sampling parameters are rejected with a clear message
+    #[cfg_attr(not(doc), test)]
+    fn test_chat_request_validate_ranges() {
+        assert!(request_with_defaults().validate().is_ok());
+
+        let mut request = request_with_defaults();
+        request.temperature = Some(2.5);
+        assert_eq!(
+            request.validate().unwrap_err(),
+            "temperature must be between 0 and 2, got 2.5"
+        );
+
+        let mut request = request_with_defaults();
+        request.presence_penalty = Some(-2.1);
+        assert!(request.validate().unwrap_err().contains("presence_penalty"));
+
+        let mut request = request_with_defaults();
+        request.frequency_penalty = Some(2.0);
+        assert!(request.validate().is_ok());
+
+        let mut request = request_with_defaults();
+        request.stop = Some(vec!["a".to_string(); 5]);
+        assert!(request.validate().unwrap_err().contains("stop"));
+
+        let mut request = request_with_defaults();
+        request.logit_bias = Some(HashMap::from([("50256".to_string(), 101)]));
+        assert!(request.validate().unwrap_err().contains("logit_bias"));
+    }
+
+    /// Test that optional parameters are only serialised when set
+    #[cfg_attr(not(doc), test)]
+    fn test_chat_request_serialises_response_format() {
+        let mut request = request_with_defaults();
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("seed").is_none());
+        assert!(json.get("response_format").is_none());
+
+        request.seed = Some(7);
+        request.response_format = Some("json_object".parse().unwrap());
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["seed"], 7);
+        assert_eq!(json["response_format"]["type"], "json_object");
+    }
+
+    /// Test that a server which accepts the connection but never replies hits the read timeout
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_read_timeout() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}/", listener.local_addr().unwrap());
+        // hold the connection open without answering
+        std::thread::spawn(move || {
+            let _connection = listener.accept();
+            std::thread::sleep(Duration::from_secs(5));
+        });
+
+        let config = AppConfig {
+            read_timeout_secs: 1,
+            ..AppConfig::default()
+        };
+        let started = std::time::Instant::now();
+        let error = post_chat(
+            &build_http_client(&config),
+            &api_url,
+            "key",
+            &request_with_defaults(),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, Error::Http(e) if e.is_timeout()));
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    /// Test that a reply with no choices is an error rather than a panic
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_post_chat_empty_reply() {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(length) = header.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+            }
+            reader.read_exact(&mut vec![0; content_length]).unwrap();
+            let reply = r#"{"choices": []}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                reply.len(),
+                reply
+            )
+            .unwrap();
+        });
+
+        let error = post_chat(
+            &build_http_client(&AppConfig::default()),
+            &api_url,
+            "key",
+            &request_with_defaults(),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, Error::EmptyReply));
+    }
+
+    /// Test that tool calls are read from replies and tool results refer back to them
+    #[cfg_attr(not(doc), test)]
+    fn test_tool_call_round_trip() {
+        let reply: ReplyMessage = serde_json::from_value(serde_json::json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": {"name": "read_file", "arguments": "{\"path\": \"Cargo.toml\"}"}
+            }]
+        }))
+        .unwrap();
+        assert!(reply.content.is_none());
+        let calls = reply.tool_calls.unwrap();
+        assert_eq!(calls[0].function.name, "read_file");
+
+        let json = serde_json::to_value(Message::tool_result(&calls[0].id, "[package]")).unwrap();
+        assert_eq!(json["role"], "tool");
+        assert_eq!(json["tool_call_id"], "call_1");
+        assert!(json.get("tool_calls").is_none());
+    }
+
+    /// Test that images are sent as parts alongside the piped text
+    #[cfg_attr(not(doc), test)]
+    fn test_create_conversation_with_images() {
+        let images = vec!["data:image/png;base64,iVBORw0KGgo=".to_string()];
+        let conversation =
+            create_conversation("What is wrong?", "log", &images, &AssistantPurpose::Default);
+
+        let last = conversation.last().unwrap();
+        assert_eq!(
+            last.content,
+            Content::Parts(vec![
+                ContentPart::Text {
+                    text: "log".to_string()
+                },
+                ContentPart::ImageUrl {
+                    image_url: ImageUrl {
+                        url: images[0].clone()
+                    }
+                },
+            ])
+        );
+
+        let json = serde_json::to_value(last).unwrap();
+        assert_eq!(json["role"], "user");
+        assert_eq!(json["content"][0]["type"], "text");
+        assert_eq!(json["content"][1]["type"], "image_url");
+        assert_eq!(json["content"][1]["image_url"]["url"], images[0]);
+        assert_eq!(
+            serde_json::to_value(&conversation[1]).unwrap()["content"],
+            "What is wrong?"
+        );
+    }
 }
@@ -0,0 +1,156 @@
+use log::*; // logging
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::api::openai::{send_to_gpt4, ChatRequest, Message, Role};
+
+/// Number of times a reply is requested before giving up on schema validation
+pub const SCHEMA_MAX_ATTEMPTS: usize = 3;
+
+/// # Load JSON Schema
+///
+/// Reads a JSON Schema document from disk and checks that it is itself a valid schema
+pub fn load_schema(path: &Path) -> Result<Value, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("could not read schema {:?}: {}", path, e))?;
+    let schema: Value = serde_json::from_str(&content)
+        .map_err(|e| format!("schema {:?} is not valid JSON: {}", path, e))?;
+    jsonschema::validator_for(&schema)
+        .map_err(|e| format!("schema {:?} is not a valid JSON Schema: {}", path, e))?;
+    Ok(schema)
+}
+
+/// # JSON Mode Instructions
+///
+/// System message asking for a bare JSON document, including the schema when one is given.
+/// The API requires the word "JSON" to appear in the conversation when `json_object` is requested.
+pub fn json_instructions(schema: Option<&Value>) -> String {
+    match schema {
+        Some(schema) => format!(
+            "Respond only with a single valid JSON document and no other text. The document must conform to this JSON Schema:\n{}",
+            schema
+        ),
+        None => "Respond only with a single valid JSON document and no other text.".to_string(),
+    }
+}
+
+/// # Validate Reply Against Schema
+///
+/// Parses the model reply as JSON and validates it, returning every validation error found
+/// so it can be fed back to the model.
+pub fn validate_reply(reply: &str, schema: &Value) -> Result<Value, String> {
+    let document: Value = serde_json::from_str(reply.trim())
+        .map_err(|e| format!("The reply is not valid JSON: {}", e))?;
+
+    let validator = jsonschema::validator_for(schema).map_err(|e| e.to_string())?;
+    let errors: Vec<String> = validator
+        .iter_errors(&document)
+        .map(|e| {
+            let path = e.instance_path.to_string();
+            if path.is_empty() {
+                e.to_string()
+            } else {
+                format!("at {}: {}", path, e)
+            }
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(document)
+    } else {
+        Err(format!(
+            "The reply does not match the JSON Schema:\n- {}",
+            errors.join("\n- ")
+        ))
+    }
+}
+
+/// # Send Request And Validate Against Schema
+///
+/// Sends the chat and validates the reply. On failure the reply and the validation errors are
+/// appended to the conversation and the request is retried, up to [SCHEMA_MAX_ATTEMPTS] times.
+pub async fn send_with_schema(mut body: ChatRequest, schema: &Value) -> Result<Value, String> {
+    let mut last_error = String::new();
+
+    for attempt in 1..=SCHEMA_MAX_ATTEMPTS {
+        let reply = send_to_gpt4(&body).await.map_err(|e| e.to_string())?;
+
+        match validate_reply(&reply, schema) {
+            Ok(document) => return Ok(document),
+            Err(e) => {
+                warn!("attempt {} failed schema validation: {}", attempt, e);
+                body.messages.push(Message::new(Role::Assistant, reply));
+                body.messages.push(Message::new(
+                    Role::User,
+                    format!("{}\nReply again with only the corrected JSON document.", e),
+                ));
+                last_error = e;
+            },
+        }
+    }
+
+    Err(format!(
+        "No valid reply after {} attempts. {}",
+        SCHEMA_MAX_ATTEMPTS, last_error
+    ))
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer", "minimum": 0 }
+            },
+            "required": ["name", "age"]
+        })
+    }
+
+    /// Test that a conforming reply is returned as a parsed document
+    #[cfg_attr(not(doc), test)]
+    fn test_validate_reply_accepts_valid_document() {
+        let document = validate_reply("  {\"name\": \"Ada\", \"age\": 36}\n", &person_schema());
+        assert_eq!(document.unwrap(), json!({"name": "Ada", "age": 36}));
+    }
+
+    /// Test that prose and schema violations produce errors suitable for feeding back
+    #[cfg_attr(not(doc), test)]
+    fn test_validate_reply_reports_errors() {
+        let err = validate_reply("Sure! Here is the JSON: {}", &person_schema()).unwrap_err();
+        assert!(err.starts_with("The reply is not valid JSON"));
+
+        let err = validate_reply("{\"name\": \"Ada\", \"age\": -1}", &person_schema()).unwrap_err();
+        assert!(err.contains("/age"));
+
+        let err = validate_reply("{\"name\": \"Ada\"}", &person_schema()).unwrap_err();
+        assert!(err.contains("\"age\" is a required property"));
+    }
+
+    /// Test that schema files are checked when loaded
+    #[cfg_attr(not(doc), test)]
+    fn test_load_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let valid = dir.path().join("valid.json");
+        fs::write(&valid, person_schema().to_string()).unwrap();
+        assert_eq!(load_schema(&valid).unwrap(), person_schema());
+
+        let invalid = dir.path().join("invalid.json");
+        fs::write(&invalid, "{\"type\": 12}").unwrap();
+        assert!(load_schema(&invalid).is_err());
+
+        assert!(load_schema(&dir.path().join("missing.json")).is_err());
+    }
+
+    /// Test that the instructions always mention JSON, as required by the API
+    #[cfg_attr(not(doc), test)]
+    fn test_json_instructions() {
+        assert!(json_instructions(None).contains("JSON"));
+        assert!(json_instructions(Some(&person_schema())).contains("\"required\""));
+    }
+}
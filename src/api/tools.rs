@@ -0,0 +1,753 @@
+use log::*; // logging
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::api::mcp::{McpClient, McpResource, McpTool};
+use crate::api::openai::{
+    send_chat, ChatRequest, FunctionCall, FunctionDefinition, Message, Role, ToolDefinition,
+};
+use crate::config::models::ToolConfig;
+
+/// Most bytes of tool output sent back to the model, so one call can't use up the context
+pub const MAX_TOOL_OUTPUT_BYTES: usize = 16 * 1024;
+
+/// Most matching lines returned by the grep tool
+const MAX_GREP_MATCHES: usize = 200;
+
+/// # Local Tool
+///
+/// A tool the model can call in agent mode. File tools are read only and confined to the
+/// working directory. Commands need to be allow-listed or confirmed. Custom tools come from the
+/// config file and run without confirmation, as they were set up for the project.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tool {
+    ReadFile,
+    ListDirectory,
+    Grep,
+    RunCommand,
+    Custom(ToolConfig),
+    /// A tool from an MCP server
+    Mcp(McpTool),
+    /// Reads the resources of an MCP server, named `server__read_resource`
+    McpResources {
+        server: String,
+        qualified_name: String,
+        resources: Vec<McpResource>,
+    },
+}
+
+impl Tool {
+    pub fn name(&self) -> &str {
+        match self {
+            Tool::ReadFile => "read_file",
+            Tool::ListDirectory => "list_directory",
+            Tool::Grep => "grep",
+            Tool::RunCommand => "run_command",
+            Tool::Custom(tool) => &tool.name,
+            Tool::Mcp(tool) => &tool.qualified_name,
+            Tool::McpResources { qualified_name, .. } => qualified_name,
+        }
+    }
+
+    /// The description and argument schema sent to the model
+    pub fn definition(&self) -> ToolDefinition {
+        let resources_description;
+        let (description, parameters) = match self {
+            Tool::Custom(tool) => (tool.description.as_str(), tool.parameters.clone()),
+            Tool::Mcp(tool) => (tool.description.as_str(), tool.input_schema.clone()),
+            Tool::McpResources {
+                server, resources, ..
+            } => {
+                let listing: Vec<String> = resources
+                    .iter()
+                    .map(|resource| match &resource.description {
+                        Some(description) => {
+                            format!("{} ({}): {}", resource.uri, resource.name, description)
+                        },
+                        None => format!("{} ({})", resource.uri, resource.name),
+                    })
+                    .collect();
+                resources_description = format!(
+                    "Read a resource from the {} MCP server. Available resources:\n{}",
+                    server,
+                    listing.join("\n")
+                );
+                (
+                    resources_description.as_str(),
+                    json!({
+                        "type": "object",
+                        "properties": {"uri": {"type": "string"}},
+                        "required": ["uri"]
+                    }),
+                )
+            },
+            Tool::ReadFile => (
+                "Read a text file, given as a path relative to the working directory",
+                json!({
+                    "type": "object",
+                    "properties": {"path": {"type": "string"}},
+                    "required": ["path"]
+                }),
+            ),
+            Tool::ListDirectory => (
+                "List the entries of a directory relative to the working directory. Directories end with /",
+                json!({
+                    "type": "object",
+                    "properties": {"path": {"type": "string", "default": "."}}
+                }),
+            ),
+            Tool::Grep => (
+                "Search text files under a directory for lines matching a regular expression. Returns path:line:text",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "pattern": {"type": "string"},
+                        "path": {"type": "string", "default": "."}
+                    },
+                    "required": ["pattern"]
+                }),
+            ),
+            Tool::RunCommand => (
+                "Run a program with arguments in the working directory, without a shell. Returns the exit status, stdout and stderr. The user may refuse to run it",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "program": {"type": "string"},
+                        "args": {"type": "array", "items": {"type": "string"}}
+                    },
+                    "required": ["program"]
+                }),
+            ),
+        };
+        ToolDefinition::Function {
+            function: FunctionDefinition {
+                name: self.name().to_string(),
+                description: description.to_string(),
+                parameters,
+            },
+        }
+    }
+}
+
+/// The tools available in agent mode
+pub fn builtin_tools() -> Vec<Tool> {
+    vec![
+        Tool::ReadFile,
+        Tool::ListDirectory,
+        Tool::Grep,
+        Tool::RunCommand,
+    ]
+}
+
+/// # MCP Server Tools
+///
+/// The tools an MCP server offers, plus one to read its resources if it has any. Tools with
+/// names the API won't accept, which must be 1 to 64 letters, digits, `_` or `-`, are skipped.
+pub fn mcp_tools(client: &McpClient) -> Result<Vec<Tool>, String> {
+    let name_pattern = Regex::new(r"^[A-Za-z0-9_-]{1,64}$").unwrap();
+    let mut tools: Vec<Tool> = vec![];
+    for tool in client.list_tools()? {
+        if tool.qualified_name.len() > 64 {
+            warn!(
+                "skipping MCP tool {} as its name is too long",
+                tool.qualified_name
+            );
+            continue;
+        }
+        if !name_pattern.is_match(&tool.qualified_name) {
+            warn!(
+                "skipping MCP tool {} as its name has characters other than letters, digits, _ or -",
+                tool.qualified_name
+            );
+            continue;
+        }
+        tools.push(Tool::Mcp(tool));
+    }
+    let resources = client.list_resources()?;
+    let resources_name = format!("{}__read_resource", client.name);
+    if !resources.is_empty() && !name_pattern.is_match(&resources_name) {
+        warn!(
+            "skipping the resources of MCP server {} as its name can't be used in a tool name",
+            client.name
+        );
+    } else if !resources.is_empty() {
+        tools.push(Tool::McpResources {
+            server: client.name.clone(),
+            qualified_name: resources_name,
+            resources,
+        });
+    }
+    Ok(tools)
+}
+
+/// Cuts tool output down to [MAX_TOOL_OUTPUT_BYTES] on a character boundary
+fn cap_output(mut output: String) -> String {
+    if output.len() > MAX_TOOL_OUTPUT_BYTES {
+        let mut end = MAX_TOOL_OUTPUT_BYTES;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str("\n[output truncated]");
+    }
+    output
+}
+
+/// # Toolbox
+///
+/// The tools offered to the model and the rules for running them
+pub struct Toolbox {
+    pub tools: Vec<Tool>,
+    /// Programs `run_command` may start without asking
+    pub allowed_commands: Vec<String>,
+    /// Directory the file tools are confined to and commands run in
+    pub root: PathBuf,
+    /// Asks the user whether a command that is not allow-listed may run
+    pub confirm: fn(&str) -> bool,
+    /// Connected MCP servers, which [Tool::Mcp] and [Tool::McpResources] are called through
+    pub mcp: Vec<McpClient>,
+}
+
+impl Toolbox {
+    /// Definitions of every tool, for [ChatRequest::tools]
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.iter().map(|tool| tool.definition()).collect()
+    }
+
+    /// # Call Tool
+    ///
+    /// Runs the named tool with its JSON arguments. Failures are returned as text for the model
+    /// to read, so it can correct itself, rather than ending the conversation.
+    pub fn call(&self, call: &FunctionCall) -> String {
+        let result = match self.tools.iter().find(|tool| tool.name() == call.name) {
+            Some(tool) => serde_json::from_str::<Value>(&call.arguments)
+                .map_err(|e| format!("arguments are not valid JSON: {}", e))
+                .and_then(|args| self.run(tool, &args)),
+            None => Err(format!("there is no tool named '{}'", call.name)),
+        };
+        match result {
+            Ok(output) => cap_output(output),
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    fn run(&self, tool: &Tool, args: &Value) -> Result<String, String> {
+        match tool {
+            Tool::ReadFile => {
+                let path = self.resolve(string_arg(args, "path")?)?;
+                read_file_start(&path).map_err(|e| format!("could not read {:?}: {}", path, e))
+            },
+            Tool::ListDirectory => {
+                let path = self.resolve(args["path"].as_str().unwrap_or("."))?;
+                list_directory(&path)
+            },
+            Tool::Grep => {
+                let pattern = Regex::new(string_arg(args, "pattern")?)
+                    .map_err(|e| format!("invalid pattern: {}", e))?;
+                let path = self.resolve(args["path"].as_str().unwrap_or("."))?;
+                let mut matches = vec![];
+                let mut visited = HashSet::new();
+                grep(
+                    &pattern,
+                    &path,
+                    &self.canonical_root()?,
+                    &mut visited,
+                    &mut matches,
+                );
+                if matches.is_empty() {
+                    Ok("no matches".to_string())
+                } else {
+                    Ok(matches.join("\n"))
+                }
+            },
+            Tool::RunCommand => {
+                let program = string_arg(args, "program")?;
+                let command_args: Vec<String> = args["args"]
+                    .as_array()
+                    .map(|values| {
+                        values
+                            .iter()
+                            .map(|value| value.as_str().unwrap_or_default().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                self.run_command(program, &command_args)
+            },
+            Tool::Custom(tool) => self.run_custom(tool, args),
+            Tool::Mcp(tool) => self.mcp_client(&tool.server)?.call_tool(&tool.name, args),
+            Tool::McpResources { server, .. } => self
+                .mcp_client(server)?
+                .read_resource(string_arg(args, "uri")?),
+        }
+    }
+
+    fn mcp_client(&self, server: &str) -> Result<&McpClient, String> {
+        self.mcp
+            .iter()
+            .find(|client| client.name == server)
+            .ok_or_else(|| format!("MCP server '{}' is not connected", server))
+    }
+
+    /// Runs a custom tool once its arguments match its schema, returning stdout on success
+    fn run_custom(&self, tool: &ToolConfig, args: &Value) -> Result<String, String> {
+        let validator = jsonschema::validator_for(&tool.parameters).map_err(|e| e.to_string())?;
+        let errors: Vec<String> = validator.iter_errors(args).map(|e| e.to_string()).collect();
+        if !errors.is_empty() {
+            return Err(format!("invalid arguments: {}", errors.join("; ")));
+        }
+
+        let words = tool.render(args)?;
+        let (program, command_args) = words.split_first().expect("checked at config load");
+        eprintln!("Running `{}`", words.join(" "));
+        let output = Command::new(program)
+            .args(command_args)
+            .current_dir(&self.root)
+            .output()
+            .map_err(|e| format!("could not run `{}`: {}", program, e))?;
+
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+        }
+        Err(format!(
+            "`{}` failed with {}\nstdout:\n{}\nstderr:\n{}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+
+    fn canonical_root(&self) -> Result<PathBuf, String> {
+        self.root
+            .canonicalize()
+            .map_err(|e| format!("could not resolve the working directory: {}", e))
+    }
+
+    /// Resolves a path relative to the root, refusing anything outside it
+    fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let root = self.canonical_root()?;
+        let resolved = root
+            .join(path)
+            .canonicalize()
+            .map_err(|e| format!("could not find '{}': {}", path, e))?;
+        if !resolved.starts_with(&root) {
+            return Err(format!("'{}' is outside the working directory", path));
+        }
+        Ok(resolved)
+    }
+
+    fn run_command(&self, program: &str, args: &[String]) -> Result<String, String> {
+        let display = std::iter::once(program.to_string())
+            .chain(args.iter().cloned())
+            .collect::<Vec<String>>()
+            .join(" ");
+        let allowed = self
+            .allowed_commands
+            .iter()
+            .any(|allowed| allowed == program);
+        if !allowed && !(self.confirm)(&format!("The model wants to run `{}`. Allow?", display)) {
+            return Err(format!("the user did not allow `{}` to run", display));
+        }
+
+        eprintln!("Running `{}`", display);
+        let output = Command::new(program)
+            .args(args)
+            .current_dir(&self.root)
+            .output()
+            .map_err(|e| format!("could not run `{}`: {}", display, e))?;
+        let status = match output.status.code() {
+            Some(code) => code.to_string(),
+            None => "killed by a signal".to_string(),
+        };
+        Ok(format!(
+            "exit code: {}\nstdout:\n{}\nstderr:\n{}",
+            status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+fn string_arg<'a>(args: &'a Value, name: &str) -> Result<&'a str, String> {
+    args[name]
+        .as_str()
+        .ok_or_else(|| format!("missing string argument '{}'", name))
+}
+
+fn list_directory(path: &Path) -> Result<String, String> {
+    let mut entries: Vec<String> = fs::read_dir(path)
+        .map_err(|e| format!("could not list {:?}: {}", path, e))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() {
+                format!("{}/", name)
+            } else {
+                name
+            }
+        })
+        .collect();
+    entries.sort();
+    Ok(entries.join("\n"))
+}
+
+/// Searches text files under `path`, skipping hidden entries, until enough matches are found.
+/// Symlinks are never followed, so the search can't leave `root` or loop, and each directory
+/// is searched once.
+fn grep(
+    pattern: &Regex,
+    path: &Path,
+    root: &Path,
+    visited: &mut HashSet<PathBuf>,
+    matches: &mut Vec<String>,
+) {
+    if matches.len() >= MAX_GREP_MATCHES {
+        return;
+    }
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return;
+    };
+    if metadata.is_dir() {
+        let Ok(canonical) = path.canonicalize() else {
+            return;
+        };
+        if !canonical.starts_with(root) || !visited.insert(canonical) {
+            return;
+        }
+        let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_ok_and(|kind| !kind.is_symlink()))
+                .map(|e| e.path())
+                .collect(),
+            Err(_) => return,
+        };
+        entries.sort();
+        for entry in entries {
+            let hidden = entry
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if !hidden {
+                grep(pattern, &entry, root, visited, matches);
+            }
+        }
+        return;
+    }
+    if !metadata.is_file() {
+        return;
+    }
+    // binary and unreadable files are skipped
+    let Ok(contents) = fs::read_to_string(path) else {
+        return;
+    };
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    for (number, line) in contents.lines().enumerate() {
+        if pattern.is_match(line) {
+            matches.push(format!("{}:{}:{}", relative.display(), number + 1, line));
+            if matches.len() >= MAX_GREP_MATCHES {
+                return;
+            }
+        }
+    }
+}
+
+/// Reads no more of a file than [cap_output] will keep, so a huge file isn't loaded whole. A
+/// character cut off at the end of what was read is dropped; any other invalid UTF-8 is an error.
+fn read_file_start(path: &Path) -> Result<String, String> {
+    let mut bytes = vec![];
+    fs::File::open(path)
+        .and_then(|file| {
+            // a character is at most 4 bytes, so cap_output still sees the file was too long
+            file.take(MAX_TOOL_OUTPUT_BYTES as u64 + 4)
+                .read_to_end(&mut bytes)
+        })
+        .map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).or_else(|e| {
+        let error = e.utf8_error();
+        if error.error_len().is_some() || e.as_bytes().len() <= MAX_TOOL_OUTPUT_BYTES {
+            return Err("the file is not valid UTF-8 text".to_string());
+        }
+        let mut bytes = e.into_bytes();
+        bytes.truncate(error.valid_up_to());
+        Ok(String::from_utf8(bytes).expect("valid up to here"))
+    })
+}
+
+/// # Send Request With Tools
+///
+/// Offers the tools to the model and loops: each reply that asks for tool calls has them run
+/// locally and the results appended to the conversation before sending again. Returns the first
+/// reply with no tool calls, or an error after `max_steps` requests without one. Tools run on a
+/// blocking thread, so a slow command doesn't stall the runtime or Ctrl-C.
+pub async fn send_with_tools(
+    mut body: ChatRequest,
+    toolbox: Arc<Toolbox>,
+    max_steps: usize,
+) -> Result<String, String> {
+    body.tools = Some(toolbox.definitions());
+
+    for step in 1..=max_steps {
+        let reply = send_chat(&body).await.map_err(|e| e.to_string())?;
+        let calls = reply.tool_calls.unwrap_or_default();
+        if calls.is_empty() {
+            return Ok(reply.content.unwrap_or_default());
+        }
+
+        info!("step {}: {} tool call(s)", step, calls.len());
+        body.messages.push(Message {
+            role: Role::Assistant,
+            content: reply.content.unwrap_or_default().into(),
+            tool_calls: Some(calls.clone()),
+            tool_call_id: None,
+        });
+        for call in &calls {
+            eprintln!(
+                "Tool call: {}({})",
+                call.function.name, call.function.arguments
+            );
+            let (toolbox, function) = (Arc::clone(&toolbox), call.function.clone());
+            let result = tokio::task::spawn_blocking(move || toolbox.call(&function))
+                .await
+                .map_err(|e| format!("tool call failed: {}", e))?;
+            debug!("tool result: {}", result);
+            body.messages.push(Message::tool_result(&call.id, result));
+        }
+    }
+
+    Err(format!(
+        "No final answer after {} steps. Raise --max_steps to let the agent do more.",
+        max_steps
+    ))
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+    use crate::config::models::McpServerConfig;
+
+    fn deny(_: &str) -> bool {
+        false
+    }
+
+    fn toolbox(root: &Path) -> Toolbox {
+        Toolbox {
+            tools: builtin_tools(),
+            allowed_commands: vec!["echo".to_string()],
+            root: root.to_path_buf(),
+            confirm: deny,
+            mcp: vec![],
+        }
+    }
+
+    fn call(name: &str, arguments: Value) -> FunctionCall {
+        FunctionCall {
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    /// Test that the file tools read inside the working directory and refuse to leave it
+    #[cfg_attr(not(doc), test)]
+    fn test_file_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(
+            dir.path().join("src/main.rs"),
+            "fn main() {\n    todo!()\n}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join(".hidden"), "todo!()").unwrap();
+        let tools = toolbox(dir.path());
+
+        assert_eq!(
+            tools.call(&call("read_file", json!({"path": "src/main.rs"}))),
+            "fn main() {\n    todo!()\n}\n"
+        );
+        assert_eq!(
+            tools.call(&call("list_directory", json!({}))),
+            ".hidden\nsrc/"
+        );
+        assert_eq!(
+            tools.call(&call("grep", json!({"pattern": "todo!"}))),
+            "src/main.rs:2:    todo!()"
+        );
+        assert!(tools
+            .call(&call("read_file", json!({"path": "../"})))
+            .contains("outside the working directory"));
+        assert!(tools
+            .call(&call("read_file", json!({})))
+            .starts_with("Error: missing string argument 'path'"));
+    }
+
+    /// Test that only the start of a large file is read, even when it ends mid-character
+    #[cfg_attr(not(doc), test)]
+    fn test_read_file_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let large = dir.path().join("large.txt");
+        fs::write(&large, "é".repeat(MAX_TOOL_OUTPUT_BYTES)).unwrap();
+        let start = read_file_start(&large).unwrap();
+        assert!(start.len() > MAX_TOOL_OUTPUT_BYTES);
+        assert!(start.len() <= MAX_TOOL_OUTPUT_BYTES + 4);
+        assert!(toolbox(dir.path())
+            .call(&call("read_file", json!({"path": "large.txt"})))
+            .ends_with("é\n[output truncated]"));
+
+        let binary = dir.path().join("binary.bin");
+        fs::write(&binary, [0xff, 0xfe, 0x00]).unwrap();
+        assert_eq!(
+            read_file_start(&binary).unwrap_err(),
+            "the file is not valid UTF-8 text"
+        );
+    }
+
+    /// Test that grep doesn't follow symlinks out of the working directory or round a loop
+    #[cfg(unix)]
+    #[cfg_attr(not(doc), test)]
+    fn test_grep_skips_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret.txt"), "todo!() secret").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "todo!()\n").unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("src/loop")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("outside")).unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret.txt"),
+            dir.path().join("secret.txt"),
+        )
+        .unwrap();
+        let tools = toolbox(dir.path());
+
+        assert_eq!(
+            tools.call(&call("grep", json!({"pattern": "todo!"}))),
+            "src/lib.rs:1:todo!()"
+        );
+    }
+
+    /// Test that only allow-listed commands run when the user refuses confirmation
+    #[cfg_attr(not(doc), test)]
+    fn test_run_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let tools = toolbox(dir.path());
+
+        let output = tools.call(&call(
+            "run_command",
+            json!({"program": "echo", "args": ["hello"]}),
+        ));
+        assert!(output.starts_with("exit code: 0\n"));
+        assert!(output.contains("stdout:\nhello\n"));
+
+        assert_eq!(
+            tools.call(&call(
+                "run_command",
+                json!({"program": "rm", "args": ["-rf", "."]})
+            )),
+            "Error: the user did not allow `rm -rf .` to run"
+        );
+    }
+
+    /// Test that mistakes by the model are reported back to it
+    #[cfg_attr(not(doc), test)]
+    fn test_call_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let tools = toolbox(dir.path());
+
+        assert_eq!(
+            tools.call(&call("delete_everything", json!({}))),
+            "Error: there is no tool named 'delete_everything'"
+        );
+        assert!(tools
+            .call(&FunctionCall {
+                name: "read_file".to_string(),
+                arguments: "{not json".to_string(),
+            })
+            .starts_with("Error: arguments are not valid JSON"));
+    }
+
+    /// Test that custom tools from the config run with their arguments filled in and validated
+    #[cfg_attr(not(doc), test)]
+    fn test_custom_tool() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tools = toolbox(dir.path());
+        tools.tools.push(Tool::Custom(ToolConfig {
+            name: "greet".to_string(),
+            description: "Greets someone".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {"who": {"type": "string"}},
+                "required": ["who"]
+            }),
+            command: "echo hello {who}".to_string(),
+        }));
+
+        assert_eq!(
+            tools.call(&call("greet", json!({"who": "world; rm -rf ."}))),
+            "hello world; rm -rf .\n"
+        );
+        assert!(tools
+            .call(&call("greet", json!({})))
+            .starts_with("Error: invalid arguments"));
+        match &tools.definitions()[4] {
+            ToolDefinition::Function { function } => assert_eq!(function.name, "greet"),
+        }
+    }
+
+    /// Test that MCP tools and resources are offered and called through their server
+    #[cfg_attr(not(doc), test)]
+    fn test_mcp_tools() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut tools = toolbox(dir.path());
+        let client = McpClient::connect(&McpServerConfig {
+            name: "fixture".to_string(),
+            command: "python3".to_string(),
+            args: vec![
+                concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mcp_server.py").to_string(),
+            ],
+            env: Default::default(),
+        })
+        .unwrap();
+        tools.tools.extend(mcp_tools(&client).unwrap());
+        tools.mcp.push(client);
+
+        let names: Vec<&str> = tools.tools[4..].iter().map(|tool| tool.name()).collect();
+        assert_eq!(
+            names,
+            vec!["fixture__echo", "fixture__fail", "fixture__read_resource"]
+        );
+        match tools.tools[6].definition() {
+            ToolDefinition::Function { function } => {
+                assert!(function.description.contains("file:///notes.txt (notes)"))
+            },
+        }
+
+        assert_eq!(
+            tools.call(&call("fixture__echo", json!({"text": "hi"}))),
+            "echo: hi"
+        );
+        assert_eq!(
+            tools.call(&call("fixture__fail", json!({}))),
+            "Error: something went wrong"
+        );
+        assert_eq!(
+            tools.call(&call(
+                "fixture__read_resource",
+                json!({"uri": "file:///notes.txt"})
+            )),
+            "remember the milk"
+        );
+    }
+
+    /// Test that long output is capped
+    #[cfg_attr(not(doc), test)]
+    fn test_cap_output() {
+        let capped = cap_output("é".repeat(MAX_TOOL_OUTPUT_BYTES));
+        assert!(capped.len() <= MAX_TOOL_OUTPUT_BYTES + 20);
+        assert!(capped.ends_with("[output truncated]"));
+    }
+}
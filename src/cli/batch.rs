@@ -0,0 +1,942 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use log::*; // logging
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process; // needed to exit early
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::api::batch::{
+    add_missing_results, batch_input_ids, download_results, get_batch, submit_batch, BatchJob,
+};
+use crate::api::openai::{http_client, send_to_gpt4, AssistantPurpose, ChatRequest};
+use crate::cli::cancel::cancellable;
+use crate::cli::input::{decode_text, is_binary};
+use crate::cli::output::fail;
+use crate::cli::parse::{
+    build_request, context_dir_arg, context_instructions, truncate_arg, try_build_request,
+};
+use crate::cli::truncate::Truncate;
+use crate::config::models::load_config;
+
+/// # Define Batch Subcommand
+///
+/// `pipe-gpt batch FILE... -p "..." -o results.jsonl` sends one request per input, concurrently
+pub fn batch_command() -> Command {
+    Command::new("batch")
+        .about("Send one request per input file or JSONL line concurrently, writing results as JSONL")
+        .arg(
+            Arg::new("inputs")
+                .value_name("FILE")
+                .help("Files to send, one request each. The file path is the result id")
+                .required_unless_present_any(["jsonl", "fetch"])
+                .num_args(1..)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("jsonl")
+                .long("jsonl")
+                .value_name("prompts.jsonl")
+                .help("Read inputs from JSONL lines of {\"id\": ..., \"prompt\": ..., \"input\": ...}. id and prompt are optional")
+                .required(false)
+                .conflicts_with("inputs")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("results.jsonl")
+                .help("Append a JSONL result for each input here as it completes")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .help("Continue a partially completed batch, skipping inputs that already have a response in the output file")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("concurrency")
+                .short('j')
+                .long("concurrency")
+                .value_name("requests")
+                .help("Most requests in flight at once")
+                .required(false)
+                .default_value("4")
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("rate")
+                .long("rate")
+                .value_name("requests_per_minute")
+                .help("Most requests started per minute")
+                .required(false)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("offline")
+                .long("offline")
+                .help("Submit the inputs to the provider's Batch API instead, at lower cost, and wait for the results. This can take up to 24 hours")
+                .required(false)
+                .conflicts_with_all(["concurrency", "rate"])
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_wait")
+                .long("no-wait")
+                .help("Print the id of the submitted batch and exit, to collect the results later with --fetch")
+                .required(false)
+                .requires("offline")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fetch")
+                .long("fetch")
+                .value_name("BATCH_ID")
+                .help("Wait for a batch submitted with --offline --no-wait and write its results")
+                .required(false)
+                .conflicts_with_all(["inputs", "jsonl", "offline"]),
+        )
+        .arg(
+            Arg::new("poll")
+                .long("poll")
+                .value_name("seconds")
+                .help("How often to check on an --offline or --fetch batch")
+                .required(false)
+                .default_value("60")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("out_dir")
+                .long("out-dir")
+                .value_name("DIR")
+                .help("Also write each response to its own file in this directory, named after its id")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(truncate_arg())
+        .arg(context_dir_arg())
+}
+
+/// # Batch Item
+///
+/// One input to send. `prompt` overrides the `-p` prompt for this item.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BatchItem {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub input: String,
+}
+
+/// # Batch Result
+///
+/// One line of the output file, with either the response or the error
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    pub fn new(id: String, result: Result<String, String>) -> Self {
+        match result {
+            Ok(response) => BatchResult {
+                id,
+                response: Some(response),
+                error: None,
+            },
+            Err(error) => BatchResult {
+                id,
+                response: None,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+/// Reads a file to send, decoding it as text and refusing binary files
+fn read_input_file(path: &Path) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("could not read {:?}: {}", path, e))?;
+    if is_binary(&bytes) {
+        return Err(format!("{:?} looks like binary data", path));
+    }
+    Ok(decode_text(&bytes).0)
+}
+
+/// # Load Batch Items
+///
+/// Reads the inputs from files, or from a JSONL file where ids default to the line number.
+/// Ids must be unique so results can be matched back to inputs.
+pub fn load_items(files: &[PathBuf], jsonl: Option<&Path>) -> Result<Vec<BatchItem>, String> {
+    let items = match jsonl {
+        Some(path) => {
+            let content = fs::read_to_string(path)
+                .map_err(|e| format!("could not read {:?}: {}", path, e))?;
+            let mut items = vec![];
+            for (index, line) in content.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut item: BatchItem = serde_json::from_str(line)
+                    .map_err(|e| format!("line {} of {:?} is not valid: {}", index + 1, path, e))?;
+                if item.id.is_empty() {
+                    item.id = (index + 1).to_string();
+                }
+                items.push(item);
+            }
+            items
+        },
+        None => files
+            .iter()
+            .map(|path| {
+                Ok(BatchItem {
+                    id: path.display().to_string(),
+                    prompt: None,
+                    input: read_input_file(path)?,
+                })
+            })
+            .collect::<Result<Vec<BatchItem>, String>>()?,
+    };
+
+    let mut seen = HashSet::new();
+    for item in &items {
+        if !seen.insert(&item.id) {
+            return Err(format!("the id '{}' is used more than once", item.id));
+        }
+    }
+    Ok(items)
+}
+
+/// # Completed Ids
+///
+/// Ids that already have a response in an earlier output file. Inputs that errored are retried.
+pub fn completed_ids(path: &Path) -> Result<HashSet<String>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(format!("could not read {:?}: {}", path, e)),
+    };
+    Ok(content
+        .lines()
+        // a line cut short by an interrupted run doesn't count
+        .filter_map(|line| serde_json::from_str::<BatchResult>(line).ok())
+        .filter(|result| result.response.is_some())
+        .map(|result| result.id)
+        .collect())
+}
+
+/// # Build Item Request
+///
+/// The request for one item, built as a single run would build it, with `--truncate` and
+/// `--context-dir` applied. Errors, rather than exiting, when the item is over the token limit.
+pub fn item_request(
+    matches: &ArgMatches,
+    default_prompt: &str,
+    item: &BatchItem,
+) -> Result<ChatRequest, String> {
+    let prompt = item.prompt.as_deref().unwrap_or(default_prompt);
+    let purpose = AssistantPurpose::Default;
+    let instructions = context_instructions(prompt, &item.input, 0, matches, &purpose, None)?;
+    try_build_request(
+        prompt,
+        &item.input,
+        &[],
+        matches,
+        &purpose,
+        instructions,
+        matches.get_one::<Truncate>("truncate").copied(),
+    )
+}
+
+/// # Output File Name
+///
+/// The file an item's response is written to with `--out-dir`: its id with anything other than
+/// letters, digits, `.`, `-` and `_` replaced. When that changes the id, a short hash of the
+/// original is added so ids like `a/b` and `a_b` don't share a file: `src/main.rs` becomes
+/// `src_main.rs-1660407c.md`.
+pub fn output_file_name(id: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name == id {
+        format!("{}.md", name)
+    } else {
+        format!("{}-{:08x}.md", name, fnv1a(id))
+    }
+}
+
+/// 32-bit FNV-1a, a hash that stays the same across builds so reruns reuse the same file names
+fn fnv1a(text: &str) -> u32 {
+    text.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Writes results as they arrive and counts the failures
+struct ResultWriter<'a> {
+    file: File,
+    output: &'a Path,
+    out_dir: Option<&'a Path>,
+    total: usize,
+    finished: usize,
+    failures: usize,
+}
+
+impl ResultWriter<'_> {
+    fn record(&mut self, result: BatchResult) {
+        self.finished += 1;
+        if result.error.is_some() {
+            self.failures += 1;
+        }
+        eprintln!(
+            "[{}/{}] {} {}",
+            self.finished,
+            self.total,
+            result.id,
+            result.error.as_deref().unwrap_or("done")
+        );
+        let line = serde_json::to_string(&result).expect("Failed to serialise result");
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.flush())
+            .unwrap_or_else(|e| fail(format!("could not write {:?}: {}", self.output, e)));
+        if let (Some(dir), Some(response)) = (self.out_dir, &result.response) {
+            let path = dir.join(output_file_name(&result.id));
+            fs::write(&path, response)
+                .unwrap_or_else(|e| fail(format!("could not write {:?}: {}", path, e)));
+        }
+    }
+}
+
+/// # Wait For Batch
+///
+/// Checks on a Batch API job every `poll` until it finishes, then downloads its results. The
+/// finished job is returned too, as it may have left some requests without a result.
+pub async fn wait_for_batch(
+    http: &reqwest::Client,
+    api_url: &str,
+    api_key: &str,
+    batch_id: &str,
+    poll: Duration,
+) -> Result<(BatchJob, Vec<(String, Result<String, String>)>), String> {
+    loop {
+        let job = get_batch(http, api_url, api_key, batch_id).await?;
+        match &job.request_counts {
+            Some(counts) => eprintln!(
+                "batch {} is {}: {} of {} complete, {} failed",
+                job.id, job.status, counts.completed, counts.total, counts.failed
+            ),
+            None => eprintln!("batch {} is {}", job.id, job.status),
+        }
+        if job.is_finished() {
+            let results = download_results(http, api_url, api_key, &job).await?;
+            return Ok((job, results));
+        }
+        tokio::time::sleep(poll).await;
+    }
+}
+
+/// Whether the file ends partway through a line, as an interrupted run can leave it
+fn ends_mid_line(file: &mut File) -> io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0; 1];
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// # Open Results File
+///
+/// Opens the output file for appending and returns the ids already completed in it. A file that
+/// already has results is only reused with `--resume`, or by `--fetch`, which never sends
+/// anything again.
+pub fn open_results(
+    output: &Path,
+    resume: bool,
+    fetching: bool,
+) -> Result<(File, HashSet<String>), String> {
+    let done = if resume {
+        completed_ids(output)?
+    } else {
+        if !fetching && fs::metadata(output).is_ok_and(|metadata| metadata.len() > 0) {
+            return Err(format!(
+                "{:?} already has results. Pass --resume to continue that batch",
+                output
+            ));
+        }
+        HashSet::new()
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(output)
+        .map_err(|e| format!("could not open {:?}: {}", output, e))?;
+    // a line cut short by an interrupted run must not run into the next result
+    if ends_mid_line(&mut file).map_err(|e| format!("could not read {:?}: {}", output, e))? {
+        writeln!(file).map_err(|e| format!("could not write {:?}: {}", output, e))?;
+    }
+    Ok((file, done))
+}
+
+/// # Submit Offline Batch
+///
+/// Sends the pending items to the provider's Batch API and returns the batch id with the ids
+/// submitted in it. Items that fail before submission, such as those over the token limit, are
+/// recorded straight away.
+async fn submit_offline(
+    writer: &mut ResultWriter<'_>,
+    pending: Vec<BatchItem>,
+    matches: &ArgMatches,
+    prompt: &str,
+    api_url: &str,
+    api_key: &str,
+) -> Result<(String, Vec<String>), String> {
+    let mut requests = vec![];
+    for item in pending {
+        match item_request(matches, prompt, &item) {
+            Ok(request) => requests.push((item.id, request)),
+            Err(e) => writer.record(BatchResult::new(item.id, Err(e))),
+        }
+    }
+    if requests.is_empty() {
+        return Err("there is nothing to submit".to_string());
+    }
+    let job = cancellable(submit_batch(http_client(), api_url, api_key, &requests)).await?;
+    eprintln!(
+        "submitted {} request(s) as batch {}",
+        requests.len(),
+        job.id
+    );
+    Ok((job.id, requests.into_iter().map(|(id, _)| id).collect()))
+}
+
+/// # Fetch Offline Batch
+///
+/// Waits for a Batch API job to finish and records its results. Each of the `ids` submitted
+/// that the job returned nothing for is recorded as an error, so `--resume` sends it again.
+/// Without `ids`, as with `--fetch`, they are read back from the job's input file.
+async fn fetch_offline(
+    writer: &mut ResultWriter<'_>,
+    batch_id: &str,
+    ids: Option<Vec<String>>,
+    api_url: &str,
+    api_key: &str,
+    poll: Duration,
+) -> Result<(), String> {
+    let (job, results) = cancellable(wait_for_batch(
+        http_client(),
+        api_url,
+        api_key,
+        batch_id,
+        poll,
+    ))
+    .await?;
+    let ids = match ids {
+        Some(ids) => ids,
+        None => cancellable(batch_input_ids(http_client(), api_url, api_key, &job)).await?,
+    };
+    let results = add_missing_results(&job, &ids, results);
+    writer.total = writer.finished + ids.len();
+    for (id, result) in results {
+        writer.record(BatchResult::new(id, result));
+    }
+    Ok(())
+}
+
+/// # Run Batch Subcommand
+///
+/// - Loads the inputs, skipping those already completed when resuming
+/// - Sends up to `--concurrency` requests at once, starting at most `--rate` a minute
+/// - Appends each result to the output file as soon as it arrives, so an interrupted batch can
+///   be resumed
+/// - With `--offline` or `--fetch`, goes through the provider's Batch API instead and writes
+///   the results once the job finishes
+pub async fn batch(matches: &ArgMatches) {
+    let files: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("inputs")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let jsonl = matches.get_one::<PathBuf>("jsonl");
+    let output = matches
+        .get_one::<PathBuf>("output")
+        .expect("output is required");
+    let resume = *matches.get_one::<bool>("resume").unwrap_or(&false);
+    let concurrency = *matches.get_one::<u32>("concurrency").unwrap_or(&4) as usize;
+    let rate = matches.get_one::<u32>("rate").copied();
+    let offline = *matches.get_one::<bool>("offline").unwrap_or(&false);
+    let no_wait = *matches.get_one::<bool>("no_wait").unwrap_or(&false);
+    let fetch = matches.get_one::<String>("fetch");
+    let poll = Duration::from_secs(*matches.get_one::<u64>("poll").unwrap_or(&60));
+    let out_dir = matches.get_one::<PathBuf>("out_dir");
+
+    if let Some(dir) = out_dir {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|e| fail(format!("could not create {:?}: {}", dir, e)));
+    }
+    let items = match fetch {
+        Some(_) => vec![],
+        None => load_items(&files, jsonl.map(|path| path.as_path())).unwrap_or_else(|e| fail(e)),
+    };
+    let (file, done) = open_results(output, resume, fetch.is_some()).unwrap_or_else(|e| fail(e));
+    let pending: Vec<BatchItem> = items
+        .into_iter()
+        .filter(|item| !done.contains(&item.id))
+        .collect();
+    eprintln!(
+        "{} input(s) to send, {} already complete",
+        pending.len(),
+        done.len()
+    );
+
+    let empty_string = String::from("");
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .unwrap_or(&empty_string)
+        .clone();
+    // checks the shared arguments once, exiting early if they are invalid
+    build_request("", "", &[], matches, &AssistantPurpose::Default, None, None);
+
+    let mut writer = ResultWriter {
+        file,
+        output,
+        out_dir: out_dir.map(|dir| dir.as_path()),
+        total: pending.len(),
+        finished: 0,
+        failures: 0,
+    };
+
+    if offline || fetch.is_some() {
+        let api_url = load_config().api_url;
+        let api_key = std::env::var("AI_API_KEY")
+            .unwrap_or_else(|_| fail("the AI_API_KEY environment variable is not set".to_string()));
+        let (batch_id, ids) = match fetch {
+            Some(batch_id) => (batch_id.clone(), None),
+            None => {
+                let (batch_id, ids) =
+                    submit_offline(&mut writer, pending, matches, &prompt, &api_url, &api_key)
+                        .await
+                        .unwrap_or_else(|e| fail(e));
+                if no_wait {
+                    println!("{}", batch_id);
+                    eprintln!(
+                        "Collect the results later with: pipe-gpt batch --fetch {} -o {:?}",
+                        batch_id, output
+                    );
+                    return;
+                }
+                (batch_id, Some(ids))
+            },
+        };
+        fetch_offline(&mut writer, &batch_id, ids, &api_url, &api_key, poll)
+            .await
+            .unwrap_or_else(|e| fail(e));
+    } else {
+        run_concurrently(&mut writer, pending, matches, &prompt, concurrency, rate).await;
+    }
+
+    debug!("batch finished with {} failure(s)", writer.failures);
+    if writer.failures > 0 {
+        eprintln!(
+            "{} of {} input(s) failed. Run again with --resume to retry them.",
+            writer.failures, writer.total
+        );
+        process::exit(1);
+    }
+}
+
+/// Sends the items directly, up to `concurrency` at once and `rate` a minute
+async fn run_concurrently(
+    writer: &mut ResultWriter<'_>,
+    pending: Vec<BatchItem>,
+    matches: &ArgMatches,
+    prompt: &str,
+    concurrency: usize,
+    rate: Option<u32>,
+) {
+    cancellable(async {
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let mut interval = rate.map(|rate| {
+            let mut interval = tokio::time::interval(Duration::from_secs(60) / rate);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+        let mut tasks = JoinSet::new();
+
+        for item in pending {
+            let permit = semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            if let Some(interval) = interval.as_mut() {
+                interval.tick().await;
+            }
+            // write whatever has finished while waiting for a slot
+            while let Some(joined) = tasks.try_join_next() {
+                writer.record(joined.expect("batch task panicked"));
+            }
+
+            match item_request(matches, prompt, &item) {
+                Ok(request) => {
+                    tasks.spawn(async move {
+                        let result = send_to_gpt4(&request).await.map_err(|e| e.to_string());
+                        drop(permit);
+                        BatchResult::new(item.id, result)
+                    });
+                },
+                Err(e) => writer.record(BatchResult::new(item.id, Err(e))),
+            }
+        }
+        while let Some(joined) = tasks.join_next().await {
+            writer.record(joined.expect("batch task panicked"));
+        }
+    })
+    .await;
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+    use crate::cli::parse::setup_arguments;
+
+    /// Test that inputs load from files or JSONL and duplicate ids are refused
+    #[cfg_attr(not(doc), test)]
+    fn test_load_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.rs");
+        fs::write(&a, "fn a() {}").unwrap();
+        let binary = dir.path().join("app");
+        fs::write(&binary, b"\x7fELF\x02\x01\x01\0\0\0\0\0").unwrap();
+
+        let items = load_items(std::slice::from_ref(&a), None).unwrap();
+        assert_eq!(items[0].id, a.display().to_string());
+        assert_eq!(items[0].input, "fn a() {}");
+        assert!(load_items(&[binary], None).is_err());
+
+        let jsonl = dir.path().join("prompts.jsonl");
+        fs::write(
+            &jsonl,
+            "{\"id\": \"x\", \"input\": \"one\"}\n\n{\"prompt\": \"Translate\", \"input\": \"two\"}\n",
+        )
+        .unwrap();
+        let items = load_items(&[], Some(&jsonl)).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].id, "3");
+        assert_eq!(items[1].prompt.as_deref(), Some("Translate"));
+
+        fs::write(&jsonl, "{\"id\": \"x\"}\n{\"id\": \"x\"}\n").unwrap();
+        assert!(load_items(&[], Some(&jsonl))
+            .unwrap_err()
+            .contains("more than once"));
+    }
+
+    /// Test that only inputs with a response count as complete when resuming
+    #[cfg_attr(not(doc), test)]
+    fn test_completed_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("results.jsonl");
+        assert!(completed_ids(&output).unwrap().is_empty());
+
+        fs::write(
+            &output,
+            "{\"id\":\"a\",\"response\":\"ok\"}\n{\"id\":\"b\",\"error\":\"timed out\"}\n{\"id\":\"c\",\"resp",
+        )
+        .unwrap();
+        assert_eq!(
+            completed_ids(&output).unwrap(),
+            HashSet::from(["a".to_string()])
+        );
+    }
+
+    /// Test that each item gets its own conversation and oversized items are errors
+    #[cfg_attr(not(doc), test)]
+    fn test_item_request() {
+        let matches = setup_arguments().get_matches_from(["pipe-gpt", "-m", "50"]);
+        let item = BatchItem {
+            id: "1".to_string(),
+            prompt: Some("Summarise".to_string()),
+            input: "some text".to_string(),
+        };
+
+        let request = item_request(&matches, "Review", &item).unwrap();
+        assert_eq!(request.messages.len(), 3);
+        assert_eq!(request.messages[1].content, "Summarise");
+        assert_eq!(request.messages[2].content, "some text");
+        assert_eq!(request.max_tokens, Some(50));
+
+        let big = BatchItem {
+            input: "word ".repeat(100),
+            ..item
+        };
+        assert!(item_request(&matches, "Review", &big)
+            .unwrap_err()
+            .contains("over the limit of 50"));
+
+        let matches =
+            setup_arguments().get_matches_from(["pipe-gpt", "-m", "50", "--truncate", "head"]);
+        let request = item_request(&matches, "Review", &big).unwrap();
+        assert!(request.messages[2].content != big.input.as_str());
+    }
+
+    /// Test that a batch submitted with --no-wait can be fetched into the same output file after
+    /// an item failed before submission, and after an interrupted write
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_offline_no_wait_then_fetch() {
+        let api_url = crate::api::batch::tests::mock_batch_api();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("results.jsonl");
+        let matches = setup_arguments().get_matches_from(["pipe-gpt", "-m", "50"]);
+        let items = vec![
+            BatchItem {
+                id: "a.rs".to_string(),
+                prompt: None,
+                input: "fn a() {}".to_string(),
+            },
+            BatchItem {
+                id: "big.rs".to_string(),
+                prompt: None,
+                input: "word ".repeat(100),
+            },
+        ];
+
+        let (file, _) = open_results(&output, false, false).unwrap();
+        let mut writer = ResultWriter {
+            file,
+            output: &output,
+            out_dir: None,
+            total: items.len(),
+            finished: 0,
+            failures: 0,
+        };
+        let (batch_id, ids) =
+            submit_offline(&mut writer, items, &matches, "Review", &api_url, "key")
+                .await
+                .unwrap();
+        assert_eq!(ids, vec!["a.rs".to_string()]);
+        assert_eq!(writer.failures, 1);
+        drop(writer);
+        let mut file = OpenOptions::new().append(true).open(&output).unwrap();
+        write!(file, "{{\"id\":\"cut").unwrap();
+
+        assert!(open_results(&output, false, false).is_err());
+        let (file, _) = open_results(&output, false, true).unwrap();
+        let mut writer = ResultWriter {
+            file,
+            output: &output,
+            out_dir: None,
+            total: 0,
+            finished: 0,
+            failures: 0,
+        };
+        fetch_offline(
+            &mut writer,
+            &batch_id,
+            None,
+            &api_url,
+            "key",
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+
+        let results: Vec<BatchResult> = fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].id, "big.rs");
+        assert!(results[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("over the limit"));
+        assert_eq!(
+            results[1],
+            BatchResult::new("a.rs".to_string(), Ok("reply to a.rs".to_string()))
+        );
+    }
+
+    /// Submits the items to the mock Batch API ending in `status`, then fetches the job with or
+    /// without the submitted ids, and returns the results written and the failure count
+    async fn submit_then_fetch(
+        status: &'static str,
+        ids: &[&str],
+        pass_ids: bool,
+    ) -> (Vec<BatchResult>, usize) {
+        let api_url = crate::api::batch::tests::mock_batch_api_ending(status);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("results.jsonl");
+        let matches = setup_arguments().get_matches_from(["pipe-gpt"]);
+        let items: Vec<BatchItem> = ids
+            .iter()
+            .map(|id| BatchItem {
+                id: id.to_string(),
+                prompt: None,
+                input: "fn a() {}".to_string(),
+            })
+            .collect();
+
+        let (file, _) = open_results(&output, false, false).unwrap();
+        let mut writer = ResultWriter {
+            file,
+            output: &output,
+            out_dir: None,
+            total: items.len(),
+            finished: 0,
+            failures: 0,
+        };
+        let (batch_id, submitted) =
+            submit_offline(&mut writer, items, &matches, "Review", &api_url, "key")
+                .await
+                .unwrap();
+        fetch_offline(
+            &mut writer,
+            &batch_id,
+            pass_ids.then_some(submitted),
+            &api_url,
+            "key",
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(writer.total, ids.len());
+
+        let results = fs::read_to_string(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (results, writer.failures)
+    }
+
+    /// Test that every request of a failed job, which returns no results, is recorded as an
+    /// error with the job's errors, so the run fails and --resume retries them
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_fetch_failed_batch() {
+        let (results, failures) = submit_then_fetch("failed", &["a.rs", "b.rs"], true).await;
+
+        assert_eq!(failures, 2);
+        assert_eq!(
+            results,
+            vec![
+                BatchResult::new(
+                    "a.rs".to_string(),
+                    Err(
+                        "no result returned by batch batch_1 (failed): line 1: model is required"
+                            .to_string()
+                    )
+                ),
+                BatchResult::new(
+                    "b.rs".to_string(),
+                    Err(
+                        "no result returned by batch batch_1 (failed): line 1: model is required"
+                            .to_string()
+                    )
+                ),
+            ]
+        );
+    }
+
+    /// Test that requests an expired job left out are recorded as errors, including when the
+    /// ids are read back from the job's input file as --fetch does
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_fetch_expired_batch() {
+        for pass_ids in [true, false] {
+            let (results, failures) =
+                submit_then_fetch("expired", &["a.rs", "late.rs"], pass_ids).await;
+
+            assert_eq!(failures, 1);
+            assert_eq!(
+                results,
+                vec![
+                    BatchResult::new("a.rs".to_string(), Ok("reply to a.rs".to_string())),
+                    BatchResult::new(
+                        "late.rs".to_string(),
+                        Err("no result returned by batch batch_1 (expired)".to_string())
+                    ),
+                ]
+            );
+        }
+    }
+
+    /// Test that ids become safe file names inside the output directory
+    #[cfg_attr(not(doc), test)]
+    fn test_output_file_name() {
+        assert_eq!(output_file_name("src/main.rs"), "src_main.rs-1660407c.md");
+        assert_eq!(output_file_name("../etc/passwd"), "_etc_passwd-62dab22f.md");
+        assert_eq!(output_file_name("3"), "3.md");
+        assert_eq!(output_file_name("a_b"), "a_b.md");
+        assert_ne!(output_file_name("a/b"), output_file_name("a_b"));
+        assert_ne!(output_file_name("a/b"), output_file_name("a b"));
+        assert_eq!(fnv1a(""), 0x811c9dc5);
+        assert_eq!(fnv1a("a"), 0xe40c292c);
+    }
+
+    /// Test that an offline batch is polled until it completes and its results demultiplexed
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_wait_for_batch() {
+        let api_url = crate::api::batch::tests::mock_batch_api();
+        let http = reqwest::Client::new();
+        let matches = setup_arguments().get_matches_from(["pipe-gpt"]);
+        let items = [("a.rs", "fn a() {}"), ("bad.rs", "fn b() {}")];
+        let requests: Vec<(String, ChatRequest)> = items
+            .iter()
+            .map(|(id, input)| {
+                let item = BatchItem {
+                    id: id.to_string(),
+                    prompt: None,
+                    input: input.to_string(),
+                };
+                (
+                    item.id.clone(),
+                    item_request(&matches, "Review", &item).unwrap(),
+                )
+            })
+            .collect();
+
+        let job = submit_batch(&http, &api_url, "key", &requests)
+            .await
+            .unwrap();
+        let (job, results) =
+            wait_for_batch(&http, &api_url, "key", &job.id, Duration::from_millis(10))
+                .await
+                .unwrap();
+        assert_eq!(job.status, "completed");
+        assert_eq!(
+            results,
+            vec![
+                ("a.rs".to_string(), Ok("reply to a.rs".to_string())),
+                (
+                    "bad.rs".to_string(),
+                    Err("status 400: bad request".to_string())
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use std::future::Future;
+use std::process; // needed to exit early
+
+/// Exit code for a run interrupted by Ctrl-C, as shells report for SIGINT
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+/// # Cancel On Ctrl-C
+///
+/// Runs the future to completion unless Ctrl-C is pressed first, in which case the future is
+/// dropped, aborting any request in flight, and the process exits with [CANCELLED_EXIT_CODE]
+pub async fn cancellable<F: Future>(future: F) -> F::Output {
+    tokio::select! {
+        output = future => output,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("Cancelled.");
+            process::exit(CANCELLED_EXIT_CODE);
+        },
+    }
+}
@@ -0,0 +1,491 @@
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::*; // logging
+use regex::Regex;
+use serde_json::{json, Value};
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process; // needed to exit early
+use std::str::FromStr;
+
+use crate::api::openai::{AssistantPurpose, ResponseFormat};
+use crate::api::schema::{json_instructions, send_with_schema};
+use crate::cli::cancel::cancellable;
+use crate::cli::output::{ask, confirm, fail};
+use crate::cli::parse::build_request;
+
+/// # Define Cmd Subcommand
+///
+/// `pipe-gpt cmd "find large log files"` suggests a shell command and runs it once confirmed
+pub fn cmd_command() -> Command {
+    Command::new("cmd")
+        .about("Ask for a shell command that does a task, show it with an explanation and its risk, and run it after confirmation")
+        .arg(
+            Arg::new("task")
+                .value_name("TASK")
+                .help("What the command should do")
+                .required(true)
+                .num_args(1..),
+        )
+        .arg(
+            Arg::new("print")
+                .long("print")
+                .help("Print the command to stdout instead of running it")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+}
+
+/// # Risk
+///
+/// How much harm running a command could do, from the model's view or local checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Risk {
+    Low,
+    Medium,
+    High,
+}
+
+impl FromStr for Risk {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Risk::Low),
+            "medium" => Ok(Risk::Medium),
+            "high" => Ok(Risk::High),
+            _ => Err(format!("unknown risk '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Risk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Risk::Low => write!(f, "low"),
+            Risk::Medium => write!(f, "medium"),
+            Risk::High => write!(f, "high"),
+        }
+    }
+}
+
+/// Patterns that make a command risky whatever the model says, and why
+const RISKY_PATTERNS: [(Risk, &str, &str); 17] = [
+    (
+        Risk::High,
+        r"\brm\s+(-\S*[rRf]|--recursive|--force)",
+        "deletes recursively or without asking",
+    ),
+    (Risk::High, r"\bdd\b", "writes raw data with dd"),
+    (Risk::High, r"\bmkfs", "formats a filesystem"),
+    (
+        Risk::High,
+        r"\b(shred|wipefs|fdisk|parted)\b",
+        "destroys or repartitions disk data",
+    ),
+    (
+        Risk::High,
+        r">\s*/dev/(sd|nvme|disk|hd)",
+        "writes directly to a disk device",
+    ),
+    (Risk::High, r":\(\)\s*\{", "is a fork bomb"),
+    (Risk::High, r"\b(sudo|doas)\b", "runs as root"),
+    (
+        Risk::High,
+        r"\bchmod\s+(-\S*R|--recursive)|\bchown\s+(-\S*R|--recursive)",
+        "changes permissions recursively",
+    ),
+    (
+        Risk::High,
+        r"-delete\b|-exec\s+rm\b",
+        "deletes the files it finds",
+    ),
+    (
+        Risk::High,
+        r"\bgit\s+(push\s+.*(-f\b|--force)|reset\s+--hard|clean\s+-\S*f)",
+        "discards or overwrites git history or work",
+    ),
+    (
+        Risk::High,
+        r"\b(curl|wget)\b.*\|\s*(sudo\s+)?(ba|z)?sh\b",
+        "runs a script downloaded from the internet",
+    ),
+    (
+        Risk::High,
+        r"\b(truncate|kill\s+-9\s+-1|killall|pkill|shutdown|reboot)\b",
+        "stops processes or the machine, or empties files",
+    ),
+    (Risk::Medium, r"\brm\b", "deletes files"),
+    (
+        Risk::Medium,
+        r"\bmv\b",
+        "moves or renames files, possibly over others",
+    ),
+    (
+        Risk::Medium,
+        r"\bsed\s+(-\S*i|--in-place)",
+        "edits files in place",
+    ),
+    (
+        Risk::Medium,
+        r"\b(kill|chmod|chown)\b",
+        "changes processes or permissions",
+    ),
+    (
+        Risk::Medium,
+        r"\bxargs\b",
+        "runs a command for each input line",
+    ),
+];
+
+/// # Redirect Targets
+///
+/// Files a command writes to with `>`, not counting appends with `>>` or `2>&1` style
+/// duplications
+pub fn redirect_targets(command: &str) -> Vec<String> {
+    let redirect = Regex::new(r#"(?:^|[^>&])\d?>\|?\s*([^\s;&|<>()]+)"#).unwrap();
+    redirect
+        .captures_iter(command)
+        .map(|captures| captures[1].trim_matches(['"', '\'']).to_string())
+        .filter(|target| !target.starts_with('&') && target != "/dev/null")
+        .collect()
+}
+
+/// # Copy Targets
+///
+/// Files `tee` writes to without `-a`, and the destination of `cp` without `-n`, as these
+/// replace the contents of a file that exists
+pub fn copy_targets(command: &str) -> Vec<String> {
+    let mut targets = vec![];
+    for segment in Regex::new(r"[|;&]+").unwrap().split(command) {
+        let mut words = segment
+            .split_whitespace()
+            .map(|word| word.trim_matches(['"', '\'']))
+            .skip_while(|word| matches!(*word, "sudo" | "doas"))
+            // a redirect ends the arguments, and is checked separately
+            .take_while(|word| !word.contains(['<', '>']));
+        let Some(program) = words.next() else {
+            continue;
+        };
+        let (flags, args): (Vec<&str>, Vec<&str>) = words.partition(|word| word.starts_with('-'));
+        match program {
+            "tee"
+                if !flags
+                    .iter()
+                    .any(|flag| *flag == "--append" || is_short_flag(flag, 'a')) =>
+            {
+                targets.extend(args.iter().map(|arg| arg.to_string()))
+            },
+            "cp" if !flags
+                .iter()
+                .any(|flag| *flag == "--no-clobber" || is_short_flag(flag, 'n')) =>
+            {
+                if let [sources @ .., destination] = args.as_slice() {
+                    if !sources.is_empty() {
+                        targets.push(destination.to_string());
+                        // copying into a directory overwrites files of the same name there
+                        targets.extend(sources.iter().filter_map(|source| {
+                            Path::new(source).file_name().map(|name| {
+                                format!(
+                                    "{}/{}",
+                                    destination.trim_end_matches('/'),
+                                    name.to_string_lossy()
+                                )
+                            })
+                        }));
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    targets
+}
+
+/// Whether a flag like `-fa` includes the short option
+fn is_short_flag(flag: &str, option: char) -> bool {
+    !flag.starts_with("--") && flag[1..].contains(option)
+}
+
+/// Expands a leading `~`, `$HOME` or `${HOME}` in a target, or `None` if it still depends on
+/// the shell, such as another variable, a glob or `~user`
+fn resolve_target(target: &str, cwd: &Path) -> Option<PathBuf> {
+    let home = || env::var("HOME").ok();
+    let expanded = if target == "~" || target.starts_with("~/") {
+        format!("{}{}", home()?, &target[1..])
+    } else if let Some(rest) = target.strip_prefix("${HOME}") {
+        format!("{}{}", home()?, rest)
+    } else if let Some(rest) = target.strip_prefix("$HOME") {
+        format!("{}{}", home()?, rest)
+    } else {
+        target.to_string()
+    };
+    if expanded.contains(['$', '`', '*', '?', '[', '~']) {
+        return None;
+    }
+    Some(cwd.join(expanded))
+}
+
+/// # Classify Command
+///
+/// The risk of a command from local checks, with the reasons. Redirects, `tee` and `cp` over
+/// files that exist in `cwd` are high risk, as the contents are lost. A target that can't be
+/// resolved without running the shell is noted and treated as medium risk.
+pub fn classify(command: &str, cwd: &Path) -> (Risk, Vec<String>) {
+    let mut risk = Risk::Low;
+    let mut reasons = vec![];
+    for (pattern_risk, pattern, reason) in RISKY_PATTERNS {
+        // a milder pattern for the same program adds nothing once a worse one matched
+        if pattern_risk < risk {
+            continue;
+        }
+        if Regex::new(pattern).unwrap().is_match(command) {
+            risk = risk.max(pattern_risk);
+            reasons.push(reason.to_string());
+        }
+    }
+    let mut targets = redirect_targets(command);
+    targets.extend(copy_targets(command));
+    for target in targets {
+        match resolve_target(&target, cwd) {
+            Some(path) if path.is_file() => {
+                risk = Risk::High;
+                reasons.push(format!("overwrites the existing file {}", target));
+            },
+            Some(_) => {},
+            None => {
+                risk = risk.max(Risk::Medium);
+                reasons.push(format!(
+                    "writes to {}, which could not be checked for an existing file",
+                    target
+                ));
+            },
+        }
+    }
+    (risk, reasons)
+}
+
+/// The shape of the reply, enforced with [send_with_schema]
+fn command_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "command": {"type": "string", "minLength": 1},
+            "explanation": {"type": "string"},
+            "risk": {"enum": ["low", "medium", "high"]}
+        },
+        "required": ["command", "explanation", "risk"],
+        "additionalProperties": false
+    })
+}
+
+/// The shell commands are run with
+#[cfg(not(windows))]
+fn shell() -> (&'static str, &'static str) {
+    ("sh", "-c")
+}
+
+/// The shell commands are run with
+#[cfg(windows)]
+fn shell() -> (&'static str, &'static str) {
+    ("cmd", "/C")
+}
+
+/// Printed when there is no terminal to confirm the command on
+const PRINT_HINT: &str = "Pass --print to only print it.";
+
+/// Asks for confirmation on the terminal. High risk commands need the whole word `yes`.
+fn confirm_risk(risk: Risk) -> bool {
+    match risk {
+        Risk::High => {
+            let answer = ask(
+                "This command is high risk. Type 'yes' to run it: ",
+                PRINT_HINT,
+            );
+            answer.as_deref() == Some("yes")
+        },
+        _ => confirm("Run this command?", PRINT_HINT),
+    }
+}
+
+/// # Run Cmd Subcommand
+///
+/// - Asks for a single command as JSON with an explanation and risk
+/// - Raises the risk if local checks find destructive patterns
+/// - Shows all of it and runs the command with the shell only once confirmed, exiting with its
+///   exit code
+pub async fn cmd(matches: &ArgMatches) {
+    let task = matches
+        .get_many::<String>("task")
+        .expect("task is required")
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(" ");
+    let print = *matches.get_one::<bool>("print").unwrap_or(&false);
+
+    let (program, _) = shell();
+    let schema = command_schema();
+    let instructions = format!(
+        "{}\nThe command will be run with `{}` on {}, in the current directory.",
+        json_instructions(Some(&schema)),
+        program,
+        std::env::consts::OS
+    );
+    let mut chat_request = build_request(
+        &task,
+        "",
+        &[],
+        matches,
+        &AssistantPurpose::ShellCommand,
+        Some(instructions),
+        None,
+    );
+    chat_request.response_format = Some(ResponseFormat::JsonObject);
+
+    let reply = cancellable(send_with_schema(chat_request, &schema))
+        .await
+        .unwrap_or_else(|e| fail(e));
+    debug!("cmd reply: {}", reply);
+    let command = reply["command"].as_str().unwrap_or_default().trim();
+    let explanation = reply["explanation"].as_str().unwrap_or_default();
+    let model_risk = reply["risk"]
+        .as_str()
+        .and_then(|risk| risk.parse::<Risk>().ok())
+        .unwrap_or(Risk::High);
+
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let (local_risk, reasons) = classify(command, &cwd);
+    let risk = model_risk.max(local_risk);
+
+    eprintln!("\n    {}\n", command);
+    eprintln!("{}\n", explanation);
+    eprintln!("Risk: {}", risk);
+    for reason in &reasons {
+        eprintln!("  - {}", reason);
+    }
+
+    if print {
+        println!("{}", command);
+        return;
+    }
+    if !confirm_risk(risk) {
+        eprintln!("Not run.");
+        return;
+    }
+
+    let (program, flag) = shell();
+    let status = process::Command::new(program)
+        .arg(flag)
+        .arg(command)
+        .status()
+        .unwrap_or_else(|e| fail(format!("could not start {}: {}", program, e)));
+    process::exit(status.code().unwrap_or(1));
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Test that destructive patterns are flagged and read-only commands are not
+    #[cfg_attr(not(doc), test)]
+    fn test_classify() {
+        let cwd = Path::new("/nonexistent");
+        for command in [
+            "find /var/log -name '*.log' -mtime +7 -size +100M",
+            "du -sh * | sort -h",
+            "grep -rn TODO src 2>&1 > /dev/null",
+            "ls -la >> listing.txt",
+        ] {
+            assert_eq!(classify(command, cwd), (Risk::Low, vec![]), "{}", command);
+        }
+        for command in [
+            "rm -rf build",
+            "find . -name '*.tmp' -delete",
+            "dd if=/dev/zero of=/dev/sda",
+            "curl -fsSL https://example.com/install.sh | sh",
+            "sudo apt remove nginx",
+            "git reset --hard HEAD~1",
+        ] {
+            assert_eq!(classify(command, cwd).0, Risk::High, "{}", command);
+        }
+        assert_eq!(
+            classify("mv a.txt b.txt", cwd),
+            (
+                Risk::Medium,
+                vec!["moves or renames files, possibly over others".to_string()]
+            )
+        );
+        assert_eq!(
+            classify("rm -rf build", cwd).1,
+            vec!["deletes recursively or without asking".to_string()]
+        );
+    }
+
+    /// Test that only redirects over files that already exist are flagged
+    #[cfg_attr(not(doc), test)]
+    fn test_redirect_over_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "keep me").unwrap();
+
+        assert_eq!(
+            redirect_targets("sort notes.txt > notes.txt 2>&1 && echo hi>'out.txt' >> log"),
+            vec!["notes.txt", "out.txt"]
+        );
+        let (risk, reasons) = classify("sort notes.txt > notes.txt", dir.path());
+        assert_eq!(risk, Risk::High);
+        assert_eq!(reasons, vec!["overwrites the existing file notes.txt"]);
+        assert_eq!(
+            classify("sort notes.txt > sorted.txt", dir.path()).0,
+            Risk::Low
+        );
+    }
+
+    /// Test that tee and cp over existing files are flagged, and home paths are expanded
+    #[cfg_attr(not(doc), test)]
+    fn test_copy_over_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "keep me").unwrap();
+        fs::create_dir(dir.path().join("backup")).unwrap();
+        fs::write(dir.path().join("backup/notes.txt"), "old").unwrap();
+
+        assert_eq!(
+            copy_targets("echo hi | sudo tee -i a.txt b.txt > /dev/null; tee -a log.txt"),
+            vec!["a.txt", "b.txt"]
+        );
+        assert_eq!(
+            copy_targets("cp -r src/notes.txt backup/ && cp -n a b"),
+            vec!["backup/", "backup/notes.txt"]
+        );
+        let overwrites = |command| classify(command, dir.path());
+        assert_eq!(
+            overwrites("echo hi | tee notes.txt").1,
+            vec!["overwrites the existing file notes.txt"]
+        );
+        assert_eq!(overwrites("echo hi | tee -a notes.txt").0, Risk::Low);
+        assert_eq!(
+            overwrites("cp other/notes.txt backup").1,
+            vec!["overwrites the existing file backup/notes.txt"]
+        );
+        assert_eq!(overwrites("cp notes.txt copy.txt").0, Risk::Low);
+
+        let home = env::var("HOME").unwrap();
+        let target = resolve_target("~/.bashrc", Path::new("/tmp")).unwrap();
+        assert_eq!(target, Path::new(&home).join(".bashrc"));
+        assert_eq!(
+            resolve_target("${HOME}/x", Path::new("/tmp")).unwrap(),
+            Path::new(&home).join("x")
+        );
+        assert_eq!(resolve_target("$OUT/report.txt", Path::new("/tmp")), None);
+        assert_eq!(
+            overwrites("date > $OUT/report.txt"),
+            (
+                Risk::Medium,
+                vec![
+                    "writes to $OUT/report.txt, which could not be checked for an existing file"
+                        .to_string()
+                ]
+            )
+        );
+    }
+}
@@ -0,0 +1,397 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use log::*; // logging
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process; // needed to exit early
+
+use crate::api::openai::{count_tokens, send_to_gpt4, AssistantPurpose, Message, Role};
+use crate::cli::cancel::cancellable;
+use crate::cli::output::fail;
+use crate::cli::parse::{build_request, token_limit};
+use crate::cli::truncate::{truncate_to_tokens, Truncate};
+
+/// Number of times a message is requested before giving up on validation
+pub const COMMIT_MSG_MAX_ATTEMPTS: usize = 3;
+
+/// Marks a hook as written by `pipe-gpt hook install`, so it is safe to replace or remove
+const HOOK_MARKER: &str = "# installed by pipe-gpt hook install";
+
+/// Commit types allowed by the Conventional Commits template
+const COMMIT_TYPES: &str = "feat|fix|docs|style|refactor|perf|test|build|ci|chore|revert";
+
+/// # Define Commit Message Subcommand
+///
+/// `pipe-gpt commit-msg` writes a message for the staged changes, or fills in git's message
+/// file when run as a `prepare-commit-msg` hook
+pub fn commit_msg_command() -> Command {
+    Command::new("commit-msg")
+        .about("Write a Conventional Commits message for the staged changes")
+        .arg(
+            Arg::new("message_file")
+                .value_name("MESSAGE_FILE")
+                .help("Write the message into this file, as a prepare-commit-msg hook does. Prints it when omitted")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("source")
+                .value_name("SOURCE")
+                .help("Where git says the message came from. Nothing is written unless it is empty, so messages given with -m, merges and amends are left alone")
+                .required(false),
+        )
+        .arg(
+            Arg::new("commit")
+                .value_name("SHA")
+                .help("Commit being amended, passed by git")
+                .required(false),
+        )
+        .arg(
+            Arg::new("max_subject")
+                .long("max_subject")
+                .value_name("characters")
+                .help("Longest allowed first line")
+                .required(false)
+                .default_value("72")
+                .value_parser(value_parser!(usize)),
+        )
+}
+
+/// # Define Hook Subcommand
+///
+/// `pipe-gpt hook install` sets up `commit-msg` as the repository's prepare-commit-msg hook
+pub fn hook_command() -> Command {
+    Command::new("hook")
+        .about("Install or remove the prepare-commit-msg git hook that runs `pipe-gpt commit-msg`")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("install")
+                .about("Install the hook in the current repository")
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Replace an existing prepare-commit-msg hook")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(Command::new("uninstall").about("Remove the hook if pipe-gpt installed it"))
+}
+
+/// # Clean Reply
+///
+/// The message without a surrounding code fence or trailing whitespace
+pub fn clean_reply(reply: &str) -> String {
+    let trimmed = reply.trim();
+    let unfenced = match trimmed.strip_prefix("```") {
+        Some(rest) => rest
+            .split_once('\n')
+            .map(|(_, body)| body)
+            .unwrap_or_default()
+            .trim_end()
+            .trim_end_matches("```"),
+        None => trimmed,
+    };
+    unfenced
+        .trim()
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// # Validate Commit Message
+///
+/// Checks the first line follows the Conventional Commits template and fits in `max_subject`
+/// characters, and that a body is separated from it by a blank line
+pub fn validate_commit_message(message: &str, max_subject: usize) -> Result<(), String> {
+    let mut lines = message.lines();
+    let subject = lines.next().unwrap_or_default();
+    let pattern = Regex::new(&format!(r"^({})(\([\w./-]+\))?!?: \S", COMMIT_TYPES)).unwrap();
+    if !pattern.is_match(subject) {
+        return Err(format!(
+            "The first line \"{}\" must be `type(scope): subject` where type is one of {}.",
+            subject,
+            COMMIT_TYPES.replace('|', ", ")
+        ));
+    }
+    let length = subject.chars().count();
+    if length > max_subject {
+        return Err(format!(
+            "The first line is {} characters long, it must be at most {}.",
+            length, max_subject
+        ));
+    }
+    if lines.next().is_some_and(|line| !line.is_empty()) {
+        return Err("The first line must be followed by a blank line before the body.".to_string());
+    }
+    Ok(())
+}
+
+/// # Write Message File
+///
+/// Puts the message above what git already wrote, such as the commented list of changes
+pub fn write_message_file(path: &Path, message: &str) -> Result<(), String> {
+    let existing = fs::read_to_string(path).unwrap_or_default();
+    fs::write(path, format!("{}\n{}", message, existing))
+        .map_err(|e| format!("could not write {:?}: {}", path, e))
+}
+
+/// # Install Hook
+///
+/// Writes a prepare-commit-msg hook that runs `pipe-gpt commit-msg`. A failure never blocks the
+/// commit. An existing hook is only replaced if pipe-gpt wrote it or `force` is set.
+pub fn install_hook(path: &Path, force: bool) -> Result<(), String> {
+    if let Ok(existing) = fs::read_to_string(path) {
+        if !force && !existing.contains(HOOK_MARKER) {
+            return Err(format!(
+                "{:?} already exists. Pass --force to replace it",
+                path
+            ));
+        }
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("could not create {:?}: {}", dir, e))?;
+    }
+    let script = format!(
+        "#!/bin/sh\n{}\npipe-gpt commit-msg \"$@\" || true\n",
+        HOOK_MARKER
+    );
+    fs::write(path, script).map_err(|e| format!("could not write {:?}: {}", path, e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("could not make {:?} executable: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// # Uninstall Hook
+///
+/// Removes the hook if pipe-gpt wrote it
+pub fn uninstall_hook(path: &Path) -> Result<(), String> {
+    match fs::read_to_string(path) {
+        Ok(existing) if existing.contains(HOOK_MARKER) => {
+            fs::remove_file(path).map_err(|e| format!("could not remove {:?}: {}", path, e))
+        },
+        Ok(_) => Err(format!("{:?} was not installed by pipe-gpt", path)),
+        Err(_) => Err(format!("{:?} does not exist", path)),
+    }
+}
+
+/// Runs git in the current directory and returns its stdout
+pub fn git(args: &[&str]) -> Result<String, String> {
+    let output = process::Command::new("git")
+        .args(args)
+        .output()
+        .map_err(|e| format!("could not run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// # Generate Commit Message
+///
+/// Sends the diff and validates the reply, feeding the problems back and retrying up to
+/// [COMMIT_MSG_MAX_ATTEMPTS] times
+async fn generate(matches: &ArgMatches, diff: &str, max_subject: usize) -> Result<String, String> {
+    let empty_string = String::from("");
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .unwrap_or(&empty_string);
+    let instructions = format!("Keep the first line within {} characters.", max_subject);
+
+    // a large diff is cut down rather than refused, so the hook always has something to work with
+    let fixed_tokens = count_tokens(&format!(
+        "{}{}{}",
+        AssistantPurpose::CommitMessage,
+        prompt,
+        instructions
+    ));
+    let budget = token_limit(matches).saturating_sub(fixed_tokens + 16);
+    let diff = truncate_to_tokens(diff, budget, Truncate::Smart);
+    let request = format!("{}\n\n```diff\n{}\n```", prompt, diff);
+
+    let mut chat_request = build_request(
+        request.trim(),
+        "",
+        &[],
+        matches,
+        &AssistantPurpose::CommitMessage,
+        Some(instructions),
+        None,
+    );
+    let mut last_error = String::new();
+    for attempt in 1..=COMMIT_MSG_MAX_ATTEMPTS {
+        let reply = send_to_gpt4(&chat_request)
+            .await
+            .map_err(|e| e.to_string())?;
+        let message = clean_reply(&reply);
+        match validate_commit_message(&message, max_subject) {
+            Ok(()) => return Ok(message),
+            Err(e) => {
+                warn!(
+                    "attempt {} failed commit message validation: {}",
+                    attempt, e
+                );
+                chat_request
+                    .messages
+                    .push(Message::new(Role::Assistant, reply));
+                chat_request.messages.push(Message::new(
+                    Role::User,
+                    format!("{}\nReply again with only the corrected commit message.", e),
+                ));
+                last_error = e;
+            },
+        }
+    }
+    Err(format!(
+        "No valid commit message after {} attempts. {}",
+        COMMIT_MSG_MAX_ATTEMPTS, last_error
+    ))
+}
+
+/// # Run Commit Message Subcommand
+///
+/// - Reads the staged diff
+/// - Asks for a Conventional Commits message, checking the subject length and format
+/// - Prints it, or writes it into git's message file when run as a hook. As a hook, problems
+///   are warnings so the commit can go ahead with a message written by hand.
+pub async fn commit_msg(matches: &ArgMatches) {
+    let message_file = matches.get_one::<PathBuf>("message_file");
+    let source = matches.get_one::<String>("source");
+    let max_subject = *matches.get_one::<usize>("max_subject").unwrap_or(&72);
+
+    let fail_or_warn = |message: String| -> ! {
+        if message_file.is_some() {
+            eprintln!(
+                "Warning: pipe-gpt could not write a commit message: {}",
+                message
+            );
+            process::exit(0);
+        }
+        fail(message)
+    };
+
+    if message_file.is_some() && source.is_some_and(|source| !source.is_empty()) {
+        debug!("message source is {:?}, leaving it alone", source);
+        return;
+    }
+
+    let diff = git(&["diff", "--staged", "--no-color", "--no-ext-diff"])
+        .unwrap_or_else(|e| fail_or_warn(e));
+    if diff.trim().is_empty() {
+        fail_or_warn("there are no staged changes".to_string());
+    }
+
+    let message = cancellable(generate(matches, &diff, max_subject))
+        .await
+        .unwrap_or_else(|e| fail_or_warn(e));
+
+    match message_file {
+        Some(path) => write_message_file(path, &message).unwrap_or_else(|e| fail_or_warn(e)),
+        None => println!("{}", message),
+    }
+}
+
+/// # Run Hook Subcommand
+///
+/// Installs or removes the hook in the hooks directory git reports for this repository
+pub async fn hook(matches: &ArgMatches) {
+    let path =
+        git(&["rev-parse", "--git-path", "hooks/prepare-commit-msg"]).unwrap_or_else(|e| fail(e));
+    let path = PathBuf::from(path.trim());
+    match matches.subcommand() {
+        Some(("install", sub_matches)) => {
+            let force = *sub_matches.get_one::<bool>("force").unwrap_or(&false);
+            install_hook(&path, force).unwrap_or_else(|e| fail(e));
+            eprintln!("Installed {}", path.display());
+        },
+        Some(("uninstall", _)) => {
+            uninstall_hook(&path).unwrap_or_else(|e| fail(e));
+            eprintln!("Removed {}", path.display());
+        },
+        _ => unreachable!("a subcommand is required"),
+    }
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// Test the subject format and length checks, and that fences are removed first
+    #[cfg_attr(not(doc), test)]
+    fn test_validate_commit_message() {
+        assert!(validate_commit_message("feat(cli): add commit-msg subcommand", 72).is_ok());
+        assert!(validate_commit_message("fix!: drop support for 0.11\n\nBody here.", 72).is_ok());
+        assert!(validate_commit_message("Added a thing", 72)
+            .unwrap_err()
+            .contains("type(scope): subject"));
+        assert!(validate_commit_message("feat: a subject that goes on", 20)
+            .unwrap_err()
+            .contains("at most 20"));
+        assert!(
+            validate_commit_message("fix: subject\nbody straight after", 72)
+                .unwrap_err()
+                .contains("blank line")
+        );
+
+        assert_eq!(
+            clean_reply("```text\nfix: handle empty diff  \n\nMore detail.\n```\n"),
+            "fix: handle empty diff\n\nMore detail."
+        );
+        assert_eq!(
+            clean_reply("  docs: update readme\n"),
+            "docs: update readme"
+        );
+    }
+
+    /// Test that the message goes above git's comments
+    #[cfg_attr(not(doc), test)]
+    fn test_write_message_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("COMMIT_EDITMSG");
+        fs::write(&path, "# Please enter the commit message\n").unwrap();
+
+        write_message_file(&path, "feat: add hooks").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "feat: add hooks\n# Please enter the commit message\n"
+        );
+    }
+
+    /// Test that only hooks written by pipe-gpt are replaced or removed without --force
+    #[cfg_attr(not(doc), test)]
+    fn test_install_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hooks/prepare-commit-msg");
+
+        install_hook(&path, false).unwrap();
+        let script = fs::read_to_string(&path).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("pipe-gpt commit-msg \"$@\" || true"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o755
+            );
+        }
+        install_hook(&path, false).unwrap();
+        uninstall_hook(&path).unwrap();
+        assert!(!path.exists());
+
+        fs::write(&path, "#!/bin/sh\nmy-own-hook\n").unwrap();
+        assert!(install_hook(&path, false).unwrap_err().contains("--force"));
+        assert!(uninstall_hook(&path).is_err());
+        install_hook(&path, true).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains(HOOK_MARKER));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::api::openai::count_tokens;
+use crate::cli::index::{read_chunks, Chunk};
+
+/// BM25 term frequency saturation
+const K1: f32 = 1.2;
+
+/// BM25 document length normalisation
+const B: f32 = 0.75;
+
+/// Lowercase words of two or more letters or digits. Underscores split words too, so
+/// `read_timeout` matches a question about timeouts.
+pub fn terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| term.chars().count() > 1)
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+/// # BM25 Ranking
+///
+/// Scores each chunk against the query with Okapi BM25 and returns the indexes of chunks that
+/// share at least one term with it, best first
+pub fn bm25_rank(chunks: &[Chunk], query: &str) -> Vec<(f32, usize)> {
+    let documents: Vec<Vec<String>> = chunks.iter().map(|chunk| terms(&chunk.text)).collect();
+    if documents.is_empty() {
+        return vec![];
+    }
+    let average_length =
+        documents.iter().map(Vec::len).sum::<usize>() as f32 / documents.len() as f32;
+
+    let query_terms: HashSet<String> = terms(query).into_iter().collect();
+    let mut document_frequency: HashMap<&str, usize> = HashMap::new();
+    for document in &documents {
+        let unique: HashSet<&str> = document.iter().map(String::as_str).collect();
+        for term in unique {
+            if query_terms.contains(term) {
+                *document_frequency.entry(term).or_default() += 1;
+            }
+        }
+    }
+
+    let count = documents.len() as f32;
+    let mut ranked: Vec<(f32, usize)> = documents
+        .iter()
+        .enumerate()
+        .filter_map(|(index, document)| {
+            let mut frequency: HashMap<&str, f32> = HashMap::new();
+            for term in document {
+                if query_terms.contains(term) {
+                    *frequency.entry(term.as_str()).or_default() += 1.0;
+                }
+            }
+            if frequency.is_empty() {
+                return None;
+            }
+            let length_norm = 1.0 - B + B * document.len() as f32 / average_length.max(1.0);
+            let score = frequency
+                .iter()
+                .map(|(term, tf)| {
+                    let df = document_frequency[term] as f32;
+                    let idf = ((count - df + 0.5) / (df + 0.5) + 1.0).ln();
+                    idf * tf * (K1 + 1.0) / (tf + K1 * length_norm)
+                })
+                .sum();
+            Some((score, index))
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    ranked
+}
+
+/// How a chunk is cited in the context and the reply
+fn citation(chunk: &Chunk) -> String {
+    format!("[{}:{}-{}]", chunk.path, chunk.start_line, chunk.end_line)
+}
+
+/// # Select Snippets
+///
+/// Takes ranked chunks in order while they fit in `budget` tokens, skipping any that overlap a
+/// chunk already taken from the same file. Returns the context to send, or `None` if nothing
+/// matched or fitted.
+pub fn select_snippets(chunks: &[Chunk], ranked: &[(f32, usize)], budget: usize) -> Option<String> {
+    let mut context = String::from(
+        "Use these excerpts from the documentation where they are relevant, citing each one you use by the label before it e.g. [docs/setup.md:1-40].",
+    );
+    let mut used = count_tokens(&context);
+    let mut taken: Vec<&Chunk> = vec![];
+    for (_, index) in ranked {
+        let chunk = &chunks[*index];
+        let overlaps = taken.iter().any(|other| {
+            other.path == chunk.path
+                && other.start_line <= chunk.end_line
+                && chunk.start_line <= other.end_line
+        });
+        if overlaps {
+            continue;
+        }
+        let snippet = format!("\n\n{}\n```\n{}\n```", citation(chunk), chunk.text);
+        let tokens = count_tokens(&snippet);
+        if used + tokens > budget {
+            continue;
+        }
+        used += tokens;
+        context.push_str(&snippet);
+        taken.push(chunk);
+    }
+    if taken.is_empty() {
+        None
+    } else {
+        Some(context)
+    }
+}
+
+/// # Context From Directory
+///
+/// The snippets of files under `dir` that best match the query, within `budget` tokens
+pub fn context_from_dir(dir: &Path, query: &str, budget: usize) -> Result<Option<String>, String> {
+    let chunks = read_chunks(dir)?;
+    let ranked = bm25_rank(&chunks, query);
+    Ok(select_snippets(&chunks, &ranked, budget))
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    fn chunk(path: &str, start_line: usize, end_line: usize, text: &str) -> Chunk {
+        Chunk {
+            path: path.to_string(),
+            start_line,
+            end_line,
+            text: text.to_string(),
+            embedding: vec![],
+        }
+    }
+
+    /// Test that chunks matching more, and more often repeated, query terms rank higher and unrelated
+    /// chunks are dropped
+    #[cfg_attr(not(doc), test)]
+    fn test_bm25_rank() {
+        let chunks = vec![
+            chunk("a.md", 1, 1, "how to deploy the service to staging"),
+            chunk(
+                "b.md",
+                1,
+                1,
+                "the service reads its config from the environment",
+            ),
+            chunk("c.md", 1, 1, "deploy deploy deploy: run make deploy"),
+            chunk("d.md", 1, 1, "release notes for version two"),
+        ];
+
+        let order = |query: &str| -> Vec<usize> {
+            bm25_rank(&chunks, query)
+                .iter()
+                .map(|(_, index)| *index)
+                .collect()
+        };
+        let question = order("How do I deploy the service?");
+        assert_eq!(question[0], 0);
+        assert!(!question.contains(&3));
+        assert_eq!(order("deploy"), vec![2, 0]);
+        assert!(bm25_rank(&chunks, "kubernetes").is_empty());
+        assert_eq!(terms("read_timeout: 60s"), vec!["read", "timeout", "60s"]);
+    }
+
+    /// Test that snippets are cited, kept within the budget and not repeated
+    #[cfg_attr(not(doc), test)]
+    fn test_select_snippets() {
+        let chunks = vec![
+            chunk("docs/a.md", 1, 40, "deploy with make deploy"),
+            chunk("docs/a.md", 31, 70, "deploy overlap"),
+            chunk("docs/b.md", 1, 40, &"long deploy text ".repeat(200)),
+            chunk("docs/c.md", 1, 40, "deploy rollback"),
+        ];
+        let ranked = vec![(4.0, 0), (3.0, 1), (2.0, 2), (1.0, 3)];
+
+        let context = select_snippets(&chunks, &ranked, 200).unwrap();
+        assert!(context.contains("[docs/a.md:1-40]\n```\ndeploy with make deploy\n```"));
+        assert!(context.contains("[docs/c.md:1-40]"));
+        assert!(!context.contains("overlap"));
+        assert!(!context.contains("docs/b.md"));
+        assert!(count_tokens(&context) <= 200);
+
+        assert_eq!(select_snippets(&chunks, &[], 200), None);
+        assert_eq!(select_snippets(&chunks, &ranked, 10), None);
+    }
+}
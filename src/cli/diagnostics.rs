@@ -0,0 +1,529 @@
+use atty::Stream; // atty to determine if data is piped in or not
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use log::*; // logging
+use regex::Regex;
+use serde_json::Value;
+use std::fmt;
+use std::io;
+use std::sync::OnceLock;
+
+use crate::api::openai::{send_to_gpt4, AssistantPurpose};
+use crate::cli::cancel::cancellable;
+use crate::cli::input::{decode_text, read_limited};
+use crate::cli::output::{fail, markdown_plaintext_or_error};
+use crate::cli::parse::build_request;
+use crate::cli::triage::{source_excerpt, Location};
+use crate::cli::truncate::Truncate;
+use crate::config::models::load_config;
+
+/// Most diagnostics sent, in the order the compiler printed them
+const MAX_DIAGNOSTICS: usize = 20;
+
+/// Most lines of compiler notes and help kept for each diagnostic
+const MAX_DETAIL_LINES: usize = 20;
+
+/// Most spans shown for each diagnostic
+const MAX_SPANS: usize = 3;
+
+/// # Define Explain Subcommand
+///
+/// `cargo build 2>&1 | pipe-gpt explain` explains each compiler error with its source
+pub fn explain_command() -> Command {
+    Command::new("explain")
+        .about("Explain the rustc, gcc, clang or tsc errors piped in, with the source they point to, and propose fixes")
+        .arg(
+            Arg::new("context")
+                .long("context")
+                .value_name("lines")
+                .help("Lines of source shown either side of each line a diagnostic points to")
+                .required(false)
+                .default_value("3")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("warnings")
+                .long("warnings")
+                .help("Explain warnings as well as errors")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+}
+
+/// # Compiler
+///
+/// The compilers whose diagnostics `explain` understands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compiler {
+    /// rustc, directly or through cargo, in the human or JSON format
+    Rustc,
+    /// gcc and clang, which share a format
+    Gcc,
+    /// TypeScript's tsc, plain or with --pretty
+    Tsc,
+}
+
+impl fmt::Display for Compiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compiler::Rustc => write!(f, "rustc"),
+            Compiler::Gcc => write!(f, "gcc/clang"),
+            Compiler::Tsc => write!(f, "tsc"),
+        }
+    }
+}
+
+/// # Diagnostic
+///
+/// One error or warning with where it points in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub compiler: Compiler,
+    /// `error` or `warning`
+    pub level: String,
+    /// e.g. `E0308` or `TS2322`
+    pub code: Option<String>,
+    pub message: String,
+    /// The primary span first
+    pub spans: Vec<Location>,
+    /// Notes, help and labels the compiler printed with it
+    pub detail: Vec<String>,
+}
+
+impl Diagnostic {
+    fn new(compiler: Compiler, level: &str, code: Option<&str>, message: &str) -> Self {
+        Diagnostic {
+            compiler,
+            level: normalise_level(level),
+            code: code.map(str::to_string),
+            message: message.trim().to_string(),
+            spans: vec![],
+            detail: vec![],
+        }
+    }
+
+    fn add_span(&mut self, path: &str, line: &str) {
+        let location = Location {
+            path: path.trim_start_matches("./").to_string(),
+            line: line.parse().unwrap_or(0),
+        };
+        if location.line > 0 && !self.spans.contains(&location) {
+            self.spans.push(location);
+        }
+    }
+
+    /// Cargo's closing lines, which look like errors but say nothing about the source
+    fn is_summary(&self) -> bool {
+        self.spans.is_empty()
+            && (self.message.starts_with("aborting due to")
+                || self.message.starts_with("could not compile")
+                || self.message.contains("generated ")
+                || self.message == "build failed")
+    }
+}
+
+/// `fatal error` is still an error, and tsc's `Error` is too
+fn normalise_level(level: &str) -> String {
+    let level = level.to_lowercase();
+    if level.contains("error") {
+        "error".to_string()
+    } else {
+        level
+    }
+}
+
+fn rustc_header_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^(error|warning)(?:\[(\w+)\])?: (.+)$").unwrap())
+}
+
+fn rustc_span_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^\s*(?:-->|::: ) ?(.+?):(\d+):(\d+)$").unwrap())
+}
+
+fn gcc_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"^(.+?):(\d+):(?:\d+:)? (fatal error|error|warning|note): (.+)$").unwrap()
+    })
+}
+
+fn tsc_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        // `src/a.ts(3,7): error TS2322: ...` or, with --pretty, `src/a.ts:3:7 - error TS2322: ...`
+        Regex::new(r"^(.+?)(?:\((\d+),\d+\)|:(\d+):\d+) ?[:-] (error|warning) (TS\d+): (.+)$")
+            .unwrap()
+    })
+}
+
+/// A diagnostic from rustc's JSON output, with or without cargo's `compiler-message` wrapper
+fn parse_rustc_json(value: &Value) -> Option<Diagnostic> {
+    let message = match value.get("reason").and_then(Value::as_str) {
+        Some("compiler-message") => value.get("message")?,
+        Some(_) => return None,
+        None => value,
+    };
+    let level = message.get("level")?.as_str()?;
+    if !matches!(
+        level,
+        "error" | "warning" | "error: internal compiler error"
+    ) {
+        return None;
+    }
+    let mut diagnostic = Diagnostic::new(
+        Compiler::Rustc,
+        level,
+        message
+            .get("code")
+            .and_then(|code| code.get("code"))
+            .and_then(Value::as_str),
+        message.get("message")?.as_str()?,
+    );
+
+    let mut spans: Vec<&Value> = message
+        .get("spans")
+        .and_then(Value::as_array)
+        .map(|spans| spans.iter().collect())
+        .unwrap_or_default();
+    // the primary span is what the error is about, so it goes first
+    spans.sort_by_key(|span| !span["is_primary"].as_bool().unwrap_or(false));
+    for span in spans {
+        if let (Some(path), Some(line)) = (span["file_name"].as_str(), span["line_start"].as_u64())
+        {
+            diagnostic.add_span(path, &line.to_string());
+            if let Some(label) = span["label"].as_str() {
+                diagnostic
+                    .detail
+                    .push(format!("{}:{}: {}", path, line, label));
+            }
+        }
+    }
+    for child in message
+        .get("children")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        if let (Some(level), Some(text)) = (child["level"].as_str(), child["message"].as_str()) {
+            diagnostic.detail.push(format!("{}: {}", level, text));
+        }
+    }
+    Some(diagnostic)
+}
+
+/// # Parse Diagnostics
+///
+/// The errors and warnings in compiler output, whichever of the supported formats each line is
+/// in. Lines following a diagnostic are kept as its detail until a blank line, and repeats are
+/// dropped.
+pub fn parse_diagnostics(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    let mut open = false;
+    for line in output.lines() {
+        let line = line.trim_end();
+        if line.starts_with('{') {
+            if let Some(diagnostic) = serde_json::from_str::<Value>(line)
+                .ok()
+                .as_ref()
+                .and_then(parse_rustc_json)
+            {
+                diagnostics.push(diagnostic);
+            }
+            open = false;
+        } else if let Some(captures) = rustc_header_pattern().captures(line) {
+            diagnostics.push(Diagnostic::new(
+                Compiler::Rustc,
+                &captures[1],
+                captures.get(2).map(|code| code.as_str()),
+                &captures[3],
+            ));
+            open = true;
+        } else if let Some(captures) = tsc_pattern().captures(line) {
+            let mut diagnostic = Diagnostic::new(
+                Compiler::Tsc,
+                &captures[4],
+                Some(&captures[5]),
+                &captures[6],
+            );
+            let line_number = captures.get(2).or(captures.get(3)).unwrap().as_str();
+            diagnostic.add_span(&captures[1], line_number);
+            diagnostics.push(diagnostic);
+            open = true;
+        } else if let Some(captures) = gcc_pattern().captures(line) {
+            // notes belong to the error or warning before them
+            if &captures[3] == "note" {
+                if let Some(last) = diagnostics.last_mut().filter(|_| open) {
+                    last.add_span(&captures[1], &captures[2]);
+                    last.detail.push(line.to_string());
+                }
+                continue;
+            }
+            let mut diagnostic = Diagnostic::new(Compiler::Gcc, &captures[3], None, &captures[4]);
+            diagnostic.add_span(&captures[1], &captures[2]);
+            diagnostics.push(diagnostic);
+            open = true;
+        } else if line.trim().is_empty() {
+            open = false;
+        } else if open {
+            let Some(last) = diagnostics.last_mut() else {
+                continue;
+            };
+            if let Some(captures) = rustc_span_pattern().captures(line) {
+                last.add_span(&captures[1], &captures[2]);
+            } else {
+                last.detail.push(line.to_string());
+            }
+        }
+    }
+
+    let mut unique: Vec<Diagnostic> = vec![];
+    for diagnostic in diagnostics {
+        let repeated = unique.iter().any(|other| {
+            other.level == diagnostic.level
+                && other.message == diagnostic.message
+                && other.spans.first() == diagnostic.spans.first()
+        });
+        if !repeated && !diagnostic.is_summary() {
+            unique.push(diagnostic);
+        }
+    }
+    unique
+}
+
+/// # Diagnostics Bundle
+///
+/// Formats the diagnostics for the model: each message and code, the compiler's own notes, and
+/// the source around the lines it points to, read from under `root`
+pub fn diagnostics_bundle(
+    diagnostics: &[Diagnostic],
+    root: &std::path::Path,
+    context: usize,
+) -> String {
+    let mut bundle = format!("Diagnostics: {}\n", diagnostics.len());
+    for (index, diagnostic) in diagnostics.iter().take(MAX_DIAGNOSTICS).enumerate() {
+        bundle.push_str(&format!(
+            "\n## {}. {} {}{}: {}\n",
+            index + 1,
+            diagnostic.compiler,
+            diagnostic.level,
+            diagnostic
+                .code
+                .as_ref()
+                .map(|code| format!("[{}]", code))
+                .unwrap_or_default(),
+            diagnostic.message
+        ));
+        if !diagnostic.detail.is_empty() {
+            let detail: Vec<&str> = diagnostic
+                .detail
+                .iter()
+                .map(String::as_str)
+                .take(MAX_DETAIL_LINES)
+                .collect();
+            bundle.push_str(&format!(
+                "Compiler notes:\n```\n{}\n```\n",
+                detail.join("\n")
+            ));
+        }
+        for span in diagnostic.spans.iter().take(MAX_SPANS) {
+            match source_excerpt(root, span, context) {
+                Some(excerpt) => bundle.push_str(&format!("{}\n", excerpt)),
+                None => {
+                    bundle.push_str(&format!("{}:{} (source not found)\n", span.path, span.line))
+                },
+            }
+        }
+    }
+    if diagnostics.len() > MAX_DIAGNOSTICS {
+        bundle.push_str(&format!(
+            "\n{} more diagnostics were left out.\n",
+            diagnostics.len() - MAX_DIAGNOSTICS
+        ));
+    }
+    bundle
+}
+
+/// # Run Explain Subcommand
+///
+/// - Reads the compiler output piped in
+/// - Extracts each error, and warning with `--warnings`, with its code and spans
+/// - Reads the source lines each span points to from the working directory
+/// - Sends them and prints an explanation and fix for each
+pub async fn explain(matches: &ArgMatches) {
+    let context = *matches
+        .get_one::<usize>("context")
+        .expect("context has a default");
+    let warnings = *matches.get_one::<bool>("warnings").unwrap_or(&false);
+    let empty_string = String::from("");
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .unwrap_or(&empty_string);
+
+    if atty::is(Stream::Stdin) {
+        fail("pipe the compiler output in e.g. cargo build 2>&1 | pipe-gpt explain".to_string());
+    }
+    let bytes =
+        read_limited(io::stdin(), load_config().max_input_bytes).unwrap_or_else(|e| fail(e));
+    let (output, _) = decode_text(&bytes);
+
+    let diagnostics: Vec<Diagnostic> = parse_diagnostics(&output)
+        .into_iter()
+        .filter(|diagnostic| warnings || diagnostic.level == "error")
+        .collect();
+    if diagnostics.is_empty() {
+        eprintln!(
+            "No {} found in the input.",
+            if warnings {
+                "errors or warnings"
+            } else {
+                "errors"
+            }
+        );
+        return;
+    }
+    eprintln!("Explaining {} diagnostics", diagnostics.len());
+
+    let root = std::env::current_dir().unwrap_or_default();
+    let bundle = diagnostics_bundle(&diagnostics, &root, context);
+    debug!("diagnostics bundle: {}", bundle);
+
+    let chat_request = build_request(
+        prompt,
+        &bundle,
+        &[],
+        matches,
+        &AssistantPurpose::CompilerErrors,
+        None,
+        Some(Truncate::Smart),
+    );
+    let response =
+        cancellable(async { send_to_gpt4(&chat_request).await.map_err(|e| e.to_string()) }).await;
+    markdown_plaintext_or_error(response, false);
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const CARGO_OUTPUT: &str = "\
+   Compiling demo v0.1.0 (/tmp/demo)
+error[E0308]: mismatched types
+ --> src/main.rs:3:18
+  |
+3 |     let n: u32 = \"five\";
+  |            ---   ^^^^^^ expected `u32`, found `&str`
+  |            |
+  |            expected due to this
+
+warning: unused variable: `x`
+ --> src/lib.rs:1:5
+
+error: aborting due to 1 previous error; 1 warning emitted
+
+For more information about this error, try `rustc --explain E0308`.
+error: could not compile `demo` (bin \"demo\") due to 1 previous error
+";
+
+    /// Test rustc's human readable format, skipping cargo's closing lines
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_rustc_diagnostics() {
+        let diagnostics = parse_diagnostics(CARGO_OUTPUT);
+        assert_eq!(diagnostics.len(), 2);
+        let error = &diagnostics[0];
+        assert_eq!(error.compiler, Compiler::Rustc);
+        assert_eq!(error.level, "error");
+        assert_eq!(error.code.as_deref(), Some("E0308"));
+        assert_eq!(error.message, "mismatched types");
+        assert_eq!(
+            error.spans,
+            vec![Location {
+                path: "src/main.rs".to_string(),
+                line: 3
+            }]
+        );
+        assert!(error
+            .detail
+            .iter()
+            .any(|line| line.contains("expected `u32`")));
+        assert_eq!(diagnostics[1].level, "warning");
+        assert_eq!(diagnostics[1].spans[0].path, "src/lib.rs");
+    }
+
+    /// Test rustc's JSON format, from cargo with --message-format=json and from rustc directly
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_rustc_json() {
+        let output = r#"{"reason":"compiler-artifact","package_id":"demo"}
+{"reason":"compiler-message","message":{"$message_type":"diagnostic","message":"cannot find value `y` in this scope","code":{"code":"E0425","explanation":null},"level":"error","spans":[{"file_name":"src/util.rs","line_start":9,"line_end":9,"column_start":5,"column_end":6,"is_primary":false,"label":"similarly named `x` defined here"},{"file_name":"src/main.rs","line_start":4,"line_end":4,"column_start":13,"column_end":14,"is_primary":true,"label":"not found in this scope"}],"children":[{"message":"a local variable with a similar name exists: `x`","level":"help","spans":[],"children":[]}],"rendered":"error[E0425]: ..."}}
+{"$message_type":"diagnostic","message":"aborting due to 1 previous error","code":null,"level":"error","spans":[],"children":[],"rendered":"error: aborting"}
+{"reason":"build-finished","success":false}
+"#;
+        let diagnostics = parse_diagnostics(output);
+        assert_eq!(diagnostics.len(), 1);
+        let error = &diagnostics[0];
+        assert_eq!(error.code.as_deref(), Some("E0425"));
+        assert_eq!(error.message, "cannot find value `y` in this scope");
+        assert_eq!(error.spans[0].path, "src/main.rs");
+        assert_eq!(error.spans[1].path, "src/util.rs");
+        assert_eq!(
+            error.detail,
+            vec![
+                "src/main.rs:4: not found in this scope",
+                "src/util.rs:9: similarly named `x` defined here",
+                "help: a local variable with a similar name exists: `x`"
+            ]
+        );
+    }
+
+    /// Test gcc/clang and both tsc formats, with notes kept on the error before them
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_gcc_and_tsc_diagnostics() {
+        let output = "\
+main.c: In function 'main':
+main.c:5:12: error: 'count' undeclared (first use in this function)
+    5 |     return count;
+      |            ^~~~~
+main.c:5:12: note: each undeclared identifier is reported only once
+lib.h:2:1: warning: no newline at end of file
+src/app.ts(12,7): error TS2322: Type 'string' is not assignable to type 'number'.
+src/app.ts:20:3 - error TS2304: Cannot find name 'fetchUser'.
+";
+        let diagnostics = parse_diagnostics(output);
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(diagnostics[0].compiler, Compiler::Gcc);
+        assert_eq!(
+            diagnostics[0].message,
+            "'count' undeclared (first use in this function)"
+        );
+        assert_eq!(diagnostics[0].spans.len(), 1);
+        assert!(diagnostics[0].detail[2].contains("note: each undeclared"));
+        assert_eq!(diagnostics[1].level, "warning");
+        assert_eq!(diagnostics[2].compiler, Compiler::Tsc);
+        assert_eq!(diagnostics[2].code.as_deref(), Some("TS2322"));
+        assert_eq!(diagnostics[2].spans[0].line, 12);
+        assert_eq!(diagnostics[3].message, "Cannot find name 'fetchUser'.");
+        assert_eq!(diagnostics[3].spans[0].path, "src/app.ts");
+        assert_eq!(diagnostics[3].spans[0].line, 20);
+    }
+
+    /// Test that the source is read around each span and missing files are noted
+    #[cfg_attr(not(doc), test)]
+    fn test_diagnostics_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(
+            dir.path().join("src/main.rs"),
+            "fn main() {\n    // count\n    let n: u32 = \"five\";\n    println!(\"{}\", n);\n}\n",
+        )
+        .unwrap();
+
+        let bundle = diagnostics_bundle(&parse_diagnostics(CARGO_OUTPUT), dir.path(), 1);
+        assert!(bundle.starts_with("Diagnostics: 2\n"));
+        assert!(bundle.contains("## 1. rustc error[E0308]: mismatched types\n"));
+        assert!(bundle.contains(
+            "src/main.rs:3\n```rust\n  2 |     // count\n> 3 |     let n: u32 = \"five\";\n  4 |     println!(\"{}\", n);\n```"
+        ));
+        assert!(bundle.contains("src/lib.rs:1 (source not found)"));
+    }
+}
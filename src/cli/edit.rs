@@ -0,0 +1,200 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use log::*; // logging
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::api::openai::{send_to_gpt4, AssistantPurpose};
+use crate::cli::cancel::cancellable;
+use crate::cli::output::{atomic_write, confirm, fail, fenced_code_blocks};
+use crate::cli::parse::build_request;
+use crate::cli::patch::{apply_hunks, parse_unified_diff, preview};
+
+/// # Define Edit Subcommand
+///
+/// `pipe-gpt edit FILE -p "..."` asks for a unified diff to the file, previews it and applies it
+pub fn edit_command() -> Command {
+    Command::new("edit")
+        .about("Ask for a unified diff to a file, preview it and apply it after confirmation")
+        .arg(
+            Arg::new("file")
+                .value_name("FILE")
+                .help("File to edit")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("yes")
+                .short('y')
+                .long("yes")
+                .help("Apply the diff without asking for confirmation")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+}
+
+/// # Diff From Reply
+///
+/// Models often wrap the diff in a fenced block, so prefer the contents of a `diff` or `patch`
+/// block, then any block, then the whole reply
+pub fn diff_from_reply(reply: &str) -> String {
+    let blocks = fenced_code_blocks(reply);
+    blocks
+        .iter()
+        .find(|block| matches!(block.lang.as_deref(), Some("diff") | Some("patch")))
+        .or(blocks.first())
+        .map(|block| block.code.clone())
+        .unwrap_or_else(|| reply.to_string())
+}
+
+/// # Backup Path
+///
+/// The file the original is copied to before an edit is applied: `main.rs.bak` for the first
+/// attempt, then `main.rs.bak.1`, `main.rs.bak.2` and so on
+pub fn backup_path(path: &Path, attempt: usize) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    if attempt > 0 {
+        backup.push(format!(".{}", attempt));
+    }
+    PathBuf::from(backup)
+}
+
+/// # Write Edited File
+///
+/// Copies the original to the first backup path not already taken, so earlier backups are never
+/// overwritten, then writes the new contents. Returns the backup path.
+pub fn write_with_backup(path: &Path, contents: &str) -> Result<PathBuf, String> {
+    let backup_error = |e: io::Error| format!("could not back up {:?}: {}", path, e);
+    let mut original = fs::File::open(path).map_err(backup_error)?;
+    let mut attempt = 0;
+    let (backup, mut file) = loop {
+        let backup = backup_path(path, attempt);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&backup)
+        {
+            Ok(file) => break (backup, file),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(backup_error(e)),
+        }
+    };
+    io::copy(&mut original, &mut file).map_err(backup_error)?;
+    fs::set_permissions(
+        &backup,
+        original.metadata().map_err(backup_error)?.permissions(),
+    )
+    .map_err(backup_error)?;
+    atomic_write(path, contents).map_err(|e| format!("could not write {:?}: {}", path, e))?;
+    Ok(backup)
+}
+
+/// # Run Edit Subcommand
+///
+/// - Sends the file with the prompt, asking for a unified diff
+/// - Checks the diff applies cleanly before showing anything
+/// - Shows a coloured preview, noting hunks that only apply at other lines than stated
+/// - Applies it on confirmation, or straight away with `--yes` unless hunks moved, keeping a
+///   backup
+pub async fn edit(matches: &ArgMatches) {
+    let path = matches
+        .get_one::<PathBuf>("file")
+        .expect("file is required");
+    let yes = *matches.get_one::<bool>("yes").unwrap_or(&false);
+
+    let original = fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(format!("could not read {:?}: {}", path, e)));
+
+    let empty_string = String::from("");
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .unwrap_or(&empty_string);
+    let request = format!(
+        "{}\n\nFile: {}\n```\n{}\n```",
+        prompt,
+        path.display(),
+        original
+    );
+
+    let chat_request = build_request(
+        &request,
+        "",
+        &[],
+        matches,
+        &AssistantPurpose::Editor,
+        None,
+        None,
+    );
+    let reply = cancellable(send_to_gpt4(&chat_request))
+        .await
+        .unwrap_or_else(|e| fail(e.to_string()));
+    debug!("edit reply: {}", reply);
+
+    let hunks = parse_unified_diff(&diff_from_reply(&reply))
+        .unwrap_or_else(|e| fail(format!("the reply was not a usable diff: {}", e)));
+    let patched = apply_hunks(&original, &hunks).unwrap_or_else(|e| fail(e));
+
+    eprintln!("{}", preview(&hunks));
+    for moved in &patched.moved {
+        eprintln!("Warning: {}", moved);
+    }
+
+    // a moved hunk may have landed on the wrong match, so it is confirmed even with --yes
+    let needs_confirming = !yes || !patched.moved.is_empty();
+    let hint = if patched.moved.is_empty() {
+        "Pass --yes to apply it without confirmation."
+    } else {
+        "Hunks that moved are confirmed even with --yes."
+    };
+    if needs_confirming && !confirm(&format!("Apply these changes to {}?", path.display()), hint) {
+        eprintln!("No changes made.");
+        return;
+    }
+
+    match write_with_backup(path, &patched.text) {
+        Ok(backup) => eprintln!(
+            "Applied {} hunk(s) to {}. Original saved to {}",
+            hunks.len(),
+            path.display(),
+            backup.display()
+        ),
+        Err(e) => fail(e),
+    }
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// Test that the diff is taken from a fenced block when the model adds chatter
+    #[cfg_attr(not(doc), test)]
+    fn test_diff_from_reply() {
+        let reply = "Here is the change:\n```diff\n@@ -1 +1 @@\n-a\n+b\n```\nLet me know!";
+        assert_eq!(diff_from_reply(reply), "@@ -1 +1 @@\n-a\n+b");
+
+        let bare = "@@ -1 +1 @@\n-a\n+b";
+        assert_eq!(diff_from_reply(bare), bare);
+    }
+
+    /// Test that the original is kept as a backup when an edit is written
+    #[cfg_attr(not(doc), test)]
+    fn test_write_with_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.rs");
+        fs::write(&path, "old").unwrap();
+
+        let backup = write_with_backup(&path, "new").unwrap();
+
+        assert_eq!(backup, dir.path().join("main.rs.bak"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "old");
+
+        // a second edit keeps the first backup of the original
+        let second = write_with_backup(&path, "newer").unwrap();
+
+        assert_eq!(second, dir.path().join("main.rs.bak.1"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "old");
+        assert_eq!(fs::read_to_string(&second).unwrap(), "new");
+    }
+}
@@ -0,0 +1,551 @@
+use atty::Stream; // atty to determine if data is piped in or not
+use clap::{value_parser, Arg, ArgMatches, Command};
+use log::*; // logging
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::api::embeddings::send_embeddings;
+use crate::api::openai::{count_tokens, send_to_gpt4, AssistantPurpose};
+use crate::cli::cancel::cancellable;
+use crate::cli::input::{decode_text, is_binary, read_limited};
+use crate::cli::output::{atomic_write, fail, markdown_plaintext_or_error};
+use crate::cli::parse::{build_request, token_limit};
+use crate::config::models::load_config;
+
+/// Where the index is kept when `--index` is not given
+pub const DEFAULT_INDEX_PATH: &str = ".pipe-gpt-index.json";
+
+/// Lines in each chunk of a file
+const CHUNK_LINES: usize = 40;
+
+/// Lines shared by neighbouring chunks, so code split at a boundary is still found whole
+const CHUNK_OVERLAP: usize = 10;
+
+/// Longest chunk sent to be embedded, keeping minified or generated lines within the model's limit
+const MAX_CHUNK_CHARS: usize = 8000;
+
+/// Files larger than this are left out of the index
+const MAX_INDEXED_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Directories never worth indexing
+pub const SKIPPED_DIRS: [&str; 3] = ["target", "node_modules", "vendor"];
+
+fn index_arg() -> Arg {
+    Arg::new("index")
+        .long("index")
+        .value_name("index.json")
+        .help("Where the index is stored")
+        .required(false)
+        .default_value(DEFAULT_INDEX_PATH)
+        .value_parser(value_parser!(PathBuf))
+}
+
+/// # Define Embed Subcommand
+///
+/// `pipe-gpt embed [TEXT]...` prints the embedding of each text, or of stdin, as a JSON array
+pub fn embed_command() -> Command {
+    Command::new("embed")
+        .about("Print the embedding of each text, or of the piped input, as a JSON array per line")
+        .arg(
+            Arg::new("texts")
+                .value_name("TEXT")
+                .help("Texts to embed. Reads stdin when none are given")
+                .required(false)
+                .num_args(1..),
+        )
+}
+
+/// # Define Index Subcommand
+///
+/// `pipe-gpt index build DIR` chunks and embeds the files under DIR into a local index
+pub fn index_command() -> Command {
+    Command::new("index")
+        .about("Manage the local embeddings index used by `pipe-gpt ask`")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("build")
+                .about("Chunk and embed the text files under a directory, replacing the index")
+                .arg(
+                    Arg::new("dir")
+                        .value_name("DIR")
+                        .help("Directory to index. Hidden, target, node_modules and vendor directories are skipped")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(index_arg()),
+        )
+}
+
+/// # Define Ask Subcommand
+///
+/// `pipe-gpt ask "question"` answers using the most relevant chunks of the index as context
+pub fn ask_command() -> Command {
+    Command::new("ask")
+        .about("Answer a question about an indexed codebase, using its most relevant chunks as context")
+        .arg(
+            Arg::new("question")
+                .value_name("QUESTION")
+                .help("What to ask")
+                .required(true),
+        )
+        .arg(index_arg())
+        .arg(
+            Arg::new("top_k")
+                .short('k')
+                .long("top_k")
+                .value_name("chunks")
+                .help("How many chunks to include as context")
+                .required(false)
+                .default_value("5")
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+}
+
+/// # Chunk
+///
+/// A run of lines from one file, with its embedding once the index is built
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub path: String,
+    /// First line, counting from 1
+    pub start_line: usize,
+    /// Last line, inclusive
+    pub end_line: usize,
+    pub text: String,
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+/// # Index
+///
+/// Embedded chunks of a codebase, stored as JSON on disk. The model is kept so a question is
+/// never compared against vectors from a different model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    pub model: String,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Index {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            format!(
+                "could not read the index {:?}: {}. Create it with `pipe-gpt index build DIR`",
+                path, e
+            )
+        })?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("{:?} is not a valid index: {}", path, e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = serde_json::to_string(self).expect("Failed to serialise index");
+        atomic_write(path, &content).map_err(|e| format!("could not write {:?}: {}", path, e))
+    }
+
+    /// # Search Index
+    ///
+    /// The `k` chunks most similar to the query vector, best first
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(f32, &Chunk)> {
+        let mut scored: Vec<(f32, &Chunk)> = self
+            .chunks
+            .iter()
+            .map(|chunk| (cosine_similarity(query, &chunk.embedding), chunk))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(k);
+        scored
+    }
+}
+
+/// Cosine of the angle between two vectors, or 0 if either is empty or zero
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// # Chunk Text
+///
+/// Splits a file into overlapping runs of lines, cutting very long chunks short. Blank chunks
+/// are left out.
+pub fn chunk_text(path: &str, text: &str) -> Vec<Chunk> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start + CHUNK_LINES).min(lines.len());
+        let chunk: String = lines[start..end]
+            .join("\n")
+            .chars()
+            .take(MAX_CHUNK_CHARS)
+            .collect();
+        if !chunk.trim().is_empty() {
+            chunks.push(Chunk {
+                path: path.to_string(),
+                start_line: start + 1,
+                end_line: end,
+                text: chunk,
+                embedding: vec![],
+            });
+        }
+        if end == lines.len() {
+            break;
+        }
+        start += CHUNK_LINES - CHUNK_OVERLAP;
+    }
+    chunks
+}
+
+/// # Collect Files
+///
+/// Every file under the directory, skipping hidden and build directories, in a stable order.
+/// Symlinks are skipped, so the walk can't loop or leave the directory.
+pub fn collect_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut files = vec![];
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("could not read {:?}: {}", dir, e))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|kind| !kind.is_symlink()))
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            if !SKIPPED_DIRS.contains(&name.as_str()) {
+                files.extend(collect_files(&path)?);
+            }
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// # Read Chunks
+///
+/// Chunks of every text file under the directory. Binary and very large files are skipped.
+pub fn read_chunks(dir: &Path) -> Result<Vec<Chunk>, String> {
+    let mut chunks = vec![];
+    for file in collect_files(dir)? {
+        if fs::metadata(&file).is_ok_and(|metadata| metadata.len() > MAX_INDEXED_FILE_BYTES) {
+            debug!("skipping large file {:?}", file);
+            continue;
+        }
+        let Ok(bytes) = fs::read(&file) else {
+            continue;
+        };
+        if is_binary(&bytes) {
+            continue;
+        }
+        chunks.extend(chunk_text(
+            &file.display().to_string(),
+            &decode_text(&bytes).0,
+        ));
+    }
+    Ok(chunks)
+}
+
+/// # Context Instructions
+///
+/// The retrieved chunks as a system message, each labelled with where it came from. Chunks are
+/// taken best first while they fit in `budget` tokens, skipping any that don't. Returns the
+/// message and how many chunks it holds.
+pub fn context_instructions(chunks: &[&Chunk], budget: usize) -> (String, usize) {
+    let mut instructions = String::from(
+        "Answer the question using these excerpts from the codebase. Refer to them by path and line numbers, and say so if they don't contain the answer.",
+    );
+    let mut used = count_tokens(&instructions);
+    let mut taken = 0;
+    for chunk in chunks {
+        let excerpt = format!(
+            "\n\n{}:{}-{}\n```\n{}\n```",
+            chunk.path, chunk.start_line, chunk.end_line, chunk.text
+        );
+        let tokens = count_tokens(&excerpt);
+        if used + tokens > budget {
+            continue;
+        }
+        used += tokens;
+        instructions.push_str(&excerpt);
+        taken += 1;
+    }
+    (instructions, taken)
+}
+
+/// # Run Embed Subcommand
+///
+/// Prints one JSON array per text, in order
+pub async fn embed(matches: &ArgMatches) {
+    let mut texts: Vec<String> = matches
+        .get_many::<String>("texts")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    if texts.is_empty() {
+        if atty::is(Stream::Stdin) {
+            fail(
+                "pass the texts to embed, or pipe one in e.g. cat notes.md | pipe-gpt embed"
+                    .to_string(),
+            );
+        }
+        let bytes =
+            read_limited(io::stdin(), load_config().max_input_bytes).unwrap_or_else(|e| fail(e));
+        if is_binary(&bytes) {
+            fail("the piped input looks like binary data".to_string());
+        }
+        texts.push(decode_text(&bytes).0);
+    }
+
+    let embeddings = cancellable(send_embeddings(&texts))
+        .await
+        .unwrap_or_else(|e| fail(e.to_string()));
+    for embedding in embeddings {
+        println!(
+            "{}",
+            serde_json::to_string(&embedding).expect("Failed to serialise embedding")
+        );
+    }
+}
+
+/// # Run Index Subcommand
+///
+/// - Chunks every text file under the directory
+/// - Embeds the chunks and writes them to the index, replacing any earlier one
+pub async fn index(matches: &ArgMatches) {
+    let Some(("build", matches)) = matches.subcommand() else {
+        unreachable!("a subcommand is required");
+    };
+    let dir = matches.get_one::<PathBuf>("dir").expect("dir is required");
+    let path = matches
+        .get_one::<PathBuf>("index")
+        .expect("index has a default");
+
+    let mut chunks = read_chunks(dir).unwrap_or_else(|e| fail(e));
+    if chunks.is_empty() {
+        fail(format!("no text files found under {:?}", dir));
+    }
+    eprintln!("embedding {} chunk(s)", chunks.len());
+
+    let texts: Vec<String> = chunks.iter().map(|chunk| chunk.text.clone()).collect();
+    let embeddings = cancellable(send_embeddings(&texts))
+        .await
+        .unwrap_or_else(|e| fail(e.to_string()));
+    for (chunk, embedding) in chunks.iter_mut().zip(embeddings) {
+        chunk.embedding = embedding;
+    }
+
+    let index = Index {
+        model: load_config().embedding_model,
+        chunks,
+    };
+    index.save(path).unwrap_or_else(|e| fail(e));
+    eprintln!("indexed {} chunk(s) into {:?}", index.chunks.len(), path);
+}
+
+/// # Run Ask Subcommand
+///
+/// - Embeds the question and finds the closest chunks in the index
+/// - Sends the question with those chunks as context
+pub async fn ask(matches: &ArgMatches) {
+    let question = matches
+        .get_one::<String>("question")
+        .expect("question is required");
+    let path = matches
+        .get_one::<PathBuf>("index")
+        .expect("index has a default");
+    let top_k = *matches.get_one::<u32>("top_k").unwrap_or(&5) as usize;
+
+    let index = Index::load(path).unwrap_or_else(|e| fail(e));
+    let model = load_config().embedding_model;
+    if index.model != model {
+        fail(format!(
+            "the index was built with {} but embedding_model is {}. Rebuild it with `pipe-gpt index build DIR`",
+            index.model, model
+        ));
+    }
+
+    let query = cancellable(send_embeddings(std::slice::from_ref(question)))
+        .await
+        .unwrap_or_else(|e| fail(e.to_string()))
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| fail("no embedding was returned for the question".to_string()));
+    let found = index.search(&query, top_k);
+    for (score, chunk) in &found {
+        debug!(
+            "{:.3} {}:{}-{}",
+            score, chunk.path, chunk.start_line, chunk.end_line
+        );
+    }
+    let chunks: Vec<&Chunk> = found.into_iter().map(|(_, chunk)| chunk).collect();
+
+    // the chunks get whatever the question leaves of the token limit
+    let purpose = AssistantPurpose::Default;
+    let budget =
+        token_limit(matches).saturating_sub(count_tokens(&format!("{}{}", purpose, question)));
+    let (instructions, taken) = context_instructions(&chunks, budget);
+    if taken < chunks.len() {
+        eprintln!(
+            "Warning: only {} of the {} closest chunk(s) fit within the token limit",
+            taken,
+            chunks.len()
+        );
+    }
+
+    let chat_request = build_request(
+        question,
+        "",
+        &[],
+        matches,
+        &purpose,
+        Some(instructions),
+        None,
+    );
+    let response = cancellable(send_to_gpt4(&chat_request))
+        .await
+        .map_err(|e| e.to_string());
+    markdown_plaintext_or_error(response, false);
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    fn chunk(path: &str, embedding: Vec<f32>) -> Chunk {
+        Chunk {
+            path: path.to_string(),
+            start_line: 1,
+            end_line: 1,
+            text: path.to_string(),
+            embedding,
+        }
+    }
+
+    /// Test that the closest chunks are returned best first
+    #[cfg_attr(not(doc), test)]
+    fn test_search() {
+        let index = Index {
+            model: "test".to_string(),
+            chunks: vec![
+                chunk("x", vec![1.0, 0.0, 0.0]),
+                chunk("xy", vec![1.0, 1.0, 0.0]),
+                chunk("z", vec![0.0, 0.0, 1.0]),
+                chunk("empty", vec![]),
+            ],
+        };
+
+        let found: Vec<&str> = index
+            .search(&[0.9, 0.1, 0.0], 2)
+            .iter()
+            .map(|(_, chunk)| chunk.path.as_str())
+            .collect();
+        assert_eq!(found, vec!["x", "xy"]);
+        assert_eq!(index.search(&[0.0, 0.0, 2.0], 1)[0].1.path, "z");
+        assert_eq!(index.search(&[1.0, 0.0, 0.0], 10).len(), 4);
+
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    /// Test that files are split into overlapping chunks with their line numbers
+    #[cfg_attr(not(doc), test)]
+    fn test_chunk_text() {
+        let text: String = (1..=100).map(|line| format!("line {}\n", line)).collect();
+        let chunks = chunk_text("a.rs", &text);
+
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| (chunk.start_line, chunk.end_line))
+                .collect::<Vec<(usize, usize)>>(),
+            vec![(1, 40), (31, 70), (61, 100)]
+        );
+        assert!(chunks[1].text.starts_with("line 31\n"));
+        assert!(chunks[1].text.ends_with("line 70"));
+        assert!(chunk_text("blank.rs", "\n\n  \n").is_empty());
+    }
+
+    /// Test that the index survives a save and load, and hidden and build directories are skipped
+    #[cfg_attr(not(doc), test)]
+    fn test_index_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join("target")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.path().join("target/out.rs"), "built").unwrap();
+        fs::write(dir.path().join(".git/HEAD"), "ref").unwrap();
+        fs::write(dir.path().join("README.md"), "# readme").unwrap();
+
+        assert_eq!(
+            collect_files(dir.path()).unwrap(),
+            vec![dir.path().join("README.md"), dir.path().join("src/main.rs")]
+        );
+
+        let path = dir.path().join("index.json");
+        let index = Index {
+            model: "test".to_string(),
+            chunks: vec![chunk("a.rs", vec![0.5, 0.25])],
+        };
+        index.save(&path).unwrap();
+        assert_eq!(Index::load(&path).unwrap(), index);
+        assert!(Index::load(&dir.path().join("missing.json"))
+            .unwrap_err()
+            .contains("pipe-gpt index build"));
+
+        let (context, taken) = context_instructions(&[&index.chunks[0]], 100);
+        assert_eq!(taken, 1);
+        assert!(context.contains("a.rs:1-1\n```\na.rs\n```"));
+
+        // a chunk that doesn't fit is skipped and a smaller one after it still taken
+        let big = Chunk {
+            text: "word ".repeat(100),
+            ..chunk("big.rs", vec![])
+        };
+        let (context, taken) = context_instructions(&[&big, &index.chunks[0]], 60);
+        assert_eq!(taken, 1);
+        assert!(!context.contains("big.rs"));
+        assert!(context.contains("a.rs:1-1"));
+    }
+
+    /// Test that symlinks are skipped, so a loop or a link out of the directory isn't followed
+    #[cfg(unix)]
+    #[cfg_attr(not(doc), test)]
+    fn test_collect_files_skips_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("src/loop")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("outside")).unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("secret.txt"),
+            dir.path().join("secret.txt"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            collect_files(dir.path()).unwrap(),
+            vec![dir.path().join("src/main.rs")]
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::*; // logging
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::cli::truncate::{read_truncated, Truncate};
+
+/// Largest image accepted by the API, in bytes
+pub const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Bytes shown by the hexdump and summary binary modes
+const HEXDUMP_BYTES: usize = 1024;
+
+/// Bytes sampled to tell text from images and binary data, both by [is_binary] and before
+/// piped input is truncated
+const SNIFF_BYTES: usize = 8192;
+
+/// # Binary Input Mode
+///
+/// What to do when the piped input is binary rather than text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryMode {
+    /// Exit with an error
+    Refuse,
+    /// Send a hexdump of the start of the input
+    Hexdump,
+    /// Send the size, detected file type, a short hexdump and the printable strings
+    Summary,
+}
+
+impl FromStr for BinaryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(BinaryMode::Refuse),
+            "hexdump" => Ok(BinaryMode::Hexdump),
+            "summary" => Ok(BinaryMode::Summary),
+            other => Err(format!(
+                "expected refuse, hexdump or summary, got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+/// Text encodings recognised in piped input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+/// # Piped Input
+///
+/// Stdin after decoding, either as text or as an image data URL
+#[derive(Debug, PartialEq)]
+pub enum PipedInput {
+    Text(String),
+    Image(String),
+}
+
+/// # Read Limited
+///
+/// Reads at most `max_bytes` from the reader, erroring rather than reading on when there is more
+/// so a multi-GB stream is never pulled into memory
+pub fn read_limited(reader: impl Read, max_bytes: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    reader
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("failed to read from stdin: {}", e))?;
+    if bytes.len() > max_bytes {
+        return Err(format!(
+            "input is larger than the {} byte limit. Reduce the input or raise --max_input_bytes",
+            max_bytes
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Decodes UTF-16 code units, replacing invalid surrogates rather than failing
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| from_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// # Decode Text
+///
+/// Detects the encoding from a byte order mark, otherwise tries UTF-8 and falls back to Latin-1,
+/// which maps every byte to a character so never fails
+pub fn decode_text(bytes: &[u8]) -> (String, Encoding) {
+    if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        return (String::from_utf8_lossy(rest).into_owned(), Encoding::Utf8);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        return (decode_utf16(rest, u16::from_le_bytes), Encoding::Utf16Le);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        return (decode_utf16(rest, u16::from_be_bytes), Encoding::Utf16Be);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (text.to_string(), Encoding::Utf8),
+        Err(_) => (
+            bytes.iter().map(|&byte| byte as char).collect(),
+            Encoding::Latin1,
+        ),
+    }
+}
+
+/// # Is Binary
+///
+/// Input is treated as binary if it has a NUL byte, or if more than one in ten of its first
+/// [SNIFF_BYTES] are control characters not found in text. Check for a UTF-16 byte order mark first, as
+/// UTF-16 text is full of NUL bytes.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(SNIFF_BYTES)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample
+        .iter()
+        .filter(|&&byte| byte < 0x20 && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x0C | 0x1B))
+        .count();
+    control * 10 > sample.len()
+}
+
+/// # Hexdump
+///
+/// Formats bytes like `xxd`: offset, sixteen hex bytes, then the printable characters
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(dump, "{:08x}: {:<47}  {}", row * 16, hex.join(" "), ascii);
+    }
+    dump
+}
+
+/// Guesses common binary file types from their magic bytes
+fn binary_file_type(bytes: &[u8]) -> &'static str {
+    let signatures: [(&[u8], &str); 9] = [
+        (b"\x7FELF", "ELF executable"),
+        (b"MZ", "Windows executable"),
+        (b"%PDF", "PDF document"),
+        (b"PK\x03\x04", "ZIP archive (also docx, xlsx, jar)"),
+        (b"\x1F\x8B", "gzip compressed data"),
+        (b"BZh", "bzip2 compressed data"),
+        (b"\xFD7zXZ\x00", "xz compressed data"),
+        (b"SQLite format 3\x00", "SQLite database"),
+        (b"\x00asm", "WebAssembly module"),
+    ];
+    signatures
+        .iter()
+        .find(|(magic, _)| bytes.starts_with(magic))
+        .map(|(_, name)| *name)
+        .unwrap_or("unknown binary data")
+}
+
+/// Printable ASCII runs of at least four characters, like `strings`
+fn printable_strings(bytes: &[u8], limit: usize) -> Vec<String> {
+    bytes
+        .split(|byte| !(byte.is_ascii_graphic() || *byte == b' '))
+        .filter(|run| run.len() >= 4)
+        .take(limit)
+        .map(|run| String::from_utf8_lossy(run).into_owned())
+        .collect()
+}
+
+/// # Binary Summary
+///
+/// Describes binary input in a form that can be sent to the model
+pub fn binary_summary(bytes: &[u8]) -> String {
+    format!(
+        "Binary input summary\nSize: {} bytes\nDetected type: {}\nFirst {} bytes:\n{}\nPrintable strings:\n{}",
+        bytes.len(),
+        binary_file_type(bytes),
+        bytes.len().min(256),
+        hexdump(&bytes[..bytes.len().min(256)]),
+        printable_strings(bytes, 100).join("\n")
+    )
+}
+
+/// Binary data, unless it is UTF-16 text, which is full of NUL bytes
+fn looks_binary(bytes: &[u8]) -> bool {
+    let has_utf16_bom = bytes.starts_with(b"\xFF\xFE") || bytes.starts_with(b"\xFE\xFF");
+    !has_utf16_bom && is_binary(bytes)
+}
+
+/// # Read Piped Input
+///
+/// Reads stdin up to the byte limit and decides how to send it: images as data URLs, text decoded
+/// to a string, and binary data refused or converted according to `binary`. With a `truncate`
+/// strategy, text over the limit is streamed and cut down instead of refused. The type is
+/// sniffed from the first bytes beforehand, as cutting an image or binary data would corrupt it.
+pub fn read_piped_input(
+    mut reader: impl Read,
+    max_bytes: usize,
+    binary: BinaryMode,
+    truncate: Option<Truncate>,
+) -> Result<PipedInput, String> {
+    let mut start = vec![];
+    reader
+        .by_ref()
+        .take(SNIFF_BYTES as u64)
+        .read_to_end(&mut start)
+        .map_err(|e| format!("failed to read from stdin: {}", e))?;
+    let is_text = image_mime_type(&start).is_none() && !looks_binary(&start);
+    let reader = Cursor::new(start).chain(reader);
+    let bytes = match truncate {
+        Some(strategy) if is_text => read_truncated(reader, max_bytes, strategy)?,
+        _ => read_limited(reader, max_bytes)?,
+    };
+
+    // piped screenshots are sent as images rather than text
+    if let Some(url) = image_data_url(&bytes) {
+        return Ok(PipedInput::Image(url));
+    }
+
+    if looks_binary(&bytes) {
+        return match binary {
+            BinaryMode::Refuse => Err(format!(
+                "the piped input looks like binary data ({}). Pass --binary hexdump or --binary summary to send a description of it instead",
+                binary_file_type(&bytes)
+            )),
+            BinaryMode::Hexdump => Ok(PipedInput::Text(hexdump(
+                &bytes[..bytes.len().min(HEXDUMP_BYTES)],
+            ))),
+            BinaryMode::Summary => Ok(PipedInput::Text(binary_summary(&bytes))),
+        };
+    }
+
+    let (text, encoding) = decode_text(&bytes);
+    debug!("piped input decoded as {:?}", encoding);
+    Ok(PipedInput::Text(text))
+}
+
+/// # Detect Image Type
+///
+/// Returns the MIME type of PNG, JPEG, GIF and WEBP images, the formats accepted by vision
+/// capable models, by checking their magic bytes
+pub fn image_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// # Image Data URL
+///
+/// Encodes image bytes as a base64 `data:` URL, or returns `None` if they are not an image
+pub fn image_data_url(bytes: &[u8]) -> Option<String> {
+    image_mime_type(bytes).map(|mime| format!("data:{};base64,{}", mime, STANDARD.encode(bytes)))
+}
+
+/// # Load Image File
+///
+/// Reads an image for the `--image` argument and encodes it as a data URL
+pub fn load_image(path: &Path) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("could not read image {:?}: {}", path, e))?;
+    if bytes.len() > MAX_IMAGE_BYTES {
+        return Err(format!(
+            "image {:?} is {} bytes, the limit is {} bytes",
+            path,
+            bytes.len(),
+            MAX_IMAGE_BYTES
+        ));
+    }
+    image_data_url(&bytes)
+        .ok_or_else(|| format!("{:?} is not a PNG, JPEG, GIF or WEBP image", path))
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// Test that supported images are recognised by their magic bytes
+    #[cfg_attr(not(doc), test)]
+    fn test_image_mime_type() {
+        assert_eq!(image_mime_type(PNG_HEADER), Some("image/png"));
+        assert_eq!(
+            image_mime_type(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(image_mime_type(b"GIF89a..."), Some("image/gif"));
+        assert_eq!(image_mime_type(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(image_mime_type(b"RIFF\0\0\0\0WAVEfmt "), None);
+        assert_eq!(image_mime_type(b"hello world"), None);
+        assert_eq!(image_mime_type(b""), None);
+    }
+
+    /// Test that images are encoded as data URLs
+    #[cfg_attr(not(doc), test)]
+    fn test_image_data_url() {
+        assert_eq!(
+            image_data_url(b"GIF89a").unwrap(),
+            "data:image/gif;base64,R0lGODlh"
+        );
+        assert!(image_data_url(b"plain text").is_none());
+    }
+
+    /// Test loading images from disk, rejecting files that are not images
+    #[cfg_attr(not(doc), test)]
+    fn test_load_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("screenshot.png");
+        fs::write(&image, PNG_HEADER).unwrap();
+        assert!(load_image(&image)
+            .unwrap()
+            .starts_with("data:image/png;base64,"));
+
+        let text = dir.path().join("notes.png");
+        fs::write(&text, "not really a png").unwrap();
+        assert!(load_image(&text).unwrap_err().contains("is not a PNG"));
+
+        assert!(load_image(&dir.path().join("missing.png")).is_err());
+    }
+
+    /// Test that a byte order mark selects the encoding and invalid UTF-8 falls back to Latin-1
+    #[cfg_attr(not(doc), test)]
+    fn test_decode_text() {
+        assert_eq!(
+            decode_text("héllo".as_bytes()),
+            ("héllo".to_string(), Encoding::Utf8)
+        );
+        assert_eq!(
+            decode_text(b"\xEF\xBB\xBFbom"),
+            ("bom".to_string(), Encoding::Utf8)
+        );
+        assert_eq!(
+            decode_text(b"\xFF\xFEh\0i\0"),
+            ("hi".to_string(), Encoding::Utf16Le)
+        );
+        assert_eq!(
+            decode_text(b"\xFE\xFF\0h\0i"),
+            ("hi".to_string(), Encoding::Utf16Be)
+        );
+        assert_eq!(
+            decode_text(b"caf\xE9"),
+            ("café".to_string(), Encoding::Latin1)
+        );
+    }
+
+    /// Test binary detection on NUL bytes and control characters
+    #[cfg_attr(not(doc), test)]
+    fn test_is_binary() {
+        assert!(!is_binary(b"plain text\twith tabs\r\n"));
+        assert!(!is_binary(b"\x1B[31mcoloured log\x1B[0m"));
+        assert!(!is_binary(b""));
+        assert!(is_binary(b"\x7FELF\x02\x01\x01\0\0"));
+        assert!(is_binary(&[0x01, 0x02, 0x03, b'a', b'b']));
+    }
+
+    /// Test reading piped input in each binary mode and with the byte limit
+    #[cfg_attr(not(doc), test)]
+    fn test_read_piped_input() {
+        let elf: &[u8] = b"\x7FELF\x02\x01\x01\0\0\0\0\0/lib64/ld-linux.so";
+
+        let err = read_piped_input(elf, 1024, BinaryMode::Refuse, None).unwrap_err();
+        assert!(err.contains("ELF executable"));
+
+        match read_piped_input(elf, 1024, BinaryMode::Hexdump, None).unwrap() {
+            PipedInput::Text(text) => assert!(text.starts_with("00000000: 7f 45 4c 46")),
+            other => panic!("expected text, got {:?}", other),
+        }
+        match read_piped_input(elf, 1024, BinaryMode::Summary, None).unwrap() {
+            PipedInput::Text(text) => {
+                assert!(text.contains("Size: 30 bytes"));
+                assert!(text.contains("/lib64/ld-linux.so"));
+            },
+            other => panic!("expected text, got {:?}", other),
+        }
+
+        assert_eq!(
+            read_piped_input(&b"\xFF\xFEh\0i\0"[..], 1024, BinaryMode::Refuse, None).unwrap(),
+            PipedInput::Text("hi".to_string())
+        );
+        assert!(matches!(
+            read_piped_input(PNG_HEADER, 1024, BinaryMode::Refuse, None).unwrap(),
+            PipedInput::Image(_)
+        ));
+
+        assert!(read_piped_input(&b"12345"[..], 5, BinaryMode::Refuse, None).is_ok());
+        assert!(
+            read_piped_input(&b"123456"[..], 5, BinaryMode::Refuse, None)
+                .unwrap_err()
+                .contains("5 byte limit")
+        );
+
+        // truncation only applies to text, so images and binary data are never cut up
+        let large_png = [PNG_HEADER, &[0; 2048]].concat();
+        assert!(read_piped_input(
+            &large_png[..],
+            1024,
+            BinaryMode::Refuse,
+            Some(Truncate::Head)
+        )
+        .unwrap_err()
+        .contains("1024 byte limit"));
+        let err = read_piped_input(elf, 16, BinaryMode::Refuse, Some(Truncate::Tail)).unwrap_err();
+        assert!(err.contains("byte limit"));
+        assert!(matches!(
+            read_piped_input(elf, 1024, BinaryMode::Hexdump, Some(Truncate::Tail)).unwrap(),
+            PipedInput::Text(text) if text.starts_with("00000000: 7f 45 4c 46")
+        ));
+        match read_piped_input(
+            &b"line\n".repeat(100)[..],
+            64,
+            BinaryMode::Refuse,
+            Some(Truncate::Head),
+        )
+        .unwrap()
+        {
+            PipedInput::Text(text) => assert!(text.contains("lines omitted")),
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    /// Test the hexdump layout matches xxd
+    #[cfg_attr(not(doc), test)]
+    fn test_hexdump() {
+        assert_eq!(
+            hexdump(b"0123456789abcdefXY\n"),
+            "00000000: 30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  0123456789abcdef\n00000010: 58 59 0a                                         XY.\n"
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Longest example line shown for each pattern
+const MAX_EXAMPLE_CHARS: usize = 300;
+
+/// Most patterns listed, so a log of mostly unique lines still gives a short summary
+const MAX_CLUSTERS: usize = 100;
+
+/// Keys that commonly hold the timestamp, level and message of a JSON log line
+const JSON_TIME_KEYS: [&str; 5] = ["timestamp", "@timestamp", "time", "ts", "date"];
+const JSON_LEVEL_KEYS: [&str; 4] = ["level", "severity", "lvl", "log.level"];
+const JSON_MESSAGE_KEYS: [&str; 4] = ["message", "msg", "error", "event"];
+
+/// # Log Format
+///
+/// The formats `--log` recognises, checked line by line so mixed input still works
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogFormat {
+    /// nginx and apache combined or common access logs
+    Combined,
+    Json,
+    Syslog,
+    Plain,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Combined => write!(f, "access log"),
+            LogFormat::Json => write!(f, "JSON lines"),
+            LogFormat::Syslog => write!(f, "syslog"),
+            LogFormat::Plain => write!(f, "plain text"),
+        }
+    }
+}
+
+/// One parsed log line
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub format: LogFormat,
+    pub timestamp: Option<String>,
+    pub level: Option<String>,
+    /// HTTP status of an access log line, kept out of the message so it is never masked
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+fn combined_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r#"^(\S+) \S+ \S+ \[([^\]]+)\] "([^"]*)" (\d{3}) (\S+)(?: "[^"]*" "([^"]*)")?"#)
+            .unwrap()
+    })
+}
+
+fn syslog_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(
+            r"^(?:<\d+>\d? ?)?([A-Z][a-z]{2} [ \d]\d \d{2}:\d{2}:\d{2}|\d{4}-\d{2}-\d{2}T\S+) (\S+) ([^\s:\[]+)(?:\[\d+\])?: ?(.*)$",
+        )
+        .unwrap()
+    })
+}
+
+fn plain_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"^\[?(\d{4}-\d{2}-\d{2}[T ][\d:.,]+(?:Z|[+-]\d{2}:?\d{2})?)\]?\s*(.*)$")
+            .unwrap()
+    })
+}
+
+fn level_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"(?i)\b(fatal|critical|error|err|warning|warn|info|debug|trace)\b").unwrap()
+    })
+}
+
+/// Lowercase level names, so `ERR`, `error` and `Error` are counted together
+fn normalise_level(level: &str) -> String {
+    match level.to_lowercase().as_str() {
+        "err" => "error".to_string(),
+        "warning" => "warn".to_string(),
+        "critical" | "crit" => "fatal".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Most severe first, so errors lead the summary whatever their count
+fn severity(level: Option<&str>) -> u8 {
+    match level {
+        Some("fatal") => 0,
+        Some("error") => 1,
+        Some("warn") => 2,
+        Some("info") => 3,
+        None => 4,
+        _ => 5,
+    }
+}
+
+/// # Parse Log Line
+///
+/// Recognises access log, JSON and syslog lines, falling back to plain text with an optional
+/// leading timestamp and a level found anywhere in the line
+pub fn parse_line(line: &str) -> LogEntry {
+    if let Some(captures) = combined_pattern().captures(line) {
+        let status: u16 = captures[4].parse().unwrap_or(0);
+        let level = match status {
+            500.. => "error",
+            400..=499 => "warn",
+            _ => "info",
+        };
+        let user_agent = captures
+            .get(6)
+            .map(|agent| format!(" \"{}\"", agent.as_str()))
+            .unwrap_or_default();
+        return LogEntry {
+            format: LogFormat::Combined,
+            timestamp: Some(captures[2].to_string()),
+            level: Some(level.to_string()),
+            status: Some(status),
+            message: format!("\"{}\"{}", &captures[3], user_agent),
+        };
+    }
+
+    if line.trim_start().starts_with('{') {
+        if let Ok(Value::Object(mut object)) = serde_json::from_str::<Value>(line) {
+            let mut take = |keys: &[&str]| {
+                keys.iter().find_map(|key| {
+                    object.remove(*key).map(|value| match value {
+                        Value::String(text) => text,
+                        other => other.to_string(),
+                    })
+                })
+            };
+            let timestamp = take(&JSON_TIME_KEYS);
+            let level = take(&JSON_LEVEL_KEYS).map(|level| normalise_level(&level));
+            let message = take(&JSON_MESSAGE_KEYS)
+                .unwrap_or_else(|| Value::Object(object.clone()).to_string());
+            return LogEntry {
+                format: LogFormat::Json,
+                timestamp,
+                level,
+                status: None,
+                message,
+            };
+        }
+    }
+
+    if let Some(captures) = syslog_pattern().captures(line) {
+        let message = format!("{}: {}", &captures[3], &captures[4]);
+        return LogEntry {
+            format: LogFormat::Syslog,
+            timestamp: Some(captures[1].to_string()),
+            level: level_pattern()
+                .captures(&captures[4])
+                .map(|level| normalise_level(&level[1])),
+            status: None,
+            message,
+        };
+    }
+
+    let (timestamp, message) = match plain_pattern().captures(line) {
+        Some(captures) => (Some(captures[1].to_string()), captures[2].to_string()),
+        None => (None, line.to_string()),
+    };
+    LogEntry {
+        format: LogFormat::Plain,
+        timestamp,
+        level: level_pattern()
+            .captures(&message)
+            .map(|level| normalise_level(&level[1])),
+        status: None,
+        message,
+    }
+}
+
+/// # Message Template
+///
+/// The message with the parts that vary between otherwise identical lines replaced, so they
+/// can be counted together: ids, addresses, times, hex and numbers
+pub fn template(message: &str) -> String {
+    static PATTERNS: OnceLock<Vec<(Regex, &'static str)>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        [
+            (
+                r"\b[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}\b",
+                "<uuid>",
+            ),
+            (r"\b\d{1,3}(?:\.\d{1,3}){3}(?::\d+)?\b", "<ip>"),
+            (
+                r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?",
+                "<time>",
+            ),
+            (r"\b\d{2}:\d{2}:\d{2}(?:[.,]\d+)?\b", "<time>"),
+            (r"\b0x[0-9a-fA-F]+\b", "<hex>"),
+        ]
+        .into_iter()
+        .map(|(pattern, replacement)| (Regex::new(pattern).unwrap(), replacement))
+        .collect()
+    });
+    static HEX: OnceLock<Regex> = OnceLock::new();
+    static NUMBER: OnceLock<Regex> = OnceLock::new();
+    let hex = HEX.get_or_init(|| Regex::new(r"\b[0-9a-fA-F]{6,}\b").unwrap());
+    let number = NUMBER.get_or_init(|| Regex::new(r"\d+(?:\.\d+)?").unwrap());
+
+    let mut template = message.trim().to_string();
+    for (pattern, replacement) in patterns {
+        template = pattern.replace_all(&template, *replacement).to_string();
+    }
+    // hashes mix digits and letters, unlike words such as "deadline" or plain numbers
+    template = hex
+        .replace_all(&template, |captures: &regex::Captures| {
+            let word = &captures[0];
+            if word.chars().any(|c| c.is_ascii_digit())
+                && word.chars().any(|c| c.is_ascii_alphabetic())
+            {
+                "<hex>".to_string()
+            } else {
+                word.to_string()
+            }
+        })
+        .to_string();
+    number.replace_all(&template, "<n>").to_string()
+}
+
+/// Lines that share a level, status and template
+struct Cluster {
+    level: Option<String>,
+    status: Option<u16>,
+    template: String,
+    count: usize,
+    first: Option<String>,
+    last: Option<String>,
+    example: String,
+}
+
+/// # Summarise Log
+///
+/// Parses each line and groups them by level and template, so repeated lines cost one line
+/// with a count. Patterns are listed most severe first, then most frequent, each with when it
+/// was first and last seen and one example line. Only the first [MAX_CLUSTERS] patterns are
+/// listed, followed by a count of the rest.
+pub fn summarise_log(input: &str) -> String {
+    let mut clusters: Vec<Cluster> = vec![];
+    let mut positions: HashMap<(Option<String>, Option<u16>, String), usize> = HashMap::new();
+    let mut formats: HashMap<LogFormat, usize> = HashMap::new();
+    let mut levels: HashMap<String, usize> = HashMap::new();
+    let mut lines = 0;
+
+    for line in input.lines().filter(|line| !line.trim().is_empty()) {
+        lines += 1;
+        let entry = parse_line(line);
+        *formats.entry(entry.format).or_default() += 1;
+        *levels
+            .entry(entry.level.clone().unwrap_or_else(|| "none".to_string()))
+            .or_default() += 1;
+
+        let key = (entry.level.clone(), entry.status, template(&entry.message));
+        let position = *positions.entry(key.clone()).or_insert_with(|| {
+            clusters.push(Cluster {
+                level: key.0,
+                status: key.1,
+                template: key.2,
+                count: 0,
+                first: entry.timestamp.clone(),
+                last: None,
+                example: line.chars().take(MAX_EXAMPLE_CHARS).collect(),
+            });
+            clusters.len() - 1
+        });
+        let cluster = &mut clusters[position];
+        cluster.count += 1;
+        if entry.timestamp.is_some() {
+            cluster.last = entry.timestamp;
+        }
+    }
+
+    let mut formats: Vec<(LogFormat, usize)> = formats.into_iter().collect();
+    formats.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    let mut levels: Vec<(String, usize)> = levels.into_iter().collect();
+    levels.sort_by(|a, b| {
+        severity(Some(a.0.as_str()).filter(|level| *level != "none"))
+            .cmp(&severity(
+                Some(b.0.as_str()).filter(|level| *level != "none"),
+            ))
+            .then(b.1.cmp(&a.1))
+    });
+    // stable, so equal patterns stay in the order they first appeared
+    clusters.sort_by(|a, b| {
+        severity(a.level.as_deref())
+            .cmp(&severity(b.level.as_deref()))
+            .then(b.count.cmp(&a.count))
+    });
+
+    let mut summary = format!(
+        "Log summary: {} lines grouped into {} patterns. Format: {}. Lines by level: {}.\n",
+        lines,
+        clusters.len(),
+        formats
+            .iter()
+            .map(|(format, count)| format!("{} ({})", format, count))
+            .collect::<Vec<String>>()
+            .join(", "),
+        levels
+            .iter()
+            .map(|(level, count)| format!("{} {}", level, count))
+            .collect::<Vec<String>>()
+            .join(", "),
+    );
+    summary.push_str("Variable parts are replaced with <n>, <ip>, <uuid>, <hex> and <time>.\n");
+    for cluster in clusters.iter().take(MAX_CLUSTERS) {
+        summary.push_str(&format!(
+            "\n[{}x] {}{}{}\n",
+            cluster.count,
+            cluster
+                .level
+                .as_ref()
+                .map(|level| format!("{} ", level))
+                .unwrap_or_default(),
+            cluster
+                .status
+                .map(|status| format!("{} ", status))
+                .unwrap_or_default(),
+            cluster.template
+        ));
+        match (&cluster.first, &cluster.last) {
+            (Some(first), Some(last)) if cluster.count > 1 && first != last => {
+                summary.push_str(&format!("  first {}, last {}\n", first, last))
+            },
+            (Some(first), _) => summary.push_str(&format!("  at {}\n", first)),
+            _ => {},
+        }
+        if cluster.count > 1 || cluster.template != cluster.example {
+            summary.push_str(&format!("  e.g. {}\n", cluster.example));
+        }
+    }
+    if clusters.len() > MAX_CLUSTERS {
+        let rest = &clusters[MAX_CLUSTERS..];
+        summary.push_str(&format!(
+            "\n[{} more patterns covering {} lines]\n",
+            rest.len(),
+            rest.iter().map(|cluster| cluster.count).sum::<usize>()
+        ));
+    }
+    summary
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// Test that each supported format is recognised and split up
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_line() {
+        let access = parse_line(
+            r#"10.0.0.1 - - [10/Oct/2024:13:55:36 +0000] "GET /api/users/42 HTTP/1.1" 502 157 "-" "curl/8.0""#,
+        );
+        assert_eq!(access.format, LogFormat::Combined);
+        assert_eq!(
+            access.timestamp.as_deref(),
+            Some("10/Oct/2024:13:55:36 +0000")
+        );
+        assert_eq!(access.level.as_deref(), Some("error"));
+        assert_eq!(access.status, Some(502));
+        assert_eq!(
+            access.message,
+            "\"GET /api/users/42 HTTP/1.1\" \"curl/8.0\""
+        );
+
+        let json = parse_line(
+            r#"{"ts": "2024-10-10T13:55:36Z", "level": "WARNING", "msg": "retrying", "attempt": 2}"#,
+        );
+        assert_eq!(json.format, LogFormat::Json);
+        assert_eq!(json.timestamp.as_deref(), Some("2024-10-10T13:55:36Z"));
+        assert_eq!(json.level.as_deref(), Some("warn"));
+        assert_eq!(json.message, "retrying");
+
+        let syslog =
+            parse_line("Oct 10 13:55:36 web1 sshd[812]: error: Connection reset by 1.2.3.4");
+        assert_eq!(syslog.format, LogFormat::Syslog);
+        assert_eq!(syslog.timestamp.as_deref(), Some("Oct 10 13:55:36"));
+        assert_eq!(syslog.level.as_deref(), Some("error"));
+        assert_eq!(syslog.message, "sshd: error: Connection reset by 1.2.3.4");
+
+        let plain = parse_line("2024-10-10 13:55:36,123 INFO worker started");
+        assert_eq!(plain.format, LogFormat::Plain);
+        assert_eq!(plain.timestamp.as_deref(), Some("2024-10-10 13:55:36,123"));
+        assert_eq!(plain.level.as_deref(), Some("info"));
+        assert_eq!(plain.message, "INFO worker started");
+    }
+
+    /// Test that the parts that vary between repeats are masked
+    #[cfg_attr(not(doc), test)]
+    fn test_template() {
+        assert_eq!(
+            template("user 42 from 10.0.0.1:5432 took 1.5ms at 2024-10-10T13:55:36Z"),
+            "user <n> from <ip> took <n>ms at <time>"
+        );
+        assert_eq!(
+            template("request 3fa85f64-5717-4562-b3fc-2c963f66afa6 failed at 0x7ffd2a"),
+            "request <uuid> failed at <hex>"
+        );
+        assert_eq!(template("commit deadbeef1 pushed"), "commit <hex> pushed");
+        assert_eq!(template("connection refused"), "connection refused");
+    }
+
+    /// Test that repeated lines are counted once, with errors first
+    #[cfg_attr(not(doc), test)]
+    fn test_summarise_log() {
+        let mut log = String::new();
+        for user in 0..50 {
+            log.push_str(&format!(
+                "2024-10-10 13:55:{:02} INFO user {} logged in\n",
+                user % 60,
+                user
+            ));
+        }
+        log.push_str("2024-10-10 13:56:00 ERROR database timeout after 30s\n");
+        log.push_str("2024-10-10 13:56:05 ERROR database timeout after 31s\n");
+
+        let summary = summarise_log(&log);
+        assert!(summary.starts_with("Log summary: 52 lines grouped into 2 patterns."));
+        assert!(summary.contains("Lines by level: error 2, info 50."));
+        let error = summary
+            .find("[2x] error ERROR database timeout after <n>s")
+            .unwrap();
+        let info = summary.find("[50x] info INFO user <n> logged in").unwrap();
+        assert!(error < info);
+        assert!(summary.contains("first 2024-10-10 13:56:00, last 2024-10-10 13:56:05"));
+        assert!(summary.contains("e.g. 2024-10-10 13:55:00 INFO user 0 logged in"));
+        assert!(summary.lines().count() < 12);
+    }
+
+    /// Test that a log of unique lines lists the first patterns and counts the rest
+    #[cfg_attr(not(doc), test)]
+    fn test_summarise_log_caps_patterns() {
+        let log: String = (0..MAX_CLUSTERS + 20)
+            .map(|word| format!("WARN unexpected token {}\n", "x".repeat(word + 1)))
+            .collect();
+
+        let summary = summarise_log(&log);
+        assert!(summary.contains("grouped into 120 patterns"));
+        assert!(summary.contains("[1x] warn WARN unexpected token x\n"));
+        assert!(!summary.contains(&"x".repeat(MAX_CLUSTERS + 1)));
+        assert!(summary.ends_with("\n[20 more patterns covering 20 lines]\n"));
+    }
+}
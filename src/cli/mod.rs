@@ -1,2 +1,18 @@
+pub mod batch;
+pub mod cancel;
+pub mod cmd;
+pub mod commit;
+pub mod context;
+pub mod diagnostics;
+pub mod edit;
+pub mod index;
+pub mod input;
+pub mod logs;
 pub mod output;
 pub mod parse;
+pub mod patch;
+pub mod pr;
+pub mod translate;
+pub mod triage;
+pub mod truncate;
+pub mod watch;
@@ -1,12 +1,265 @@
+use atty::Stream; // atty to determine if stdin can answer a prompt
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process; // needed to exit early
+use std::str::FromStr;
+use tempfile::NamedTempFile;
 // termimad for markdown rendering in the command line
 use termimad::{crossterm::style::Color::Yellow, gray, MadSkin};
+
+/// # Extraction Mode
+///
+/// What to pull out of a markdown response instead of printing it whole
+#[derive(Debug, Clone, PartialEq)]
+pub enum Extract {
+    /// Fenced code blocks, optionally only those tagged with the given language
+    Code(Option<String>),
+}
+
+impl FromStr for Extract {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "code" => Ok(Extract::Code(None)),
+            Some(("code", lang)) if !lang.is_empty() => {
+                Ok(Extract::Code(Some(lang.to_lowercase())))
+            },
+            _ => Err(format!("expected code or code:LANG, got '{}'", s)),
+        }
+    }
+}
+
+/// A fenced code block found in a markdown document
+#[derive(Debug, PartialEq)]
+pub struct CodeBlock {
+    /// First word of the fence info string e.g. `rust` for ```` ```rust ````
+    pub lang: Option<String>,
+    pub code: String,
+}
+
+/// # Find Fenced Code Blocks
+///
+/// Collects the ```` ``` ```` and `~~~` fenced blocks in a markdown document. A block left open
+/// at the end of the document is still returned, as truncated replies often end mid-block.
+pub fn fenced_code_blocks(markdown: &str) -> Vec<CodeBlock> {
+    let mut blocks = vec![];
+    // (fence character, fence length, language, lines collected so far)
+    let mut open: Option<(char, usize, Option<String>, Vec<&str>)> = None;
+
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        let fence_char = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let fence_len = fence_char.map_or(0, |c| trimmed.chars().take_while(|x| *x == c).count());
+
+        match open.take() {
+            Some((c, len, lang, lines)) => {
+                if fence_char == Some(c)
+                    && fence_len >= len
+                    && trimmed[fence_len..].trim().is_empty()
+                {
+                    blocks.push(CodeBlock {
+                        lang,
+                        code: lines.join("\n"),
+                    });
+                } else {
+                    let mut lines = lines;
+                    lines.push(line);
+                    open = Some((c, len, lang, lines));
+                }
+            },
+            None if fence_len >= 3 => {
+                let info = trimmed[fence_len..].trim();
+                let lang = info
+                    .split_whitespace()
+                    .next()
+                    .map(|word| {
+                        word.trim_matches(|c| c == '{' || c == '}' || c == '.')
+                            .to_lowercase()
+                    })
+                    .filter(|word| !word.is_empty());
+                open = Some((fence_char.unwrap(), fence_len, lang, vec![]));
+            },
+            None => {},
+        }
+    }
+
+    if let Some((_, _, lang, lines)) = open {
+        blocks.push(CodeBlock {
+            lang,
+            code: lines.join("\n"),
+        });
+    }
+
+    blocks
+}
+
+/// # Extract From Response
+///
+/// Returns only the requested parts of the response, or an error when nothing matched
+pub fn extract(markdown: &str, mode: &Extract) -> Result<String, String> {
+    match mode {
+        Extract::Code(lang) => {
+            let blocks: Vec<String> = fenced_code_blocks(markdown)
+                .into_iter()
+                .filter(|block| lang.is_none() || block.lang == *lang)
+                .map(|block| block.code)
+                .collect();
+            if blocks.is_empty() {
+                match lang {
+                    Some(lang) => Err(format!("no {} code blocks found in the response", lang)),
+                    None => Err("no code blocks found in the response".to_string()),
+                }
+            } else {
+                Ok(blocks.join("\n\n"))
+            }
+        },
+    }
+}
+
+/// # Atomic Write
+///
+/// Writes to a temporary file next to the destination and renames it into place, so the
+/// destination is never left half written. Keeps the permissions of an existing file.
+pub fn atomic_write(path: &Path, contents: &str) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut temp = NamedTempFile::new_in(dir)?;
+    temp.write_all(contents.as_bytes())?;
+    temp.as_file().sync_all()?;
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(temp.path(), metadata.permissions())?;
+    }
+    temp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// # Validate Structured Output
+///
+/// When the destination is `.json`, `.yaml`/`.yml` or `.toml`, checks the contents parse as that
+/// format so a bad conversion never replaces good data. Other extensions are not checked.
+pub fn validate_format(path: &Path, contents: &str) -> Result<(), String> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let result = match extension.as_deref() {
+        Some("json") => serde_json::from_str::<serde_json::Value>(contents)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Some("yaml") | Some("yml") => serde_yaml::from_str::<serde_yaml::Value>(contents)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        Some("toml") => toml::from_str::<toml::Table>(contents)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        _ => Ok(()),
+    };
+    result.map_err(|e| {
+        format!(
+            "the response is not valid {} so {:?} was not written: {}",
+            extension.unwrap_or_default(),
+            path,
+            e
+        )
+    })
+}
+
+/// # Is Piped Input File
+///
+/// True when stdin was redirected from the file at `path` e.g. `pipe-gpt -o file.json < file.json`
+#[cfg(unix)]
+pub fn is_stdin(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata("/dev/stdin"), fs::metadata(path)) {
+        (Ok(stdin), Ok(file)) => {
+            stdin.is_file() && stdin.dev() == file.dev() && stdin.ino() == file.ino()
+        },
+        _ => false,
+    }
+}
+
+/// # Is Piped Input File
+///
+/// Detecting the file behind stdin is only supported on unix
+#[cfg(not(unix))]
+pub fn is_stdin(_path: &Path) -> bool {
+    false
+}
+
+/// Prints an error and exits early
+pub fn fail(message: String) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(1);
+}
+
+/// # Ask On Terminal
+///
+/// Prints the prompt on stderr and returns the trimmed, lowercased answer. Reads stdin when it is
+/// a terminal, otherwise `/dev/tty`, as stdin usually holds the piped input. Returns `None` when
+/// there is no terminal to answer on, after printing the `hint` on how to go ahead without one.
+pub fn ask(prompt: &str, hint: &str) -> Option<String> {
+    let mut answers: Box<dyn BufRead> = if atty::is(Stream::Stdin) {
+        Box::new(io::stdin().lock())
+    } else {
+        match fs::File::open("/dev/tty") {
+            Ok(tty) => Box::new(BufReader::new(tty)),
+            Err(_) => {
+                eprintln!("{}No terminal to answer on, refusing. {}", prompt, hint);
+                return None;
+            },
+        }
+    };
+    eprint!("{}", prompt);
+    io::stderr().flush().ok();
+    let mut answer = String::new();
+    answers.read_line(&mut answer).ok()?;
+    Some(answer.trim().to_lowercase())
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no
+pub fn confirm(question: &str, hint: &str) -> bool {
+    matches!(
+        ask(&format!("{} [y/N] ", question), hint).as_deref(),
+        Some("y" | "yes")
+    )
+}
+
+/// # Write To File Or Error
+///
+/// Validates and atomically writes the response to the output file. Exits with a non-zero code
+/// if the request failed or the response could not be written.
+pub fn write_or_error(gpt_result: Result<String, String>, path: &Path) {
+    let written = gpt_result.and_then(|mut contents| {
+        if !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        validate_format(path, &contents)?;
+        atomic_write(path, &contents).map_err(|e| format!("could not write {:?}: {}", path, e))
+    });
+    if let Err(e) = written {
+        fail(e);
+    }
+}
+
+/// # Extract Code Or Error
+///
+/// Prints only the extracted code. Exits with a non-zero code if the request failed or no
+/// matching code was found, so a redirect to a file is never silently filled with prose.
+pub fn extract_or_error(gpt_result: Result<String, String>, mode: &Extract) {
+    match gpt_result.and_then(|markdown| extract(&markdown, mode)) {
+        Ok(code) => println!("{}", code),
+        Err(e) => fail(e),
+    }
+}
+
 /// # Render in Markdown, Plaintext or Error
 ///
-/// Takes a result from the Reqwest API call
-pub fn markdown_plaintext_or_error(
-    gpt_result: Result<String, reqwest::Error>,
-    render_markdown: bool,
-) {
+/// Takes the result of the API call
+pub fn markdown_plaintext_or_error(gpt_result: Result<String, String>, render_markdown: bool) {
     match gpt_result {
         Ok(markdown) => {
             if render_markdown {
@@ -22,3 +275,88 @@ pub fn markdown_plaintext_or_error(
         Err(e) => eprintln!("Error: {}", e),
     }
 }
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// Test parsing of the --extract argument
+    #[cfg_attr(not(doc), test)]
+    fn test_extract_from_str() {
+        assert_eq!("code".parse::<Extract>().unwrap(), Extract::Code(None));
+        assert_eq!(
+            "code:Rust".parse::<Extract>().unwrap(),
+            Extract::Code(Some("rust".to_string()))
+        );
+        assert!("code:".parse::<Extract>().is_err());
+        assert!("prose".parse::<Extract>().is_err());
+    }
+
+    /// Test that code is pulled out of chatter and fences are stripped
+    #[cfg_attr(not(doc), test)]
+    fn test_extract_code_blocks() {
+        let markdown = "Here is the improved code:\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n\nAnd the test:\n\n~~~sh\ncargo test\n~~~\nHope that helps!";
+
+        assert_eq!(
+            extract(markdown, &Extract::Code(None)).unwrap(),
+            "fn main() {\n    println!(\"hi\");\n}\n\ncargo test"
+        );
+        assert_eq!(
+            extract(markdown, &Extract::Code(Some("rust".to_string()))).unwrap(),
+            "fn main() {\n    println!(\"hi\");\n}"
+        );
+        assert!(extract(markdown, &Extract::Code(Some("python".to_string()))).is_err());
+        assert!(extract("No code here.", &Extract::Code(None)).is_err());
+    }
+
+    /// Test nested fences, untagged blocks and blocks left open by a truncated reply
+    #[cfg_attr(not(doc), test)]
+    fn test_fenced_code_blocks_edge_cases() {
+        let markdown =
+            "````markdown\n```rust\nlet x = 1;\n```\n````\n```\nplain\n```\n```python\nprint(1)";
+        let blocks = fenced_code_blocks(markdown);
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].lang.as_deref(), Some("markdown"));
+        assert_eq!(blocks[0].code, "```rust\nlet x = 1;\n```");
+        assert_eq!(blocks[1].lang, None);
+        assert_eq!(blocks[1].code, "plain");
+        assert_eq!(blocks[2].lang.as_deref(), Some("python"));
+        assert_eq!(blocks[2].code, "print(1)");
+    }
+
+    /// Test that structured formats are validated by extension
+    #[cfg_attr(not(doc), test)]
+    fn test_validate_format() {
+        assert!(validate_format(Path::new("a.json"), "{\"a\": 1}").is_ok());
+        assert!(validate_format(Path::new("a.JSON"), "Sure! {\"a\": 1}").is_err());
+        assert!(validate_format(Path::new("a.yaml"), "a: 1\nb: [1, 2]").is_ok());
+        assert!(validate_format(Path::new("a.yml"), "a: [1, 2").is_err());
+        assert!(validate_format(Path::new("a.toml"), "[a]\nb = 1").is_ok());
+        assert!(validate_format(Path::new("a.toml"), "b = ").is_err());
+        assert!(validate_format(Path::new("a.txt"), "anything").is_ok());
+        assert!(validate_format(Path::new("Makefile"), "anything").is_ok());
+    }
+
+    /// Test that an atomic write replaces the contents and keeps the file permissions
+    #[cfg_attr(not(doc), test)]
+    fn test_atomic_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.txt");
+
+        atomic_write(&path, "first").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+            atomic_write(&path, "second").unwrap();
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        assert!(!is_stdin(&path));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -1,10 +1,85 @@
+use crate::api::mcp::McpClient;
 use crate::api::openai::AssistantPurpose;
-use crate::api::openai::{count_tokens, create_conversation};
+use crate::api::openai::{
+    count_tokens, create_conversation, ChatRequest, Message, ResponseFormat, Role,
+    IMAGE_TOKEN_ESTIMATE,
+};
+use crate::api::schema::{json_instructions, load_schema};
+use crate::api::tools::{builtin_tools, mcp_tools, Tool, Toolbox};
+use crate::cli::batch::batch_command;
+use crate::cli::cmd::cmd_command;
+use crate::cli::commit::{commit_msg_command, hook_command};
+use crate::cli::context::context_from_dir;
+use crate::cli::diagnostics::explain_command;
+use crate::cli::edit::edit_command;
+use crate::cli::index::{ask_command, embed_command, index_command};
+use crate::cli::input::{load_image, BinaryMode};
+use crate::cli::logs::summarise_log;
+use crate::cli::output::{confirm, fail, is_stdin, Extract};
+use crate::cli::pr::pr_describe_command;
+use crate::cli::translate::translate_command;
+use crate::cli::triage::triage_command;
+use crate::cli::truncate::{truncate_to_tokens, Truncate};
+use crate::cli::watch::watch_command;
 use crate::config::models::load_config;
-use clap::{command, value_parser, Arg, ArgAction, Command}; // clap for command line argument parsing
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command}; // clap for command line argument parsing
 use log::*; // logging
-use openai_api_rust::chat::ChatBody;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::process; // needed to exit early
+use std::sync::Arc;
+
+/// # Parsed Options
+///
+/// Settings from the command line that control how the response is handled
+pub struct Options {
+    /// Render markdown instead of outputting as plain text
+    pub render_markdown: bool,
+    /// JSON Schema the response must validate against
+    pub schema: Option<Value>,
+    /// Print only these parts of the response
+    pub extract: Option<Extract>,
+    /// Write the response to this file instead of stdout
+    pub output: Option<PathBuf>,
+    /// Tools the model may call in agent mode
+    pub toolbox: Option<Arc<Toolbox>>,
+    /// Most requests made in agent mode before giving up
+    pub max_steps: usize,
+}
+
+/// Parses a `TOKEN_ID=BIAS` pair for the `--logit_bias` argument
+fn parse_logit_bias(s: &str) -> Result<(String, i32), String> {
+    let (token, bias) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TOKEN_ID=BIAS, got '{}'", s))?;
+    let bias = bias
+        .trim()
+        .parse::<i32>()
+        .map_err(|_| format!("bias must be a whole number, got '{}'", bias))?;
+    Ok((token.trim().to_string(), bias))
+}
+
+/// The `--truncate` argument, shared with subcommands that send their own input
+pub fn truncate_arg() -> Arg {
+    Arg::new("truncate")
+        .long("truncate")
+        .value_name("strategy")
+        .help("Cut input over the token or byte limit down to size instead of exiting: keep the head, tail, middle (start and end evenly) or smart (whole lines, mostly from the end)")
+        .required(false)
+        .value_parser(value_parser!(Truncate))
+}
+
+/// The `--context-dir` argument, shared with subcommands that send their own input
+pub fn context_dir_arg() -> Arg {
+    Arg::new("context_dir")
+        .long("context-dir")
+        .value_name("dir")
+        .help("Send the snippets of files under this directory that best match the prompt and input as context, cited by path and line, within the token limit")
+        .required(false)
+        .value_parser(value_parser!(PathBuf))
+}
+
 /// # Define Command Line Arguments
 ///
 /// This function defines command line arguments and their descriptions
@@ -13,14 +88,63 @@ use std::process; // needed to exit early
 ///
 /// - `-p [prepend]`: Text to prepend to the piped content e.g. `-p "find the pattern: "`
 /// - `--markdown`: Render markdown instead of outputting as plain text.
+/// - `--json`: Ask for a JSON object with no surrounding prose.
+/// - `--schema [schema.json]`: Validate the JSON reply against a JSON Schema, retrying with the
+///   validation errors on failure. Only the validated document is printed.
+/// - `--extract code[:lang]`: Print only the fenced code blocks of the response, optionally only
+///   those in the given language. Exits non-zero if there are none.
+/// - `--image [path.png]`: Send an image with the request, for vision capable models. Repeatable.
+///   Images piped to stdin are detected and sent the same way.
+/// - `--binary [refuse|hexdump|summary]`: What to do when binary data is piped in. Defaults to
+///   refusing it. Text is decoded from UTF-8, UTF-16 with a byte order mark, or Latin-1.
+/// - `--max_input_bytes [bytes]`: Refuse piped input larger than this rather than reading it all.
+/// - `--truncate [head|tail|middle|smart]`: Cut input that is over the token or byte limit down
+///   to size instead of exiting. `smart` keeps whole lines from the start and end, collapses
+///   repeated lines and marks what was removed.
+/// - `--log`: Treat the piped input as a log. Access log, JSON, syslog and plain lines are
+///   grouped into patterns with counts, so far more log fits in the token limit.
+/// - `--context-dir [dir]`: Send the snippets of files under the directory that best match the
+///   prompt and input, ranked with BM25 and cited by path and line, within the token limit.
+/// - `--agent`: Let the model read files, list directories, grep and run commands in the working
+///   directory before answering. Commands not in `--allow` or the `allowed_commands` config are
+///   confirmed on the terminal first. `--max_steps [steps]` limits the number of requests. Tools
+///   from the `tools` config section and the servers in `mcp_servers` are offered too.
+/// - `-o [path]`: Write the response to a file atomically. `.json`, `.yaml` and `.toml` responses
+///   are checked to parse first. Refuses to overwrite the file piped in unless `--in-place` is given.
 ///
 /// ## Advanced Usage
 ///
-/// - `-t [temperature]`: Set response temperature between 0.0 and 1.0. Higher values are more
+/// - `-t [temperature]`: Set response temperature between 0.0 and 2.0. Higher values are more
 ///   likely to generate diverse text, but with a risk of grammar errors and generation of nonsense
 /// - `-m [max_tokens]`: Advanced: Adjust token limit up to a maximum of 4096 for GPT4.
 /// - `-s [top_p]`: Advanced: Adjust top_p of response between 0.0 and 1.0. It's the nucleus
 ///   sampling parameter.
+/// - `--stop [sequence]`: Advanced: Stop generating at this sequence. Repeat for up to 4 sequences.
+/// - `--presence_penalty [value]`, `--frequency_penalty [value]`: Advanced: Penalties between
+///   -2.0 and 2.0 that discourage repeating topics or lines.
+/// - `--logit_bias [token_id=bias]`: Advanced: Bias a token id between -100 and 100. Repeatable.
+/// - `--user [id]`: Advanced: End-user identifier forwarded to the API.
+/// - `--seed [seed]`: Advanced: Seed for best-effort deterministic sampling.
+/// - `--response_format [text|json_object]`: Advanced: Request plain text or a JSON object.
+///
+/// The advanced arguments and `-p` are global, so they also apply to subcommands.
+///
+/// ## Subcommands
+///
+/// - `edit [file] -p [prompt]`: Ask for a unified diff to the file, preview it and apply it.
+/// - `batch [files] -p [prompt] -o [results.jsonl]`: Send one request per file, or per line with
+///   `--jsonl`, concurrently. `--resume` continues a partially completed batch.
+/// - `embed [texts]`: Print the embedding of each text, or of the piped input, as JSON.
+/// - `index build [dir]`: Chunk and embed the text files under the directory into a local index.
+/// - `ask [question]`: Answer a question using the most relevant chunks of the index as context.
+/// - `cmd [task]`: Suggest a shell command for the task, explain it and run it after confirmation.
+/// - `commit-msg`: Write a Conventional Commits message for the staged changes.
+/// - `hook install|uninstall`: Manage the prepare-commit-msg hook that runs `commit-msg`.
+/// - `pr-describe --base [branch]`: Write a pull request description for the commits since base.
+/// - `triage`: Find the failing tests in piped test output and suggest their root causes.
+/// - `explain`: Explain the piped compiler errors with the source they point to and propose fixes.
+/// - `watch [paths] -p [prompt]`: Send each file that changes with the prompt and print the reply.
+/// - `translate [file] --to [language]`: Translate a document's prose, keeping its structure.
 pub fn setup_arguments() -> Command {
     let config = load_config();
 
@@ -38,6 +162,107 @@ pub fn setup_arguments() -> Command {
         .required(false)
         .action(ArgAction::SetTrue);
 
+    let json_flag = Arg::new("json")
+        .long("json")
+        .value_name("json")
+        .help("Ask for a JSON object with no surrounding prose")
+        .required(false)
+        .action(ArgAction::SetTrue);
+
+    let schema_arg = Arg::new("schema")
+        .long("schema")
+        .value_name("schema.json")
+        .help(
+            "Validate the JSON reply against this JSON Schema, retrying on failure. Implies --json",
+        )
+        .required(false)
+        .value_parser(value_parser!(PathBuf));
+
+    let extract_arg = Arg::new("extract")
+        .long("extract")
+        .value_name("code[:lang]")
+        .help("Print only the fenced code blocks of the response e.g. \"code:rust\". Exits non-zero if none are found")
+        .required(false)
+        .conflicts_with_all(["markdown", "schema"])
+        .value_parser(value_parser!(Extract));
+
+    let image_arg = Arg::new("image")
+        .long("image")
+        .value_name("path")
+        .help("Send a PNG, JPEG, GIF or WEBP image with the request, for vision capable models. Repeatable")
+        .required(false)
+        .action(ArgAction::Append)
+        .value_parser(value_parser!(PathBuf));
+
+    let binary_arg = Arg::new("binary")
+        .long("binary")
+        .value_name("mode")
+        .help("What to do when binary data is piped in: refuse it, or send a hexdump or summary of it")
+        .required(false)
+        .default_value("refuse")
+        .value_parser(["refuse", "hexdump", "summary"]);
+
+    let max_input_bytes_arg = Arg::new("max_input_bytes")
+        .long("max_input_bytes")
+        .value_name("max_input_bytes")
+        .help(format!(
+            "Refuse piped input larger than this many bytes rather than reading it all. Defaults to {}",
+            config.max_input_bytes
+        ))
+        .required(false)
+        .value_parser(value_parser!(usize));
+
+    let log_flag = Arg::new("log")
+        .long("log")
+        .value_name("log")
+        .help("Treat the piped input as a log: group access log, JSON, syslog or plain lines into patterns with counts and send that summary instead of the raw lines")
+        .required(false)
+        .action(ArgAction::SetTrue);
+
+    let agent_flag = Arg::new("agent")
+        .long("agent")
+        .value_name("agent")
+        .help("Let the model read files, list directories, grep and run commands in the working directory before answering. Commands are confirmed unless allowed with --allow")
+        .required(false)
+        .conflicts_with("schema")
+        .action(ArgAction::SetTrue);
+
+    let allow_arg = Arg::new("allow")
+        .long("allow")
+        .value_name("program")
+        .help("Let the agent run this program without confirmation e.g. \"cargo\". Repeatable")
+        .required(false)
+        .requires("agent")
+        .action(ArgAction::Append);
+
+    let max_steps_arg = Arg::new("max_steps")
+        .long("max_steps")
+        .value_name("max_steps")
+        .help(format!(
+            "Most requests the agent may make before giving up. Defaults to {}",
+            config.agent_max_steps
+        ))
+        .required(false)
+        .requires("agent")
+        .value_parser(value_parser!(usize));
+
+    let output_arg = Arg::new("output")
+        .short('o')
+        .long("output")
+        .value_name("path")
+        .help("Write the response to this file atomically. .json, .yaml and .toml responses must parse before they are written")
+        .required(false)
+        .conflicts_with("markdown")
+        .value_parser(value_parser!(PathBuf));
+
+    let in_place_flag = Arg::new("in-place")
+        .long("in-place")
+        .value_name("in-place")
+        .help("Allow --output to overwrite the file piped in with <")
+        .required(false)
+        .requires("output")
+        .action(ArgAction::SetTrue);
+
     let max_tokens_arg = Arg::new("max_tokens")
         .short('m')
         .long("max_tokens")
@@ -47,6 +272,7 @@ pub fn setup_arguments() -> Command {
             config.max_tokens
         ))
         .required(false)
+        .global(true)
         .value_parser(value_parser!(i32));
 
     let prepend_arg = Arg::new("prepend")
@@ -54,14 +280,16 @@ pub fn setup_arguments() -> Command {
         .long("prepend")
         .value_name("prepend")
         .help("Text to prepend to the piped content e.g. \"find the pattern: \"")
-        .required(false);
+        .required(false)
+        .global(true);
 
     let temperature_arg = Arg::new("temperature")
         .short('t')
         .long("temperature")
         .value_name("temperature")
-        .help("Set response temperature between 0.0 and 1.0. Higher values are more likely to generate diverse text, but with a risk of grammar errors and generation of nonsense")
+        .help("Set response temperature between 0.0 and 2.0. Higher values are more likely to generate diverse text, but with a risk of grammar errors and generation of nonsense")
         .required(false)
+        .global(true)
         .value_parser(value_parser!(f32));
 
     let top_p_arg = Arg::new("top_p")
@@ -70,39 +298,235 @@ pub fn setup_arguments() -> Command {
         .value_name("top_p")
         .help("Advanced: Adjust top_p of response between 0.0 and 1.0. It's the nucleus sampling parameter")
         .required(false)
+        .global(true)
+        .value_parser(value_parser!(f32));
+
+    let stop_arg = Arg::new("stop")
+        .long("stop")
+        .value_name("stop")
+        .help("Advanced: Stop generating when this sequence is produced. Repeat for up to 4 sequences")
+        .required(false)
+        .global(true)
+        .action(ArgAction::Append);
+
+    let presence_penalty_arg = Arg::new("presence_penalty")
+        .long("presence_penalty")
+        .value_name("presence_penalty")
+        .help("Advanced: Penalty between -2.0 and 2.0. Positive values encourage new topics")
+        .required(false)
+        .global(true)
+        .allow_negative_numbers(true)
         .value_parser(value_parser!(f32));
 
+    let frequency_penalty_arg = Arg::new("frequency_penalty")
+        .long("frequency_penalty")
+        .value_name("frequency_penalty")
+        .help("Advanced: Penalty between -2.0 and 2.0. Positive values discourage repeated lines")
+        .required(false)
+        .global(true)
+        .allow_negative_numbers(true)
+        .value_parser(value_parser!(f32));
+
+    let logit_bias_arg = Arg::new("logit_bias")
+        .long("logit_bias")
+        .value_name("token_id=bias")
+        .help("Advanced: Bias a token id between -100 and 100 e.g. \"50256=-100\". Repeatable")
+        .required(false)
+        .global(true)
+        .allow_negative_numbers(true)
+        .action(ArgAction::Append)
+        .value_parser(parse_logit_bias);
+
+    let user_arg = Arg::new("user")
+        .long("user")
+        .value_name("user")
+        .help("Advanced: End-user identifier forwarded to the API")
+        .required(false)
+        .global(true);
+
+    let seed_arg = Arg::new("seed")
+        .long("seed")
+        .value_name("seed")
+        .help("Advanced: Seed for best-effort deterministic sampling")
+        .required(false)
+        .global(true)
+        .allow_negative_numbers(true)
+        .value_parser(value_parser!(i64));
+
+    let response_format_arg = Arg::new("response_format")
+        .long("response_format")
+        .value_name("response_format")
+        .help("Advanced: Request plain text or a JSON object from the model")
+        .required(false)
+        .global(true)
+        .value_parser(["text", "json_object"]);
+
     command!() // requires `cargo` feature
         .about("Sends piped content to GPT-4. Author: Craig Mayhew")
         .arg(code_review_flag)
         .arg(markdown_flag)
+        .arg(json_flag)
+        .arg(schema_arg)
+        .arg(extract_arg)
+        .arg(image_arg)
+        .arg(binary_arg)
+        .arg(max_input_bytes_arg)
+        .arg(truncate_arg())
+        .arg(log_flag)
+        .arg(context_dir_arg())
+        .arg(agent_flag)
+        .arg(allow_arg)
+        .arg(max_steps_arg)
+        .arg(output_arg)
+        .arg(in_place_flag)
         .arg(max_tokens_arg)
         .arg(prepend_arg)
         .arg(temperature_arg)
         .arg(top_p_arg)
+        .arg(stop_arg)
+        .arg(presence_penalty_arg)
+        .arg(frequency_penalty_arg)
+        .arg(logit_bias_arg)
+        .arg(user_arg)
+        .arg(seed_arg)
+        .arg(response_format_arg)
+        .subcommand(edit_command())
+        .subcommand(batch_command())
+        .subcommand(embed_command())
+        .subcommand(index_command())
+        .subcommand(ask_command())
+        .subcommand(cmd_command())
+        .subcommand(commit_msg_command())
+        .subcommand(hook_command())
+        .subcommand(pr_describe_command())
+        .subcommand(triage_command())
+        .subcommand(explain_command())
+        .subcommand(watch_command())
+        .subcommand(translate_command())
 }
 
-/// # Parse Command Line Arguments
+/// # Piped Input Settings
 ///
-/// Arguments are set to defaults where ommitted
-pub fn parse_arguments(input: &str, args_setup: Command) -> (ChatBody, bool) {
+/// The byte limit, binary mode and truncation strategy used when reading stdin, from the
+/// arguments or config file
+pub fn input_settings(matches: &ArgMatches) -> (usize, BinaryMode, Option<Truncate>) {
     let config = load_config();
+    let max_input_bytes = *matches
+        .get_one::<usize>("max_input_bytes")
+        .unwrap_or(&config.max_input_bytes);
+    let binary = matches
+        .get_one::<String>("binary")
+        .map(|mode| mode.parse::<BinaryMode>().expect("restricted by clap"))
+        .unwrap_or(BinaryMode::Refuse);
+    let truncate = matches.get_one::<Truncate>("truncate").copied();
+    (max_input_bytes, binary, truncate)
+}
 
-    let matches = args_setup.get_matches();
+/// # Token Limit
+///
+/// The most tokens a request may use, from `-m` or the config file
+pub fn token_limit(matches: &ArgMatches) -> usize {
+    let max_tokens = *matches
+        .get_one::<i32>("max_tokens")
+        .unwrap_or(&load_config().max_tokens);
+    max_tokens.max(0) as usize
+}
 
+/// # Parse Command Line Arguments
+///
+/// Arguments are set to defaults where ommitted. `piped_images` are data URLs of images that were
+/// piped in rather than given with `--image`.
+pub fn parse_arguments(
+    input: &str,
+    piped_images: Vec<String>,
+    matches: &ArgMatches,
+) -> (ChatRequest, Options) {
     let empty_string = String::from("");
 
     let prepend = matches
         .get_one::<String>("prepend")
         .unwrap_or(&empty_string);
-    let max_tokens = *matches
-        .get_one::<i32>("max_tokens")
-        .unwrap_or(&config.max_tokens);
-    let temperature = *matches
-        .get_one::<f32>("temperature")
-        .unwrap_or(&config.temperature);
-    let top_p = *matches.get_one::<f32>("top_p").unwrap_or(&0.95);
+    let log_summary;
+    let input = if *matches.get_one::<bool>("log").unwrap_or(&false) {
+        log_summary = summarise_log(input);
+        debug!("log summary: {}", log_summary);
+        log_summary.as_str()
+    } else {
+        input
+    };
+    let schema = matches
+        .get_one::<PathBuf>("schema")
+        .map(|path| match load_schema(path) {
+            Ok(schema) => schema,
+            Err(e) => {
+                eprintln!("Invalid request parameters: {}", e);
+                process::exit(1);
+            },
+        });
+    let json_mode = *matches.get_one::<bool>("json").unwrap_or(&false) || schema.is_some();
     let render_markdown = *matches.get_one::<bool>("markdown").unwrap_or(&false);
+    let extract = matches.get_one::<Extract>("extract").cloned();
+    let output = matches.get_one::<PathBuf>("output").cloned();
+    let in_place = *matches.get_one::<bool>("in-place").unwrap_or(&false);
+    let config = load_config();
+    let max_steps = *matches
+        .get_one::<usize>("max_steps")
+        .unwrap_or(&config.agent_max_steps);
+    let toolbox = if *matches.get_one::<bool>("agent").unwrap_or(&false) {
+        let mut allowed_commands = config.allowed_commands.clone();
+        allowed_commands.extend(
+            matches
+                .get_many::<String>("allow")
+                .unwrap_or_default()
+                .cloned(),
+        );
+        let mut tools = builtin_tools();
+        tools.extend(config.tools.iter().cloned().map(Tool::Custom));
+        // a server that fails to start shouldn't stop the others being used
+        let mut mcp = vec![];
+        for server in &config.mcp_servers {
+            match McpClient::connect(server).and_then(|client| {
+                let server_tools = mcp_tools(&client)?;
+                Ok((client, server_tools))
+            }) {
+                Ok((client, server_tools)) => {
+                    tools.extend(server_tools);
+                    mcp.push(client);
+                },
+                Err(e) => eprintln!("Warning: skipping MCP server: {}", e),
+            }
+        }
+        Some(Arc::new(Toolbox {
+            tools,
+            allowed_commands,
+            root: PathBuf::from("."),
+            confirm: |question| confirm(question, "Pass --allow to run it without confirmation."),
+            mcp,
+        }))
+    } else {
+        None
+    };
+    let mut images = piped_images;
+    for path in matches.get_many::<PathBuf>("image").unwrap_or_default() {
+        match load_image(path) {
+            Ok(url) => images.push(url),
+            Err(e) => {
+                eprintln!("Invalid request parameters: {}", e);
+                process::exit(1);
+            },
+        }
+    }
+
+    // checked before sending so a mistake costs nothing
+    if let Some(path) = &output {
+        if !in_place && is_stdin(path) {
+            eprintln!(
+                "Refusing to overwrite {:?} as it is also the input. Pass --in-place to allow this.",
+                path
+            );
+            process::exit(1);
+        }
+    }
 
     let assistant_purpose = if *matches.get_one::<bool>("code-review").unwrap_or(&false) {
         AssistantPurpose::CodeReviewer
@@ -110,58 +534,290 @@ pub fn parse_arguments(input: &str, args_setup: Command) -> (ChatBody, bool) {
         AssistantPurpose::Default
     };
 
-    let conversation = create_conversation(prepend, input, &assistant_purpose);
+    // json mode needs the word "JSON" in the conversation, so add instructions after the system prompt
+    let instructions = if json_mode {
+        Some(json_instructions(schema.as_ref()))
+    } else {
+        None
+    };
 
-    let token_count = count_tokens(&format!(
-        "{}{}{}",
+    let instructions = context_instructions(
         prepend,
         input,
-        &assistant_purpose.to_string()
-    ));
+        images.len(),
+        matches,
+        &assistant_purpose,
+        instructions,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Invalid request parameters: {}", e);
+        process::exit(1);
+    });
+
+    let mut chat_request = build_request(
+        prepend,
+        input,
+        &images,
+        matches,
+        &assistant_purpose,
+        instructions,
+        matches.get_one::<Truncate>("truncate").copied(),
+    );
+    if json_mode {
+        chat_request.response_format = Some(ResponseFormat::JsonObject);
+    }
+
+    (
+        chat_request,
+        Options {
+            render_markdown,
+            schema,
+            extract,
+            output,
+            toolbox,
+            max_steps,
+        },
+    )
+}
+
+/// # Add Context From Directory
+///
+/// Adds the snippets under `--context-dir` that best match the prompt and input to the
+/// instructions. The snippets get whatever the rest of the request leaves of the token limit.
+pub fn context_instructions(
+    prepend: &str,
+    input: &str,
+    image_count: usize,
+    matches: &ArgMatches,
+    purpose: &AssistantPurpose,
+    instructions: Option<String>,
+) -> Result<Option<String>, String> {
+    let Some(dir) = matches.get_one::<PathBuf>("context_dir") else {
+        return Ok(instructions);
+    };
+    let used = count_tokens(&format!(
+        "{}{}{}{}",
+        purpose,
+        prepend,
+        input,
+        instructions.as_deref().unwrap_or_default()
+    )) + image_count * IMAGE_TOKEN_ESTIMATE;
+    let budget = token_limit(matches).saturating_sub(used);
+    let query = format!("{}\n{}", prepend, input);
+    match context_from_dir(dir, &query, budget)? {
+        Some(context) => Ok(Some(match instructions {
+            Some(instructions) => format!("{}\n\n{}", instructions, context),
+            None => context,
+        })),
+        None => {
+            eprintln!(
+                "Warning: nothing under {:?} matched the question within the token limit",
+                dir
+            );
+            Ok(instructions)
+        },
+    }
+}
+
+/// # Build Chat Request
+///
+/// Builds the request from the sampling arguments shared by every command, falling back to the
+/// config file where ommitted. Input over the token limit is cut down with the `truncate`
+/// strategy when one is given. Exits early if the request is still over the limit or invalid.
+pub fn build_request(
+    prepend: &str,
+    input: &str,
+    images: &[String],
+    matches: &ArgMatches,
+    purpose: &AssistantPurpose,
+    instructions: Option<String>,
+    truncate: Option<Truncate>,
+) -> ChatRequest {
+    try_build_request(
+        prepend,
+        input,
+        images,
+        matches,
+        purpose,
+        instructions,
+        truncate,
+    )
+    .unwrap_or_else(|e| fail(e))
+}
+
+/// # Try Building Chat Request
+///
+/// As [build_request], but returns an error instead of exiting, for commands that send many
+/// requests and carry on past one that is over the limit
+pub fn try_build_request(
+    prepend: &str,
+    input: &str,
+    images: &[String],
+    matches: &ArgMatches,
+    purpose: &AssistantPurpose,
+    instructions: Option<String>,
+    truncate: Option<Truncate>,
+) -> Result<ChatRequest, String> {
+    let config = load_config();
+
+    let max_tokens = *matches
+        .get_one::<i32>("max_tokens")
+        .unwrap_or(&config.max_tokens);
+    let temperature = *matches
+        .get_one::<f32>("temperature")
+        .unwrap_or(&config.temperature);
+    let top_p = *matches.get_one::<f32>("top_p").unwrap_or(&config.top_p);
+    let stop = match matches.get_many::<String>("stop") {
+        Some(values) => Some(values.cloned().collect::<Vec<String>>()),
+        None => config.stop,
+    };
+    let presence_penalty = matches
+        .get_one::<f32>("presence_penalty")
+        .copied()
+        .or(config.presence_penalty);
+    let frequency_penalty = matches
+        .get_one::<f32>("frequency_penalty")
+        .copied()
+        .or(config.frequency_penalty);
+    let logit_bias = match matches.get_many::<(String, i32)>("logit_bias") {
+        Some(values) => Some(values.cloned().collect::<HashMap<String, i32>>()),
+        None => config.logit_bias,
+    };
+    let user = matches.get_one::<String>("user").cloned().or(config.user);
+    let seed = matches.get_one::<i64>("seed").copied().or(config.seed);
+    let response_format = match matches
+        .get_one::<String>("response_format")
+        .or(config.response_format.as_ref())
+    {
+        Some(format) => Some(
+            format
+                .parse::<ResponseFormat>()
+                .map_err(|e| format!("invalid request parameters: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let instructions = instructions.unwrap_or_default();
+
+    // everything but the input, which is the only part that can be truncated
+    let fixed_tokens = count_tokens(&format!(
+        "{}{}{}",
+        prepend,
+        &purpose.to_string(),
+        instructions
+    )) + images.len() * IMAGE_TOKEN_ESTIMATE;
+    let mut token_count = fixed_tokens + count_tokens(input);
+    let mut input = input.to_string();
+
+    if let Some(strategy) = truncate {
+        if token_count as i32 > max_tokens && (fixed_tokens as i32) < max_tokens {
+            input = truncate_to_tokens(&input, max_tokens as usize - fixed_tokens, strategy);
+            let truncated_count = fixed_tokens + count_tokens(&input);
+            eprintln!(
+                "Input truncated from an estimated {} to {} tokens to fit max tokens of {}",
+                token_count, truncated_count, max_tokens
+            );
+            token_count = truncated_count;
+        }
+    }
+
+    let mut conversation = create_conversation(prepend, &input, images, purpose);
+
+    if !instructions.is_empty() {
+        conversation.insert(1, Message::new(Role::System, instructions.clone()));
+    }
 
     if token_count as i32 > max_tokens {
-        eprintln!("Maximum tokens set to: {}", max_tokens);
-        eprintln!("Estimated tokens in request: {}", token_count);
-        eprintln!("Exiting early due to exceeding max input tokens. Reduce input length or increase max tokens.");
-        process::exit(1);
+        return Err(format!(
+            "estimated {} tokens is over the limit of {}. Reduce input length, increase max tokens or pass --truncate.",
+            token_count, max_tokens
+        ));
     }
 
-    let chatbody = ChatBody {
+    let chat_request = ChatRequest {
         model: config.model,
         max_tokens: Some(max_tokens),
         temperature: Some(temperature),
         top_p: Some(top_p),
         n: Some(1),
         stream: Some(false), // streaming output is not yet supported by this rust app
-        stop: None,
-        presence_penalty: None,
-        frequency_penalty: None,
-        logit_bias: None,
-        user: None,
+        stop,
+        presence_penalty,
+        frequency_penalty,
+        logit_bias,
+        user,
+        seed,
+        response_format,
+        tools: None,
         messages: conversation,
     };
 
-    info!("ChatBody struct generated");
-    debug!("ChatBody struct: {:?} ", chatbody);
+    chat_request
+        .validate()
+        .map_err(|e| format!("invalid request parameters: {}", e))?;
 
-    (chatbody, render_markdown)
+    info!("ChatRequest struct generated");
+    debug!("ChatRequest struct: {:?} ", chat_request);
+
+    Ok(chat_request)
 }
 
 #[cfg(any(test, doc))]
 mod tests {
     use super::*;
+    use crate::api::openai::Content;
 
     /// Test that the chat_body has some sensible values after being initialised
     #[cfg_attr(not(doc), test)]
     fn test_parse_arguments() {
         let config = load_config();
-        let command = setup_arguments();
+        let matches = setup_arguments().get_matches_from(["pipe-gpt"]);
         let input = "Test".to_string();
-        let (chat_body, render_markdown) = parse_arguments(&input, command);
+        let (chat_body, options) = parse_arguments(&input, vec![], &matches);
 
         assert_eq!(chat_body.model, "gpt-4o");
         assert_eq!(chat_body.max_tokens.unwrap(), config.max_tokens);
         assert_eq!(chat_body.temperature.unwrap(), config.temperature);
-        assert_eq!(render_markdown, false);
+        assert!(!options.render_markdown);
+        assert!(options.schema.is_none());
+        assert!(options.extract.is_none());
+        assert!(options.output.is_none());
+        assert!(options.toolbox.is_none());
+        assert_eq!(options.max_steps, config.agent_max_steps);
+        assert_eq!(chat_body.response_format, None);
+    }
+
+    /// Test that matching snippets from --context-dir are sent after the system prompt
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_arguments_context_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("deploy.md"), "Run make deploy to ship").unwrap();
+        std::fs::write(dir.path().join("other.md"), "Unrelated notes").unwrap();
+        let matches = setup_arguments().get_matches_from([
+            "pipe-gpt",
+            "-p",
+            "How do I deploy?",
+            "--context-dir",
+            dir.path().to_str().unwrap(),
+        ]);
+        let (chat_body, _) = parse_arguments("", vec![], &matches);
+
+        let Content::Text(context) = &chat_body.messages[1].content else {
+            panic!("context is text");
+        };
+        assert_eq!(chat_body.messages[1].role, Role::System);
+        assert!(context.contains("deploy.md:1-1]\n```\nRun make deploy to ship"));
+        assert!(!context.contains("Unrelated"));
+    }
+
+    /// Test that logit bias pairs are parsed and malformed pairs are rejected
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_logit_bias() {
+        assert_eq!(
+            parse_logit_bias("50256=-100").unwrap(),
+            ("50256".to_string(), -100)
+        );
+        assert!(parse_logit_bias("50256").is_err());
+        assert!(parse_logit_bias("50256=lots").is_err());
     }
 }
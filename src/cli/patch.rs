@@ -0,0 +1,329 @@
+// termimad re-exports crossterm, used here to colour the diff preview
+use termimad::crossterm::style::Stylize;
+
+/// A single line of a unified diff hunk
+#[derive(Debug, Clone, PartialEq)]
+pub enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// # Diff Hunk
+///
+/// One `@@ -a,b +c,d @@` section of a unified diff
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+    /// The `@@ ... @@` header line, kept for previews and error messages
+    pub header: String,
+    /// Line in the original file where the hunk starts, counting from 1
+    pub old_start: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    /// Lines the hunk expects to find in the original file
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+
+    /// Lines the hunk leaves in the new file
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Add(text) => Some(text.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Reads the old start line from a `@@ -a,b +c,d @@` header
+fn parse_hunk_header(line: &str) -> Result<usize, String> {
+    let old_range = line
+        .trim_start_matches('@')
+        .split_whitespace()
+        .next()
+        .and_then(|range| range.strip_prefix('-'))
+        .ok_or_else(|| format!("malformed hunk header '{}'", line))?;
+    let start = old_range.split(',').next().unwrap_or_default();
+    start
+        .parse::<usize>()
+        .map_err(|_| format!("malformed hunk header '{}'", line))
+}
+
+/// Reads the old and new line counts from a `@@ -a,b +c,d @@` header. A range without a count
+/// is one line long.
+fn hunk_line_counts(line: &str) -> (usize, usize) {
+    let count = |prefix: char| {
+        line.trim_start_matches('@')
+            .split_whitespace()
+            .find_map(|range| range.strip_prefix(prefix))
+            .map(|range| match range.split_once(',') {
+                Some((_, count)) => count.parse().unwrap_or(0),
+                None => 1,
+            })
+            .unwrap_or(0)
+    };
+    (count('-'), count('+'))
+}
+
+/// # Parse Unified Diff
+///
+/// Parses the hunks of a single-file unified diff. File headers and any prose around the diff are
+/// ignored. Line counts in hunk headers are not enforced, as models often get them wrong; a hunk
+/// simply ends at the first line that is not part of it. The counts are only used to tell a
+/// removed `-- comment` or added `++ x` line from the `--- `/`+++ ` header of another file, which
+/// can only come once the hunk has all its lines.
+pub fn parse_unified_diff(diff: &str) -> Result<Vec<Hunk>, String> {
+    let mut hunks: Vec<Hunk> = vec![];
+    let mut in_hunk = false;
+    let (mut old_remaining, mut new_remaining) = (0, 0);
+
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            hunks.push(Hunk {
+                header: line.to_string(),
+                old_start: parse_hunk_header(line)?,
+                lines: vec![],
+            });
+            (old_remaining, new_remaining) = hunk_line_counts(line);
+            in_hunk = true;
+            continue;
+        }
+        let file_header = (line.starts_with("--- ") || line.starts_with("+++ "))
+            && old_remaining == 0
+            && new_remaining == 0;
+        if !in_hunk || file_header {
+            in_hunk = false;
+            continue;
+        }
+
+        let hunk = hunks.last_mut().expect("hunk started above");
+        match line.chars().next() {
+            Some(' ') => {
+                hunk.lines.push(HunkLine::Context(line[1..].to_string()));
+                old_remaining = old_remaining.saturating_sub(1);
+                new_remaining = new_remaining.saturating_sub(1);
+            },
+            Some('-') => {
+                hunk.lines.push(HunkLine::Remove(line[1..].to_string()));
+                old_remaining = old_remaining.saturating_sub(1);
+            },
+            Some('+') => {
+                hunk.lines.push(HunkLine::Add(line[1..].to_string()));
+                new_remaining = new_remaining.saturating_sub(1);
+            },
+            // "\ No newline at end of file"
+            Some('\\') => {},
+            // blank context lines frequently lose their leading space
+            None => hunk.lines.push(HunkLine::Context(String::new())),
+            Some(_) => in_hunk = false,
+        }
+    }
+
+    hunks.retain(|hunk| !hunk.lines.is_empty());
+    if hunks.is_empty() {
+        return Err("no hunks found in the diff".to_string());
+    }
+    Ok(hunks)
+}
+
+/// Finds where `needle` occurs in `haystack` at or after `from`, preferring the position
+/// closest to `expected`
+fn find_closest(haystack: &[&str], needle: &[&str], from: usize, expected: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(expected.clamp(from, haystack.len()));
+    }
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    (from..=haystack.len() - needle.len())
+        .filter(|&start| haystack[start..start + needle.len()] == *needle)
+        .min_by_key(|&start| start.abs_diff(expected))
+}
+
+/// # Patched File
+///
+/// The text with a diff applied, and the hunks that had to be moved to apply
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patched {
+    pub text: String,
+    /// One line for each hunk whose context was found at a different line than its header
+    /// states, saying where it was applied
+    pub moved: Vec<String>,
+}
+
+/// # Apply Unified Diff
+///
+/// Applies the hunks to the original text. Each hunk's context and removed lines must match the
+/// file exactly. A hunk whose context is not at the line its header states is applied at the
+/// closest match instead, and noted in [Patched::moved] so it can be confirmed. Returns an error
+/// naming the first hunk that does not apply, leaving nothing half applied.
+pub fn apply_hunks(original: &str, hunks: &[Hunk]) -> Result<Patched, String> {
+    let line_ending = if original.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let lines: Vec<&str> = original.lines().collect();
+
+    let mut output: Vec<&str> = vec![];
+    let mut moved = vec![];
+    let mut position = 0;
+
+    for (index, hunk) in hunks.iter().enumerate() {
+        let old = hunk.old_lines();
+        let stated = hunk.old_start.saturating_sub(1);
+        let expected = stated.max(position);
+        let start = find_closest(&lines, &old, position, expected).ok_or_else(|| {
+            format!(
+                "hunk {} ({}) does not apply: its context was not found in the file",
+                index + 1,
+                hunk.header
+            )
+        })?;
+        if start != stated {
+            moved.push(format!(
+                "hunk {} ({}) applied at line {}, {} line(s) {} than its header states",
+                index + 1,
+                hunk.header,
+                start + 1,
+                start.abs_diff(stated),
+                if start > stated { "later" } else { "earlier" }
+            ));
+        }
+
+        output.extend_from_slice(&lines[position..start]);
+        output.extend(hunk.new_lines());
+        position = start + old.len();
+    }
+    output.extend_from_slice(&lines[position..]);
+
+    let mut text = output.join(line_ending);
+    if original.ends_with('\n') || original.is_empty() {
+        text.push_str(line_ending);
+    }
+    Ok(Patched { text, moved })
+}
+
+/// # Coloured Diff Preview
+///
+/// Renders the hunks for the terminal, with removed lines in red and added lines in green
+pub fn preview(hunks: &[Hunk]) -> String {
+    let mut rendered = vec![];
+    for hunk in hunks {
+        rendered.push(hunk.header.clone().cyan().to_string());
+        for line in &hunk.lines {
+            rendered.push(match line {
+                HunkLine::Context(text) => format!(" {}", text),
+                HunkLine::Remove(text) => format!("-{}", text).red().to_string(),
+                HunkLine::Add(text) => format!("+{}", text).green().to_string(),
+            });
+        }
+    }
+    rendered.join("\n")
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str =
+        "fn main() {\n    let a = 1;\n    let b = 2;\n    println!(\"{}\", a + b);\n}\n";
+
+    /// Test that hunks are parsed with headers and prose ignored
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_unified_diff() {
+        let diff = "Here you go:\n--- a/src/main.rs\n+++ b/src/main.rs\n@@ -2,2 +2,2 @@\n     let a = 1;\n-    let b = 2;\n+    let b = 3;\nThat should do it.";
+        let hunks = parse_unified_diff(diff).unwrap();
+
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].old_start, 2);
+        assert_eq!(
+            hunks[0].lines,
+            vec![
+                HunkLine::Context("    let a = 1;".to_string()),
+                HunkLine::Remove("    let b = 2;".to_string()),
+                HunkLine::Add("    let b = 3;".to_string()),
+            ]
+        );
+
+        assert!(parse_unified_diff("I could not produce a diff").is_err());
+        assert!(parse_unified_diff("@@ nonsense @@\n+x").is_err());
+    }
+
+    /// Test that removing a `-- ` comment or adding a `++ ` line doesn't end the hunk early
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_unified_diff_dashed_lines() {
+        let original = "SELECT 1;\n-- old comment\nSELECT 2;\n";
+        let diff = "--- a/q.sql\n+++ b/q.sql\n@@ -1,3 +1,3 @@\n SELECT 1;\n--- old comment\n+++ new comment\n SELECT 2;\n--- a/other.sql\n+++ b/other.sql";
+        let hunks = parse_unified_diff(diff).unwrap();
+
+        assert_eq!(
+            hunks[0].lines,
+            vec![
+                HunkLine::Context("SELECT 1;".to_string()),
+                HunkLine::Remove("-- old comment".to_string()),
+                HunkLine::Add("++ new comment".to_string()),
+                HunkLine::Context("SELECT 2;".to_string()),
+            ]
+        );
+        assert_eq!(
+            apply_hunks(original, &hunks).unwrap().text,
+            "SELECT 1;\n++ new comment\nSELECT 2;\n"
+        );
+        assert_eq!(hunk_line_counts("@@ -4 +4,2 @@"), (1, 2));
+    }
+
+    /// Test that a diff applies, and that a hunk whose line numbers are off is applied at its
+    /// context but reported as moved
+    #[cfg_attr(not(doc), test)]
+    fn test_apply_hunks() {
+        let expected = "fn main() {\n    let a = 1;\n    let b = 2;\n    let c = 3;\n    println!(\"{}\", a + b);\n}\n";
+        let diff =
+            "@@ -3,2 +3,3 @@\n     let b = 2;\n+    let c = 3;\n     println!(\"{}\", a + b);";
+        let patched = apply_hunks(ORIGINAL, &parse_unified_diff(diff).unwrap()).unwrap();
+        assert_eq!(patched.text, expected);
+        assert!(patched.moved.is_empty());
+
+        let diff =
+            "@@ -7,2 +7,3 @@\n     let b = 2;\n+    let c = 3;\n     println!(\"{}\", a + b);";
+        let patched = apply_hunks(ORIGINAL, &parse_unified_diff(diff).unwrap()).unwrap();
+        assert_eq!(patched.text, expected);
+        assert_eq!(
+            patched.moved,
+            vec!["hunk 1 (@@ -7,2 +7,3 @@) applied at line 3, 4 line(s) earlier than its header states"]
+        );
+    }
+
+    /// Test that a diff whose context does not match is rejected
+    #[cfg_attr(not(doc), test)]
+    fn test_apply_hunks_rejects_mismatched_context() {
+        let diff = "@@ -2,1 +2,1 @@\n-    let a = 100;\n+    let a = 2;";
+        let err = apply_hunks(ORIGINAL, &parse_unified_diff(diff).unwrap()).unwrap_err();
+
+        assert!(err.starts_with("hunk 1 (@@ -2,1 +2,1 @@) does not apply"));
+    }
+
+    /// Test that multiple hunks apply in order and CRLF line endings are preserved
+    #[cfg_attr(not(doc), test)]
+    fn test_apply_hunks_multiple_crlf() {
+        let original = "a\r\nb\r\nc\r\nd\r\n";
+        let diff = "@@ -1 +1 @@\n-a\n+A\n@@ -4 +4 @@\n-d\n+D";
+
+        assert_eq!(
+            apply_hunks(original, &parse_unified_diff(diff).unwrap())
+                .unwrap()
+                .text,
+            "A\r\nb\r\nc\r\nD\r\n"
+        );
+    }
+}
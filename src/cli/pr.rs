@@ -0,0 +1,252 @@
+use clap::{value_parser, Arg, ArgMatches, Command};
+use log::*; // logging
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::api::openai::{count_tokens, send_to_gpt4, AssistantPurpose};
+use crate::cli::cancel::cancellable;
+use crate::cli::commit::git;
+use crate::cli::output::{fail, markdown_plaintext_or_error};
+use crate::cli::parse::{build_request, token_limit};
+use crate::cli::truncate::{truncate_to_tokens, Truncate};
+
+/// Where GitHub looks for a pull request template, relative to the repository root
+const PR_TEMPLATE_PATHS: [&str; 6] = [
+    ".github/pull_request_template.md",
+    ".github/PULL_REQUEST_TEMPLATE.md",
+    "pull_request_template.md",
+    "PULL_REQUEST_TEMPLATE.md",
+    "docs/pull_request_template.md",
+    "docs/PULL_REQUEST_TEMPLATE.md",
+];
+
+/// Tokens kept free for the labels and fences around each part of the request
+const FORMATTING_TOKENS: usize = 64;
+
+/// # Define PR Describe Subcommand
+///
+/// `pipe-gpt pr-describe --base main` writes a description of the changes since `base`
+pub fn pr_describe_command() -> Command {
+    Command::new("pr-describe")
+        .about("Write a pull request description from the commits and diff between a base branch and HEAD")
+        .arg(
+            Arg::new("base")
+                .long("base")
+                .value_name("branch")
+                .help("Branch the pull request merges into")
+                .required(false)
+                .default_value("main"),
+        )
+        .arg(
+            Arg::new("template")
+                .long("template")
+                .value_name("template.md")
+                .help("Pull request template to fill in. Defaults to the repository's own, if it has one")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+}
+
+/// # Find PR Template
+///
+/// The first of GitHub's template locations that exists under the repository root
+pub fn find_pr_template(root: &Path) -> Option<PathBuf> {
+    PR_TEMPLATE_PATHS
+        .iter()
+        .map(|path| root.join(path))
+        .find(|path| path.is_file())
+}
+
+/// # Split Diff By File
+///
+/// Splits `git diff` output at each `diff --git` header into the path and that file's diff
+pub fn split_diff_by_file(diff: &str) -> Vec<(String, String)> {
+    let mut files: Vec<(String, String)> = vec![];
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("diff --git ") {
+            let path = header
+                .rsplit_once(" b/")
+                .map(|(_, path)| path)
+                .unwrap_or(header)
+                .to_string();
+            files.push((path, String::new()));
+        }
+        if let Some((_, file_diff)) = files.last_mut() {
+            file_diff.push_str(line);
+            file_diff.push('\n');
+        }
+    }
+    files
+}
+
+/// # Summarise Files
+///
+/// Asks for a summary of each file's diff in turn, cutting down any single file that is over the
+/// token limit itself
+async fn summarise_files(matches: &ArgMatches, diff: &str) -> Result<String, String> {
+    let files = split_diff_by_file(diff);
+    let budget = token_limit(matches).saturating_sub(
+        count_tokens(&AssistantPurpose::DiffSummary.to_string()) + FORMATTING_TOKENS,
+    );
+    let mut summaries = String::new();
+    for (index, (path, file_diff)) in files.iter().enumerate() {
+        eprintln!("[{}/{}] summarising {}", index + 1, files.len(), path);
+        let file_diff = truncate_to_tokens(file_diff, budget, Truncate::Smart);
+        let chat_request = build_request(
+            &format!("```diff\n{}\n```", file_diff),
+            "",
+            &[],
+            matches,
+            &AssistantPurpose::DiffSummary,
+            None,
+            None,
+        );
+        let summary = send_to_gpt4(&chat_request)
+            .await
+            .map_err(|e| e.to_string())?;
+        summaries.push_str(&format!("### {}\n{}\n\n", path, summary.trim()));
+    }
+    Ok(summaries)
+}
+
+/// # Run PR Describe Subcommand
+///
+/// - Collects the commit log and diff between the merge base with `--base` and HEAD
+/// - Uses the repository's pull request template, or `--template`, if there is one
+/// - Sends the whole diff when it fits in the token limit, otherwise a summary of each file
+/// - Prints the description
+pub async fn pr_describe(matches: &ArgMatches) {
+    let base = matches
+        .get_one::<String>("base")
+        .expect("base has a default");
+    let empty_string = String::from("");
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .unwrap_or(&empty_string);
+
+    // two dots for the log, so commits that landed on base since the branch point are left out,
+    // and three for the diff, which then starts from the merge base
+    let log_range = format!("{}..HEAD", base);
+    let diff_range = format!("{}...HEAD", base);
+    let log =
+        git(&["log", "--no-merges", "--format=- %s%n%b", &log_range]).unwrap_or_else(|e| fail(e));
+    // a long branch shouldn't leave no room for the changes, so keep the newest commits
+    let log = truncate_to_tokens(&log, token_limit(matches) / 4, Truncate::Head);
+    let diff =
+        git(&["diff", "--no-color", "--no-ext-diff", &diff_range]).unwrap_or_else(|e| fail(e));
+    if diff.trim().is_empty() {
+        fail(format!("there are no changes between {} and HEAD", base));
+    }
+
+    let template_path = matches.get_one::<PathBuf>("template").cloned().or_else(|| {
+        let root = git(&["rev-parse", "--show-toplevel"]).ok()?;
+        find_pr_template(Path::new(root.trim()))
+    });
+    let template = match &template_path {
+        Some(path) => {
+            debug!("using pull request template {:?}", path);
+            Some(
+                fs::read_to_string(path)
+                    .unwrap_or_else(|e| fail(format!("could not read {:?}: {}", path, e))),
+            )
+        },
+        None => None,
+    };
+    let instructions = template
+        .as_ref()
+        .map(|template| format!("Fill in this pull request template:\n{}", template));
+
+    let fixed_tokens = count_tokens(&format!(
+        "{}{}{}{}",
+        AssistantPurpose::PullRequest,
+        prompt,
+        log,
+        instructions.as_deref().unwrap_or_default()
+    )) + FORMATTING_TOKENS;
+    let budget = token_limit(matches).saturating_sub(fixed_tokens);
+
+    let response = cancellable(async {
+        let changes = if count_tokens(&diff) <= budget {
+            format!("Diff:\n```diff\n{}\n```", diff)
+        } else {
+            eprintln!("The diff is over the token limit, summarising each file instead");
+            let summaries = summarise_files(matches, &diff).await?;
+            format!(
+                "Summary of the changes to each file:\n{}",
+                truncate_to_tokens(&summaries, budget, Truncate::Smart)
+            )
+        };
+        let request = format!(
+            "{}\n\nCommits since {}:\n{}\n\n{}",
+            prompt, base, log, changes
+        );
+        let chat_request = build_request(
+            request.trim(),
+            "",
+            &[],
+            matches,
+            &AssistantPurpose::PullRequest,
+            instructions.clone(),
+            None,
+        );
+        send_to_gpt4(&chat_request).await.map_err(|e| e.to_string())
+    })
+    .await;
+    markdown_plaintext_or_error(response, false);
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// Test that each file's diff is kept with its path, including renames
+    #[cfg_attr(not(doc), test)]
+    fn test_split_diff_by_file() {
+        let diff = "\
+diff --git a/src/main.rs b/src/main.rs
+index 1..2 100644
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1 +1 @@
+-old
++new
+diff --git a/old name.md b/new name.md
+similarity index 100%
+rename from old name.md
+rename to new name.md
+";
+        let files = split_diff_by_file(diff);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, "src/main.rs");
+        assert!(files[0].1.starts_with("diff --git a/src/main.rs"));
+        assert!(files[0].1.ends_with("+new\n"));
+        assert_eq!(files[1].0, "new name.md");
+        assert!(files[1].1.contains("rename to new name.md"));
+        assert!(split_diff_by_file("").is_empty());
+    }
+
+    /// Test that templates are found in GitHub's locations, in order
+    #[cfg_attr(not(doc), test)]
+    fn test_find_pr_template() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(find_pr_template(dir.path()), None);
+
+        fs::create_dir_all(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs/pull_request_template.md"), "## Why").unwrap();
+        assert_eq!(
+            find_pr_template(dir.path()),
+            Some(dir.path().join("docs/pull_request_template.md"))
+        );
+
+        fs::create_dir_all(dir.path().join(".github")).unwrap();
+        fs::write(
+            dir.path().join(".github/pull_request_template.md"),
+            "## What",
+        )
+        .unwrap();
+        assert_eq!(
+            find_pr_template(dir.path()),
+            Some(dir.path().join(".github/pull_request_template.md"))
+        );
+    }
+}
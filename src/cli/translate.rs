@@ -0,0 +1,887 @@
+use atty::Stream; // atty to determine if data is piped in or not
+use clap::{value_parser, Arg, ArgMatches, Command};
+use log::*; // logging
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::api::openai::{count_tokens, AssistantPurpose, ResponseFormat};
+use crate::api::schema::{json_instructions, send_with_schema};
+use crate::cli::cancel::cancellable;
+use crate::cli::input::{decode_text, read_limited};
+use crate::cli::output::fail;
+use crate::cli::parse::{build_request, token_limit};
+use crate::config::models::load_config;
+
+/// Most segments sent in one request
+const MAX_BATCH_SEGMENTS: usize = 40;
+
+/// # Define Translate Subcommand
+///
+/// `pipe-gpt translate README.fr.md --to en` translates the prose and keeps the structure
+pub fn translate_command() -> Command {
+    Command::new("translate")
+        .about("Translate the prose of a markdown, HTML or gettext .po document, keeping code, links, markup and placeholders as they are")
+        .arg(
+            Arg::new("file")
+                .value_name("FILE")
+                .help("Document to translate. Reads stdin if not given")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("to")
+                .long("to")
+                .value_name("language")
+                .help("Language to translate into, as a name or code e.g. en, de or Brazilian Portuguese")
+                .required(true),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("format")
+                .help("Document format. Defaults to the file extension, or markdown for stdin")
+                .required(false)
+                .value_parser(["markdown", "html", "po"]),
+        )
+}
+
+/// # Document Format
+///
+/// The formats `translate` can take apart and put back together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentFormat {
+    Markdown,
+    Html,
+    /// gettext catalogues, where each empty `msgstr` is filled in from its `msgid`
+    Po,
+}
+
+impl FromStr for DocumentFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" => Ok(DocumentFormat::Markdown),
+            "html" => Ok(DocumentFormat::Html),
+            "po" => Ok(DocumentFormat::Po),
+            other => Err(format!("expected markdown, html or po, got '{}'", other)),
+        }
+    }
+}
+
+impl DocumentFormat {
+    /// The format from a file extension, treating anything unknown as markdown
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html") | Some("htm") | Some("xhtml") => DocumentFormat::Html,
+            Some("po") | Some("pot") => DocumentFormat::Po,
+            _ => DocumentFormat::Markdown,
+        }
+    }
+}
+
+/// # Document Piece
+///
+/// A document is split into pieces that are copied as they are and pieces that are translated
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Keep(String),
+    Translate(String),
+    /// A gettext `msgstr`, with its key e.g. `msgstr[1] `, filled in from the translation of the
+    /// unescaped `msgid`
+    PoString {
+        prefix: String,
+        source: String,
+    },
+}
+
+impl Piece {
+    /// The text to translate, if this piece is translated
+    fn source(&self) -> Option<&str> {
+        match self {
+            Piece::Keep(_) => None,
+            Piece::Translate(text) => Some(text),
+            Piece::PoString { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Text is only worth sending if it has letters in it outside code, links and placeholders
+fn has_prose(text: &str) -> bool {
+    protected_pattern()
+        .replace_all(text, "")
+        .chars()
+        .any(char::is_alphabetic)
+}
+
+/// Adds the text as a piece to translate, keeping the whitespace around it as it is
+fn push_prose(pieces: &mut Vec<Piece>, text: &str) {
+    if !has_prose(text) {
+        pieces.push(Piece::Keep(text.to_string()));
+        return;
+    }
+    let trimmed = text.trim();
+    let start = text.len() - text.trim_start().len();
+    let end = start + trimmed.len();
+    if start > 0 {
+        pieces.push(Piece::Keep(text[..start].to_string()));
+    }
+    pieces.push(Piece::Translate(trimmed.to_string()));
+    if end < text.len() {
+        pieces.push(Piece::Keep(text[end..].to_string()));
+    }
+}
+
+fn markdown_prefix_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r"^\s*(?:>\s?)*(?:#{1,6}\s+|[-*+]\s+(?:\[[ xX]\]\s+)?|\d+[.)]\s+)?").unwrap()
+    })
+}
+
+/// # Split Markdown
+///
+/// Front matter, fenced and indented code, HTML lines and table rules are kept. Headings, list
+/// items and table cells are translated without their markup, and the lines of a paragraph are
+/// translated together.
+pub fn split_markdown(document: &str) -> Vec<Piece> {
+    let mut pieces: Vec<Piece> = vec![];
+    let mut lines = document.split_inclusive('\n').peekable();
+    let mut fence: Option<String> = None;
+    let mut previous_blank = true;
+    let mut in_list = false;
+    let mut paragraph_open = false;
+
+    if lines.peek().is_some_and(|line| line.trim_end() == "---") {
+        let mut front_matter = lines.next().unwrap_or_default().to_string();
+        for line in lines.by_ref() {
+            front_matter.push_str(line);
+            if line.trim_end() == "---" {
+                break;
+            }
+        }
+        pieces.push(Piece::Keep(front_matter));
+    }
+
+    for line in lines {
+        let content = line.trim_end_matches(['\n', '\r']);
+        let newline = &line[content.len()..];
+        let trimmed = content.trim_start();
+
+        if let Some(marker) = &fence {
+            if trimmed.starts_with(marker.as_str()) {
+                fence = None;
+            }
+            pieces.push(Piece::Keep(line.to_string()));
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(trimmed[..3].to_string());
+            pieces.push(Piece::Keep(line.to_string()));
+            in_list = false;
+            paragraph_open = false;
+            continue;
+        }
+        if trimmed.is_empty() {
+            pieces.push(Piece::Keep(line.to_string()));
+            previous_blank = true;
+            paragraph_open = false;
+            continue;
+        }
+
+        let indented_code = (content.starts_with("    ") || content.starts_with('\t'))
+            && previous_blank
+            && !in_list;
+        let html = trimmed.starts_with('<') && trimmed.ends_with('>');
+        let table_rule =
+            trimmed.starts_with('|') && trimmed.chars().all(|c| matches!(c, '|' | '-' | ':' | ' '));
+        previous_blank = false;
+        if indented_code || html || table_rule || !has_prose(content) {
+            pieces.push(Piece::Keep(line.to_string()));
+            paragraph_open = false;
+            continue;
+        }
+
+        if trimmed.starts_with('|') {
+            // each cell on its own, so the pipes stay where they are
+            for (index, cell) in content.split('|').enumerate() {
+                if index > 0 {
+                    pieces.push(Piece::Keep("|".to_string()));
+                }
+                push_prose(&mut pieces, cell);
+            }
+            pieces.push(Piece::Keep(newline.to_string()));
+            paragraph_open = false;
+            continue;
+        }
+
+        let prefix = markdown_prefix_pattern()
+            .find(content)
+            .map(|found| found.as_str())
+            .unwrap_or_default();
+        let text = &content[prefix.len()..];
+        let list_item =
+            prefix.trim_start().starts_with(['-', '*', '+']) || prefix.trim().ends_with(['.', ')']);
+        if list_item {
+            in_list = true;
+        } else if !content.starts_with([' ', '\t']) {
+            in_list = false;
+        }
+
+        // a line carrying on a plain paragraph is joined to it, as sentences run across lines
+        let plain = prefix.trim().is_empty();
+        if plain && paragraph_open {
+            if let [.., Piece::Translate(paragraph), Piece::Keep(end)] = pieces.as_mut_slice() {
+                if end.trim().is_empty() {
+                    paragraph.push_str(end);
+                    paragraph.push_str(prefix);
+                    paragraph.push_str(text);
+                    *end = newline.to_string();
+                    continue;
+                }
+            }
+        }
+        if !prefix.is_empty() {
+            pieces.push(Piece::Keep(prefix.to_string()));
+        }
+        push_prose(&mut pieces, text);
+        pieces.push(Piece::Keep(newline.to_string()));
+        paragraph_open = plain;
+    }
+    pieces
+}
+
+fn html_tag_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN
+        .get_or_init(|| Regex::new(r"(?s)<!--.*?-->|<![^>]*>|<\?.*?\?>|</?[A-Za-z][^>]*>").unwrap())
+}
+
+/// Elements whose contents are code or data rather than prose
+const HTML_RAW_ELEMENTS: [&str; 6] = ["script", "style", "pre", "code", "textarea", "svg"];
+
+/// # Split HTML
+///
+/// Tags, comments and the contents of `script`, `style`, `pre` and `code` elements are kept, and
+/// the text between tags is translated
+pub fn split_html(document: &str) -> Vec<Piece> {
+    let mut pieces: Vec<Piece> = vec![];
+    let mut position = 0;
+    let mut raw: Option<String> = None;
+    for tag in html_tag_pattern().find_iter(document) {
+        let text = &document[position..tag.start()];
+        if raw.is_some() {
+            pieces.push(Piece::Keep(text.to_string()));
+        } else {
+            push_prose(&mut pieces, text);
+        }
+        pieces.push(Piece::Keep(tag.as_str().to_string()));
+        position = tag.end();
+
+        let name = tag
+            .as_str()
+            .trim_start_matches('<')
+            .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .find(|part| !part.is_empty())
+            .unwrap_or_default()
+            .to_lowercase();
+        match &raw {
+            Some(open) if tag.as_str().starts_with("</") && &name == open => raw = None,
+            None if !tag.as_str().starts_with("</")
+                && !tag.as_str().ends_with("/>")
+                && HTML_RAW_ELEMENTS.contains(&name.as_str()) =>
+            {
+                raw = Some(name)
+            },
+            _ => {},
+        }
+    }
+    let rest = &document[position..];
+    if raw.is_some() {
+        pieces.push(Piece::Keep(rest.to_string()));
+    } else {
+        push_prose(&mut pieces, rest);
+    }
+    pieces
+}
+
+/// The contents of a quoted .po string, unescaped
+fn po_unquote(line: &str) -> String {
+    let quoted = line.trim();
+    let inner = quoted
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .unwrap_or(quoted);
+    let mut text = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some('r') => text.push('\r'),
+            Some(other) => text.push(other),
+            None => text.push('\\'),
+        }
+    }
+    text
+}
+
+/// Quotes text as a .po string
+fn po_quote(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r");
+    format!("\"{}\"", escaped)
+}
+
+/// One keyword of a .po entry, like `msgid`, with its value and the lines it spans
+struct PoField {
+    key: String,
+    value: String,
+    lines: Range<usize>,
+}
+
+/// Adds one .po entry, filling in its `msgstr`s if none are translated yet
+fn push_po_entry(pieces: &mut Vec<Piece>, lines: &[&str]) {
+    let mut fields: Vec<PoField> = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('"') {
+            if let Some(field) = fields.last_mut() {
+                field.value.push_str(&po_unquote(trimmed));
+                field.lines.end = index + 1;
+            }
+        } else if !trimmed.starts_with('#') {
+            if let Some((key, value)) = trimmed.split_once(' ') {
+                fields.push(PoField {
+                    key: key.to_string(),
+                    value: po_unquote(value),
+                    lines: index..index + 1,
+                });
+            }
+        }
+    }
+
+    let field = |key: &str| fields.iter().find(|field| field.key == key);
+    let msgid = field("msgid")
+        .map(|field| field.value.as_str())
+        .unwrap_or_default();
+    let plural = field("msgid_plural").map(|field| field.value.as_str());
+    let msgstrs: Vec<&PoField> = fields
+        .iter()
+        .filter(|field| field.key.starts_with("msgstr"))
+        .collect();
+    // the header, translated and obsolete entries are left as they are
+    let untranslated = !msgid.is_empty()
+        && !msgstrs.is_empty()
+        && msgstrs.iter().all(|field| field.value.is_empty());
+    if !untranslated {
+        pieces.push(Piece::Keep(lines.concat()));
+        return;
+    }
+
+    let mut index = 0;
+    while index < lines.len() {
+        match msgstrs.iter().find(|field| field.lines.start == index) {
+            Some(field) => {
+                let source = match (field.key.as_str(), plural) {
+                    ("msgstr" | "msgstr[0]", _) | (_, None) => msgid,
+                    (_, Some(plural)) => plural,
+                };
+                pieces.push(Piece::PoString {
+                    prefix: format!("{} ", field.key),
+                    source: source.to_string(),
+                });
+                index = field.lines.end;
+            },
+            None => {
+                pieces.push(Piece::Keep(lines[index].to_string()));
+                index += 1;
+            },
+        }
+    }
+}
+
+/// # Split PO
+///
+/// Each entry with an empty `msgstr` is translated from its `msgid`, or `msgid_plural` for the
+/// plural forms. Comments, context and entries that are already translated are kept.
+pub fn split_po(document: &str) -> Vec<Piece> {
+    let mut pieces: Vec<Piece> = vec![];
+    let mut entry: Vec<&str> = vec![];
+    for line in document.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            push_po_entry(&mut pieces, &entry);
+            entry.clear();
+            pieces.push(Piece::Keep(line.to_string()));
+        } else {
+            entry.push(line);
+        }
+    }
+    push_po_entry(&mut pieces, &entry);
+    pieces.retain(|piece| piece != &Piece::Keep(String::new()));
+    pieces
+}
+
+/// Takes the document apart in the given format
+pub fn split_document(document: &str, format: DocumentFormat) -> Vec<Piece> {
+    match format {
+        DocumentFormat::Markdown => split_markdown(document),
+        DocumentFormat::Html => split_html(document),
+        DocumentFormat::Po => split_po(document),
+    }
+}
+
+/// # Reassemble
+///
+/// Puts the document back together with a translation for each piece that was translated, in
+/// order
+pub fn reassemble(pieces: &[Piece], translations: &[String]) -> String {
+    let mut translations = translations.iter();
+    let mut document = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Keep(text) => document.push_str(text),
+            Piece::Translate(text) => {
+                document.push_str(translations.next().unwrap_or(text));
+            },
+            Piece::PoString { prefix, source } => {
+                let translation = translations.next().unwrap_or(source);
+                document.push_str(prefix);
+                document.push_str(&po_quote(translation));
+                document.push('\n');
+            },
+        }
+    }
+    document
+}
+
+fn protected_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(concat!(
+            // inline code
+            r"`[^`\n]+`",
+            // link and image targets, the text before them is still translated
+            r"|\]\([^)\s]*(?:\s+\x22[^\x22]*\x22)?\)",
+            // autolinks, URLs and email addresses
+            r"|<[a-z]+://[^>\s]+>|\b[a-z]+://[^\s<>()\x22']*[^\s<>()\x22'.,;:!?]",
+            // inline HTML tags and entities
+            r"|</?[A-Za-z][^<>]*>|&(?:[A-Za-z]+|#\d+|#x[0-9A-Fa-f]+);",
+            // {{name}}, {name}, {0}, ${name}
+            r"|\{\{[^{}]*\}\}|\$?\{[A-Za-z0-9_.:-]*\}",
+            // printf style: %s, %d, %1$s, %(name)s, %.2f
+            r"|%(?:\d+\$)?(?:\([A-Za-z0-9_]+\))?[-+0#]*\d*(?:\.\d+)?[sdifuxXeEgGc@]",
+        ))
+        .unwrap()
+    })
+}
+
+/// The mask standing in for the `index`th protected span of a segment
+fn mask(index: usize) -> String {
+    format!("⟦{}⟧", index)
+}
+
+/// # Protect Segment
+///
+/// Replaces inline code, link targets, URLs, tags, entities and placeholders with numbered masks
+/// like `⟦0⟧`, so the model can move them within the sentence but can't change them. Returns the
+/// masked text and what each mask stands for.
+pub fn protect(text: &str) -> (String, Vec<String>) {
+    let mut spans: Vec<String> = vec![];
+    let masked = protected_pattern().replace_all(text, |captures: &regex::Captures| {
+        let span = &captures[0];
+        // the `]` closes the link text, which is translated
+        let (before, span) = match span.strip_prefix(']') {
+            Some(target) => ("]", target),
+            None => ("", span),
+        };
+        spans.push(span.to_string());
+        format!("{}{}", before, mask(spans.len() - 1))
+    });
+    (masked.into_owned(), spans)
+}
+
+fn mask_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"⟦(\d+)⟧").unwrap())
+}
+
+/// # Restore Segment
+///
+/// Puts the protected spans back in a translation, checking each mask is there exactly once and
+/// no others were made up, so no placeholder is lost, repeated or invented
+pub fn restore(translation: &str, spans: &[String]) -> Result<String, String> {
+    let mut seen: BTreeMap<usize, usize> = BTreeMap::new();
+    for captures in mask_pattern().captures_iter(translation) {
+        let index: usize = captures[1].parse().unwrap_or(usize::MAX);
+        *seen.entry(index).or_default() += 1;
+    }
+    let missing: Vec<String> = (0..spans.len())
+        .filter(|index| !seen.contains_key(index))
+        .map(mask)
+        .collect();
+    let repeated: Vec<String> = seen
+        .iter()
+        .filter(|(index, count)| **index >= spans.len() || **count > 1)
+        .map(|(index, _)| mask(*index))
+        .collect();
+    if !missing.is_empty() || !repeated.is_empty() {
+        return Err(format!(
+            "placeholders changed, missing: [{}], extra or repeated: [{}]",
+            missing.join(", "),
+            repeated.join(", ")
+        ));
+    }
+    Ok(mask_pattern()
+        .replace_all(translation, |captures: &regex::Captures| {
+            spans[captures[1].parse::<usize>().unwrap_or_default()].clone()
+        })
+        .into_owned())
+}
+
+/// # Batches
+///
+/// Groups segments in order so each batch is at most `budget` tokens and
+/// [MAX_BATCH_SEGMENTS] segments. A segment over the budget on its own gets a batch to itself.
+pub fn batches(segments: &[String], budget: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    let mut start = 0;
+    let mut tokens = 0;
+    for (index, segment) in segments.iter().enumerate() {
+        let segment_tokens = count_tokens(segment);
+        let full = index - start >= MAX_BATCH_SEGMENTS || tokens + segment_tokens > budget;
+        if index > start && full {
+            ranges.push(start..index);
+            start = index;
+            tokens = 0;
+        }
+        tokens += segment_tokens;
+    }
+    if start < segments.len() {
+        ranges.push(start..segments.len());
+    }
+    ranges
+}
+
+/// The shape of the reply, one translation per segment
+fn translations_schema(count: usize) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "translations": {
+                "type": "array",
+                "items": {"type": "string"},
+                "minItems": count,
+                "maxItems": count
+            }
+        },
+        "required": ["translations"],
+        "additionalProperties": false
+    })
+}
+
+/// Asks for the translations of one batch of masked segments, in order
+async fn translate_batch(
+    matches: &ArgMatches,
+    language: &str,
+    prompt: &str,
+    segments: &[String],
+) -> Result<Vec<String>, String> {
+    let schema = translations_schema(segments.len());
+    let request = format!(
+        "Translate each of these {} segments into {}.{}\n{}",
+        segments.len(),
+        language,
+        if prompt.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", prompt)
+        },
+        serde_json::to_string_pretty(segments).map_err(|e| e.to_string())?
+    );
+    let mut chat_request = build_request(
+        &request,
+        "",
+        &[],
+        matches,
+        &AssistantPurpose::Translator,
+        Some(json_instructions(Some(&schema))),
+        None,
+    );
+    chat_request.response_format = Some(ResponseFormat::JsonObject);
+    let reply = send_with_schema(chat_request, &schema).await?;
+    Ok(reply["translations"]
+        .as_array()
+        .map(|translations| {
+            translations
+                .iter()
+                .map(|translation| translation.as_str().unwrap_or_default().to_string())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// # Run Translate Subcommand
+///
+/// - Splits the document into structure to keep and prose to translate
+/// - Masks inline code, links and placeholders in the prose
+/// - Sends the prose in batches within the token limit
+/// - Checks every mask came back once, asking again one segment at a time where one didn't, and
+///   keeping the original text if it still doesn't
+/// - Prints the reassembled document
+pub async fn translate(matches: &ArgMatches) {
+    let language = matches.get_one::<String>("to").expect("to is required");
+    let empty_string = String::from("");
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .unwrap_or(&empty_string);
+    let path = matches.get_one::<PathBuf>("file");
+    let format = match (matches.get_one::<String>("format"), path) {
+        (Some(format), _) => format.parse().expect("restricted by clap"),
+        (None, Some(path)) => DocumentFormat::from_path(path),
+        (None, None) => DocumentFormat::Markdown,
+    };
+
+    let document = match path {
+        Some(path) => fs::read_to_string(path)
+            .unwrap_or_else(|e| fail(format!("could not read {:?}: {}", path, e))),
+        None => {
+            if atty::is(Stream::Stdin) {
+                fail("give a file to translate or pipe one in".to_string());
+            }
+            let bytes = read_limited(io::stdin(), load_config().max_input_bytes)
+                .unwrap_or_else(|e| fail(e));
+            decode_text(&bytes).0
+        },
+    };
+
+    let pieces = split_document(&document, format);
+    let sources: Vec<&str> = pieces.iter().filter_map(Piece::source).collect();
+    let (masked, spans): (Vec<String>, Vec<Vec<String>>) =
+        sources.iter().map(|source| protect(source)).unzip();
+    debug!("{} segments to translate as {:?}", masked.len(), format);
+
+    // the reply is about as long as the request, so the batch gets a third of the limit
+    let budget = token_limit(matches) / 3;
+    let ranges = batches(&masked, budget);
+    let mut translations: Vec<String> = sources.iter().map(|source| source.to_string()).collect();
+    cancellable(async {
+        let mut retry = vec![];
+        for (number, range) in ranges.iter().enumerate() {
+            eprintln!(
+                "[{}/{}] translating {} segments",
+                number + 1,
+                ranges.len(),
+                range.len()
+            );
+            let replies = translate_batch(matches, language, prompt, &masked[range.clone()])
+                .await
+                .unwrap_or_else(|e| fail(e));
+            for (index, reply) in range.clone().zip(replies) {
+                match restore(&reply, &spans[index]) {
+                    Ok(translation) => translations[index] = translation,
+                    Err(e) => {
+                        debug!("segment {}: {}", index, e);
+                        retry.push(index);
+                    },
+                }
+            }
+        }
+        for index in retry {
+            let reply = translate_batch(matches, language, prompt, &masked[index..index + 1])
+                .await
+                .unwrap_or_else(|e| fail(e));
+            match restore(
+                reply.first().map(String::as_str).unwrap_or_default(),
+                &spans[index],
+            ) {
+                Ok(translation) => translations[index] = translation,
+                Err(e) => eprintln!(
+                    "Warning: kept segment {} untranslated as its {}: {}",
+                    index + 1,
+                    e,
+                    sources[index]
+                ),
+            }
+        }
+    })
+    .await;
+
+    print!("{}", reassemble(&pieces, &translations));
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// The sources of the pieces that are translated
+    fn sources(pieces: &[Piece]) -> Vec<&str> {
+        pieces.iter().filter_map(Piece::source).collect()
+    }
+
+    /// Test that code, front matter and markup are kept and prose is split out
+    #[cfg_attr(not(doc), test)]
+    fn test_split_markdown() {
+        let document = "\
+---
+title: Démarrage
+---
+# Démarrage rapide
+
+Installez le paquet puis
+lancez la commande.
+
+- Première étape
+- [x] Deuxième étape
+
+```sh
+cargo install pipe-gpt # ne pas traduire
+```
+
+    let code = indenté;
+
+| Option | Description |
+|--------|-------------|
+| `-p` | Le texte |
+";
+        let pieces = split_markdown(document);
+        assert_eq!(
+            sources(&pieces),
+            vec![
+                "Démarrage rapide",
+                "Installez le paquet puis\nlancez la commande.",
+                "Première étape",
+                "Deuxième étape",
+                "Option",
+                "Description",
+                "Le texte",
+            ]
+        );
+        assert_eq!(reassemble(&pieces, &[]), document);
+    }
+
+    /// Test that tags and the contents of code and script elements are kept
+    #[cfg_attr(not(doc), test)]
+    fn test_split_html() {
+        let document = "<!DOCTYPE html>\n<html><head><title>Bienvenue</title><script>var s = \"ne pas traduire\";</script></head>\n<body><p>Bonjour <b>le monde</b>&nbsp;!</p><pre>code brut</pre></body></html>\n";
+        let pieces = split_html(document);
+        // "&nbsp;!" has nothing to translate once the entity is left out
+        assert_eq!(sources(&pieces), vec!["Bienvenue", "Bonjour", "le monde"]);
+        assert_eq!(reassemble(&pieces, &[]), document);
+    }
+
+    /// Test that only untranslated entries are filled in, with plurals and escapes
+    #[cfg_attr(not(doc), test)]
+    fn test_split_po() {
+        let document = r#"msgid ""
+msgstr ""
+"Language: en\n"
+
+#: src/main.rs:10
+msgid "Fichier \"%s\" introuvable"
+msgstr ""
+
+msgid "Déjà traduit"
+msgstr "Already translated"
+
+msgid "%d fichier"
+msgid_plural "%d fichiers"
+msgstr[0] ""
+msgstr[1] ""
+"#;
+        let pieces = split_po(document);
+        assert_eq!(
+            sources(&pieces),
+            vec!["Fichier \"%s\" introuvable", "%d fichier", "%d fichiers"]
+        );
+        let translations = vec![
+            "File \"%s\" not found".to_string(),
+            "%d file".to_string(),
+            "%d files".to_string(),
+        ];
+        assert_eq!(
+            reassemble(&pieces, &translations),
+            r#"msgid ""
+msgstr ""
+"Language: en\n"
+
+#: src/main.rs:10
+msgid "Fichier \"%s\" introuvable"
+msgstr "File \"%s\" not found"
+
+msgid "Déjà traduit"
+msgstr "Already translated"
+
+msgid "%d fichier"
+msgid_plural "%d fichiers"
+msgstr[0] "%d file"
+msgstr[1] "%d files"
+"#
+        );
+    }
+
+    /// Test that code, link targets and placeholders are masked and the masks are checked
+    #[cfg_attr(not(doc), test)]
+    fn test_protect_and_restore() {
+        let (masked, spans) = protect(
+            "Lancez `cargo test`, lisez [le guide](https://example.com/guide \"Guide\") ou {count} fichiers, %(name)s et ${HOME}.",
+        );
+        assert_eq!(
+            masked,
+            "Lancez ⟦0⟧, lisez [le guide]⟦1⟧ ou ⟦2⟧ fichiers, ⟦3⟧ et ⟦4⟧."
+        );
+        assert_eq!(
+            spans,
+            vec![
+                "`cargo test`",
+                "(https://example.com/guide \"Guide\")",
+                "{count}",
+                "%(name)s",
+                "${HOME}"
+            ]
+        );
+        assert_eq!(
+            restore("Run ⟦0⟧, read [the guide]⟦1⟧ or ⟦2⟧ files, ⟦3⟧ and ⟦4⟧.", &spans).unwrap(),
+            "Run `cargo test`, read [the guide](https://example.com/guide \"Guide\") or {count} files, %(name)s and ${HOME}."
+        );
+
+        let missing = restore("Run ⟦0⟧ or ⟦2⟧ files, ⟦3⟧ and ⟦4⟧.", &spans).unwrap_err();
+        assert!(missing.contains("missing: [⟦1⟧]"));
+        let repeated = restore("⟦0⟧ ⟦0⟧ ⟦1⟧ ⟦2⟧ ⟦3⟧ ⟦4⟧ ⟦5⟧", &spans).unwrap_err();
+        assert!(repeated.contains("extra or repeated: [⟦0⟧, ⟦5⟧]"));
+
+        // "100% done" isn't a placeholder
+        assert_eq!(protect("100% done").1, Vec::<String>::new());
+    }
+
+    /// Test that batches keep to the token budget and segment count
+    #[cfg_attr(not(doc), test)]
+    fn test_batches() {
+        let segments: Vec<String> = (0..100).map(|i| format!("segment {}", i)).collect();
+        let ranges = batches(&segments, 1_000_000);
+        assert_eq!(ranges, vec![0..40, 40..80, 80..100]);
+
+        let long = vec![
+            "word ".repeat(500),
+            "short".to_string(),
+            "short".to_string(),
+        ];
+        assert_eq!(batches(&long, 100), vec![0..1, 1..3]);
+        assert!(batches(&[], 100).is_empty());
+    }
+}
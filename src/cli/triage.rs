@@ -0,0 +1,663 @@
+use atty::Stream; // atty to determine if data is piped in or not
+use clap::{value_parser, Arg, ArgMatches, Command};
+use log::*; // logging
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::api::openai::{send_to_gpt4, AssistantPurpose};
+use crate::cli::cancel::cancellable;
+use crate::cli::input::{decode_text, read_limited};
+use crate::cli::output::{fail, markdown_plaintext_or_error};
+use crate::cli::parse::build_request;
+use crate::cli::truncate::Truncate;
+use crate::config::models::load_config;
+
+/// Most source locations shown for each failure, nearest the failure first
+const MAX_LOCATIONS: usize = 3;
+
+/// Most lines of a failure's own output kept in the bundle
+const MAX_EXCERPT_LINES: usize = 30;
+
+/// Largest source file read for an excerpt
+const MAX_SOURCE_BYTES: u64 = 1024 * 1024;
+
+/// Path fragments of frames in dependencies or the standard library rather than the project
+const LIBRARY_PATHS: [&str; 6] = [
+    "/rustc/",
+    ".cargo/registry",
+    "node_modules",
+    "site-packages",
+    "/lib/python",
+    "node:internal",
+];
+
+/// # Define Triage Subcommand
+///
+/// `cargo test 2>&1 | pipe-gpt triage` asks for the likely causes of the failing tests
+pub fn triage_command() -> Command {
+    Command::new("triage")
+        .about("Find the failing tests in piped cargo test, pytest or jest output and ask for root-cause hypotheses")
+        .arg(
+            Arg::new("context")
+                .long("context")
+                .value_name("lines")
+                .help("Lines of source shown either side of each line a failure points to")
+                .required(false)
+                .default_value("3")
+                .value_parser(value_parser!(usize)),
+        )
+}
+
+/// # Test Runner
+///
+/// The test runners whose output `triage` understands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestRunner {
+    Cargo,
+    Pytest,
+    Jest,
+}
+
+impl fmt::Display for TestRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestRunner::Cargo => write!(f, "cargo test"),
+            TestRunner::Pytest => write!(f, "pytest"),
+            TestRunner::Jest => write!(f, "jest"),
+        }
+    }
+}
+
+/// A line of a source file that a failure points to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub path: String,
+    pub line: usize,
+}
+
+/// # Test Failure
+///
+/// One failing test with what the runner printed about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub name: String,
+    /// The panic, assertion or error message
+    pub message: String,
+    /// Everything the runner printed for this test
+    pub output: Vec<String>,
+    /// Project source locations in the trace, in the order they were printed
+    pub locations: Vec<Location>,
+}
+
+fn ansi_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap())
+}
+
+fn code_frame_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^\s*>?\s*\d+ \|").unwrap())
+}
+
+fn pytest_header_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^_{3,} (.+?) _{3,}$").unwrap())
+}
+
+fn location_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(
+            r#"File "([^"]+)", line (\d+)|((?:[A-Za-z]:)?[\w./\\@-]*\.(?:rs|py|js|jsx|ts|tsx|mjs|cjs)):(\d+)"#,
+        )
+        .unwrap()
+    })
+}
+
+/// # Detect Test Runner
+///
+/// Recognises the runner from lines only it prints
+pub fn detect_runner(output: &str) -> Option<TestRunner> {
+    if output.contains("test result: ")
+        || output.contains("---- ") && output.contains(" stdout ----")
+    {
+        Some(TestRunner::Cargo)
+    } else if output.contains("short test summary info") || output.contains("= FAILURES =") {
+        Some(TestRunner::Pytest)
+    } else if output.contains("Test Suites:") || output.contains("● ") {
+        Some(TestRunner::Jest)
+    } else {
+        None
+    }
+}
+
+/// # Find Locations
+///
+/// Source locations mentioned in the lines, skipping library frames and repeats
+pub fn find_locations(lines: &[String]) -> Vec<Location> {
+    let mut locations: Vec<Location> = vec![];
+    for line in lines {
+        for captures in location_pattern().captures_iter(line) {
+            let (path, number) = match (captures.get(1), captures.get(3)) {
+                (Some(path), _) => (path.as_str(), &captures[2]),
+                (None, Some(path)) => (path.as_str(), &captures[4]),
+                _ => continue,
+            };
+            let path = path.trim_start_matches("./");
+            if LIBRARY_PATHS.iter().any(|library| path.contains(library)) {
+                continue;
+            }
+            let location = Location {
+                path: path.to_string(),
+                line: number.parse().unwrap_or(0),
+            };
+            if location.line > 0 && !locations.contains(&location) {
+                locations.push(location);
+            }
+        }
+    }
+    locations
+}
+
+/// Splits the output into named blocks, one started by each line `header` matches
+fn split_blocks(
+    lines: &[String],
+    header: impl Fn(&str) -> Option<String>,
+    is_end: impl Fn(&str) -> bool,
+) -> Vec<(String, Vec<String>)> {
+    let mut blocks: Vec<(String, Vec<String>)> = vec![];
+    let mut open = false;
+    for line in lines {
+        if let Some(name) = header(line) {
+            blocks.push((name, vec![]));
+            open = true;
+        } else if is_end(line) {
+            open = false;
+        } else if open {
+            if let Some((_, block)) = blocks.last_mut() {
+                block.push(line.clone());
+            }
+        }
+    }
+    blocks
+}
+
+/// The panic message following `panicked at`, in both the old single line and newer formats
+fn cargo_message(block: &[String]) -> String {
+    for (index, line) in block.iter().enumerate() {
+        let Some((_, rest)) = line.split_once("panicked at ") else {
+            continue;
+        };
+        // before Rust 1.73: panicked at 'message', src/lib.rs:1:5
+        if let Some(quoted) = rest.strip_prefix('\'') {
+            if let Some((message, _)) = quoted.rsplit_once("', ") {
+                return message.to_string();
+            }
+        }
+        return block[index + 1..]
+            .iter()
+            .take_while(|line| {
+                !line.trim().is_empty()
+                    && !line.starts_with("note: ")
+                    && !line.starts_with("stack backtrace:")
+            })
+            .cloned()
+            .collect::<Vec<String>>()
+            .join("\n");
+    }
+    String::new()
+}
+
+/// Pytest's `E` lines, which hold the exception and assertion rewrite
+fn pytest_message(block: &[String]) -> String {
+    block
+        .iter()
+        .filter_map(|line| line.strip_prefix("E "))
+        .map(str::trim)
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Jest's message is everything before the code frame or stack
+fn jest_message(block: &[String]) -> String {
+    block
+        .iter()
+        .map(|line| line.trim())
+        .skip_while(|line| line.is_empty())
+        .take_while(|line| !code_frame_pattern().is_match(line) && !line.starts_with("at "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// # Parse Failures
+///
+/// The failing tests in the runner's output, with their messages and the project source
+/// locations in their traces
+pub fn parse_failures(output: &str, runner: TestRunner) -> Vec<Failure> {
+    let lines: Vec<String> = ansi_pattern()
+        .replace_all(output, "")
+        .lines()
+        .map(|line| line.trim_end().to_string())
+        .collect();
+
+    let blocks = match runner {
+        TestRunner::Cargo => split_blocks(
+            &lines,
+            |line| {
+                line.strip_prefix("---- ")
+                    .and_then(|rest| rest.strip_suffix(" stdout ----"))
+                    .map(str::to_string)
+            },
+            |line| line == "failures:" || line.starts_with("test result: "),
+        ),
+        TestRunner::Pytest => split_blocks(
+            &lines,
+            |line| {
+                pytest_header_pattern()
+                    .captures(line)
+                    .map(|captures| captures[1].to_string())
+            },
+            |line| line.starts_with("==="),
+        ),
+        TestRunner::Jest => split_blocks(
+            &lines,
+            |line| {
+                line.trim_start()
+                    .strip_prefix("● ")
+                    .filter(|name| !name.starts_with("Test suite failed"))
+                    .map(str::to_string)
+            },
+            |line| {
+                line.starts_with("Test Suites:")
+                    || line.starts_with("FAIL ")
+                    || line.starts_with("PASS ")
+            },
+        ),
+    };
+
+    blocks
+        .into_iter()
+        .map(|(name, block)| {
+            let message = match runner {
+                TestRunner::Cargo => cargo_message(&block),
+                TestRunner::Pytest => pytest_message(&block),
+                TestRunner::Jest => jest_message(&block),
+            };
+            let locations = find_locations(&block);
+            // pytest prints the innermost frame last, the others first
+            let locations = match runner {
+                TestRunner::Pytest => locations.into_iter().rev().collect(),
+                _ => locations,
+            };
+            Failure {
+                name,
+                message,
+                output: block,
+                locations,
+            }
+        })
+        .collect()
+}
+
+/// Name of the fence language for a source file
+fn fence_language(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("rs") => "rust",
+        Some("py") => "python",
+        Some("ts") | Some("tsx") => "typescript",
+        Some("js") | Some("jsx") | Some("mjs") | Some("cjs") => "javascript",
+        Some("c") | Some("h") => "c",
+        Some("cc") | Some("cpp") | Some("hpp") => "cpp",
+        _ => "",
+    }
+}
+
+/// # Source Excerpt
+///
+/// The lines either side of `line` in the file under `root`, numbered and with the line itself
+/// marked, or `None` if the file can't be read or is shorter than that. Only files under `root`
+/// are read, as the paths come from piped output, and very large files are skipped.
+pub fn source_excerpt(root: &Path, location: &Location, context: usize) -> Option<String> {
+    let path = root.join(&location.path).canonicalize().ok()?;
+    if !path.starts_with(root.canonicalize().ok()?) {
+        debug!("not reading {:?} as it is outside {:?}", path, root);
+        return None;
+    }
+    if fs::metadata(&path).ok()?.len() > MAX_SOURCE_BYTES {
+        debug!("not reading {:?} as it is too large", path);
+        return None;
+    }
+    let source = fs::read_to_string(&path).ok()?;
+    let lines: Vec<&str> = source.lines().collect();
+    if location.line == 0 || location.line > lines.len() {
+        return None;
+    }
+    let first = location.line.saturating_sub(context).max(1);
+    let last = (location.line + context).min(lines.len());
+    let width = last.to_string().len();
+    let excerpt: Vec<String> = (first..=last)
+        .map(|number| {
+            let marker = if number == location.line { ">" } else { " " };
+            format!("{} {:>width$} | {}", marker, number, lines[number - 1])
+        })
+        .collect();
+    Some(format!(
+        "{}:{}\n```{}\n{}\n```",
+        location.path,
+        location.line,
+        fence_language(&location.path),
+        excerpt.join("\n")
+    ))
+}
+
+/// # Triage Bundle
+///
+/// Formats the failures compactly for the model: the message, the trace locations and the
+/// source around the nearest few of them
+pub fn triage_bundle(
+    runner: TestRunner,
+    failures: &[Failure],
+    summary: Option<&str>,
+    root: &Path,
+    context: usize,
+) -> String {
+    let mut bundle = format!(
+        "Test runner: {}\nFailing tests: {}\n",
+        runner,
+        failures.len()
+    );
+    if let Some(summary) = summary {
+        bundle.push_str(&format!("Summary: {}\n", summary));
+    }
+    for (index, failure) in failures.iter().enumerate() {
+        bundle.push_str(&format!("\n## {}. {}\n", index + 1, failure.name));
+        if !failure.message.is_empty() {
+            bundle.push_str(&format!("Message:\n{}\n", failure.message));
+        }
+        if failure.locations.is_empty() || failure.message.is_empty() {
+            let output: Vec<&str> = failure
+                .output
+                .iter()
+                .map(String::as_str)
+                .filter(|line| !line.trim().is_empty())
+                .take(MAX_EXCERPT_LINES)
+                .collect();
+            bundle.push_str(&format!("Output:\n```\n{}\n```\n", output.join("\n")));
+        }
+        if !failure.locations.is_empty() {
+            let trace: Vec<String> = failure
+                .locations
+                .iter()
+                .map(|location| format!("{}:{}", location.path, location.line))
+                .collect();
+            bundle.push_str(&format!("Trace: {}\n", trace.join(" <- ")));
+        }
+        for location in failure.locations.iter().take(MAX_LOCATIONS) {
+            if let Some(excerpt) = source_excerpt(root, location, context) {
+                bundle.push_str(&format!("{}\n", excerpt));
+            }
+        }
+    }
+    bundle
+}
+
+/// The runner's closing totals line, if it printed one
+fn summary_line(output: &str, runner: TestRunner) -> Option<String> {
+    let output = ansi_pattern().replace_all(output, "");
+    let line = match runner {
+        TestRunner::Cargo => output
+            .lines()
+            .rfind(|line| line.starts_with("test result: FAILED")),
+        TestRunner::Pytest => output
+            .lines()
+            .rfind(|line| line.starts_with('=') && line.contains(" failed")),
+        TestRunner::Jest => output.lines().find(|line| line.starts_with("Tests:")),
+    };
+    line.map(|line| {
+        line.trim_matches(|c: char| c == '=' || c.is_whitespace())
+            .to_string()
+    })
+}
+
+/// # Run Triage Subcommand
+///
+/// - Reads the test output piped in and recognises the runner
+/// - Extracts each failing test's name, message and trace
+/// - Reads the source lines the traces point to from the working directory
+/// - Sends the bundle and prints the hypotheses
+pub async fn triage(matches: &ArgMatches) {
+    let context = *matches
+        .get_one::<usize>("context")
+        .expect("context has a default");
+    let empty_string = String::from("");
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .unwrap_or(&empty_string);
+
+    if atty::is(Stream::Stdin) {
+        fail("pipe the test output in e.g. cargo test 2>&1 | pipe-gpt triage".to_string());
+    }
+    let bytes =
+        read_limited(io::stdin(), load_config().max_input_bytes).unwrap_or_else(|e| fail(e));
+    let (output, _) = decode_text(&bytes);
+
+    let runner = detect_runner(&output).unwrap_or_else(|| {
+        fail("the input doesn't look like cargo test, pytest or jest output".to_string())
+    });
+    let failures = parse_failures(&output, runner);
+    if failures.is_empty() {
+        eprintln!("No failing tests found in the {} output.", runner);
+        return;
+    }
+    eprintln!("Triaging {} failing tests from {}", failures.len(), runner);
+
+    let root = std::env::current_dir().unwrap_or_default();
+    let summary = summary_line(&output, runner);
+    let bundle = triage_bundle(runner, &failures, summary.as_deref(), &root, context);
+    debug!("triage bundle: {}", bundle);
+
+    let chat_request = build_request(
+        prompt,
+        &bundle,
+        &[],
+        matches,
+        &AssistantPurpose::TestTriage,
+        None,
+        Some(Truncate::Smart),
+    );
+    let response =
+        cancellable(async { send_to_gpt4(&chat_request).await.map_err(|e| e.to_string()) }).await;
+    markdown_plaintext_or_error(response, false);
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    const CARGO_OUTPUT: &str = "\
+running 3 tests
+test tests::adds ... ok
+test tests::divides ... FAILED
+test tests::parses ... FAILED
+
+failures:
+
+---- tests::divides stdout ----
+
+thread 'tests::divides' panicked at src/maths.rs:4:5:
+attempt to divide by zero
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+---- tests::parses stdout ----
+thread 'tests::parses' panicked at 'assertion failed: `(left == right)`', src/lib.rs:20:9
+
+failures:
+    tests::divides
+    tests::parses
+
+test result: FAILED. 1 passed; 2 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s
+";
+
+    const PYTEST_OUTPUT: &str = "\
+============================= test session starts ==============================
+collected 2 items
+
+tests/test_prices.py F.                                                  [100%]
+
+=================================== FAILURES ===================================
+________________________________ test_discount _________________________________
+
+    def test_discount():
+>       assert apply_discount(100, 0.1) == 90
+E       assert 90.00000000000001 == 90
+E        +  where 90.00000000000001 = apply_discount(100, 0.1)
+
+tests/test_prices.py:5: AssertionError
+=========================== short test summary info ============================
+FAILED tests/test_prices.py::test_discount - assert 90.00000000000001 == 90
+========================= 1 failed, 1 passed in 0.02s ==========================
+";
+
+    const JEST_OUTPUT: &str = "\
+FAIL src/sum.test.js
+  \u{1b}[1m\u{1b}[31m● sum › adds negative numbers\u{1b}[39m\u{1b}[22m
+
+    expect(received).toBe(expected) // Object.is equality
+
+    Expected: -3
+    Received: 3
+
+      4 | test('adds negative numbers', () => {
+    > 5 |   expect(sum(-1, -2)).toBe(-3);
+        |                       ^
+
+      at Object.<anonymous> (src/sum.test.js:5:23)
+      at Promise.then.completed (node_modules/jest-circus/build/utils.js:298:28)
+
+Test Suites: 1 failed, 1 total
+Tests:       1 failed, 2 passed, 3 total
+";
+
+    /// Test that each runner is recognised from its own output only
+    #[cfg_attr(not(doc), test)]
+    fn test_detect_runner() {
+        assert_eq!(detect_runner(CARGO_OUTPUT), Some(TestRunner::Cargo));
+        assert_eq!(detect_runner(PYTEST_OUTPUT), Some(TestRunner::Pytest));
+        assert_eq!(detect_runner(JEST_OUTPUT), Some(TestRunner::Jest));
+        assert_eq!(detect_runner("make: *** [all] Error 1"), None);
+    }
+
+    /// Test that failing tests are found with their messages and project locations
+    #[cfg_attr(not(doc), test)]
+    fn test_parse_failures() {
+        let cargo = parse_failures(CARGO_OUTPUT, TestRunner::Cargo);
+        assert_eq!(cargo.len(), 2);
+        assert_eq!(cargo[0].name, "tests::divides");
+        assert_eq!(cargo[0].message, "attempt to divide by zero");
+        assert_eq!(
+            cargo[0].locations,
+            vec![Location {
+                path: "src/maths.rs".to_string(),
+                line: 4
+            }]
+        );
+        assert_eq!(cargo[1].name, "tests::parses");
+        assert_eq!(cargo[1].message, "assertion failed: `(left == right)`");
+        assert_eq!(cargo[1].locations[0].path, "src/lib.rs");
+
+        let pytest = parse_failures(PYTEST_OUTPUT, TestRunner::Pytest);
+        assert_eq!(pytest.len(), 1);
+        assert_eq!(pytest[0].name, "test_discount");
+        assert_eq!(
+            pytest[0].message,
+            "assert 90.00000000000001 == 90\n+  where 90.00000000000001 = apply_discount(100, 0.1)"
+        );
+        assert_eq!(pytest[0].locations[0].path, "tests/test_prices.py");
+        assert_eq!(pytest[0].locations[0].line, 5);
+
+        let jest = parse_failures(JEST_OUTPUT, TestRunner::Jest);
+        assert_eq!(jest.len(), 1);
+        assert_eq!(jest[0].name, "sum › adds negative numbers");
+        assert_eq!(
+            jest[0].message,
+            "expect(received).toBe(expected) // Object.is equality\nExpected: -3\nReceived: 3"
+        );
+        // the node_modules frame is dropped
+        assert_eq!(
+            jest[0].locations,
+            vec![Location {
+                path: "src/sum.test.js".to_string(),
+                line: 5
+            }]
+        );
+
+        assert_eq!(
+            summary_line(CARGO_OUTPUT, TestRunner::Cargo).as_deref(),
+            Some("test result: FAILED. 1 passed; 2 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s")
+        );
+        assert_eq!(
+            summary_line(PYTEST_OUTPUT, TestRunner::Pytest).as_deref(),
+            Some("1 failed, 1 passed in 0.02s")
+        );
+    }
+
+    /// Test that the referenced source lines are pulled from disk into the bundle
+    #[cfg_attr(not(doc), test)]
+    fn test_triage_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(
+            dir.path().join("src/maths.rs"),
+            "pub fn divide(a: i32, b: i32) -> i32 {\n    // no check for zero\n    let q = a / b;\n    q\n}\n",
+        )
+        .unwrap();
+
+        let failures = parse_failures(CARGO_OUTPUT, TestRunner::Cargo);
+        let bundle = triage_bundle(TestRunner::Cargo, &failures, None, dir.path(), 1);
+        assert!(bundle.starts_with("Test runner: cargo test\nFailing tests: 2\n"));
+        assert!(bundle.contains("## 1. tests::divides\nMessage:\nattempt to divide by zero\n"));
+        assert!(bundle.contains(
+            "src/maths.rs:4\n```rust\n  3 |     let q = a / b;\n> 4 |     q\n  5 | }\n```"
+        ));
+        // src/lib.rs isn't on disk, so only its location is given
+        assert!(bundle.contains("Trace: src/lib.rs:20\n"));
+        assert!(!bundle.contains("src/lib.rs:20\n```"));
+    }
+
+    /// Test that only files under the root are read, whichever way the path is written
+    #[cfg_attr(not(doc), test)]
+    fn test_source_excerpt_stays_in_root() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("project")).unwrap();
+        fs::write(dir.path().join("project/main.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("secret.txt"), "password\n").unwrap();
+        let root = dir.path().join("project");
+        let location = |path: String| Location { path, line: 1 };
+
+        assert!(source_excerpt(&root, &location("main.py".to_string()), 0).is_some());
+        let absolute = root.join("main.py").display().to_string();
+        assert!(source_excerpt(&root, &location(absolute), 0).is_some());
+
+        assert_eq!(
+            source_excerpt(&root, &location("../secret.txt".to_string()), 0),
+            None
+        );
+        let outside = dir.path().join("secret.txt").display().to_string();
+        assert_eq!(source_excerpt(&root, &location(outside), 0), None);
+
+        fs::write(
+            root.join("huge.py"),
+            "x\n".repeat(MAX_SOURCE_BYTES as usize),
+        )
+        .unwrap();
+        assert_eq!(
+            source_excerpt(&root, &location("huge.py".to_string()), 0),
+            None
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use regex::Regex;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::str::FromStr;
+
+use crate::api::openai::count_tokens;
+
+/// Tokens set aside for the elision marker
+const MARKER_TOKENS: usize = 16;
+
+/// Size of each read when streaming stdin
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// # Truncation Strategy
+///
+/// Which part of an oversized input survives the token budget
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truncate {
+    /// Keep the start
+    Head,
+    /// Keep the end, usually the most useful part of a log
+    Tail,
+    /// Keep the start and end in equal measure, cutting exactly at the budget
+    Middle,
+    /// Keep whole lines from the start and end, favouring the end, after collapsing repeated lines
+    Smart,
+}
+
+impl FromStr for Truncate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "head" => Ok(Truncate::Head),
+            "tail" => Ok(Truncate::Tail),
+            "middle" => Ok(Truncate::Middle),
+            "smart" => Ok(Truncate::Smart),
+            other => Err(format!(
+                "expected head, tail, middle or smart, got '{}'",
+                other
+            )),
+        }
+    }
+}
+
+/// The line put in place of the text that was removed, naming the limit it was cut to fit e.g.
+/// `token` or `byte`
+fn marker(lines: usize, limit: &str) -> String {
+    format!(
+        "[... {} lines omitted to fit the {} limit ...]",
+        lines, limit
+    )
+}
+
+/// Drops a UTF-8 sequence cut off at the end of a chunk
+fn trim_partial_end(bytes: &mut Vec<u8>) {
+    if let Err(e) = std::str::from_utf8(bytes) {
+        if e.error_len().is_none() {
+            bytes.truncate(e.valid_up_to());
+        }
+    }
+}
+
+/// Drops the continuation bytes of a UTF-8 sequence cut off at the start of a chunk
+fn trim_partial_start(bytes: &mut VecDeque<u8>) {
+    for _ in 0..3 {
+        match bytes.front() {
+            Some(byte) if byte & 0xC0 == 0x80 => {
+                bytes.pop_front();
+            },
+            _ => break,
+        }
+    }
+}
+
+/// Counts the lines in a run of bytes that is being dropped
+fn count_lines(bytes: impl IntoIterator<Item = u8>) -> usize {
+    bytes.into_iter().filter(|&byte| byte == b'\n').count()
+}
+
+/// # Read Truncated
+///
+/// Streams the reader keeping at most `max_bytes` in memory, so an input of any size can be
+/// piped in. The kept bytes depend on the strategy: the start for head, the end for tail, and an
+/// even split of start and end otherwise. Dropped bytes are replaced with a marker.
+pub fn read_truncated(
+    mut reader: impl Read,
+    max_bytes: usize,
+    strategy: Truncate,
+) -> Result<Vec<u8>, String> {
+    let head_limit = match strategy {
+        Truncate::Head => max_bytes,
+        Truncate::Tail => 0,
+        Truncate::Middle | Truncate::Smart => max_bytes / 2,
+    };
+    let tail_limit = max_bytes - head_limit;
+
+    let mut head: Vec<u8> = vec![];
+    let mut tail: VecDeque<u8> = VecDeque::new();
+    let mut dropped_lines = 0;
+    let mut dropped_any = false;
+    let mut chunk = vec![0; CHUNK_BYTES];
+
+    loop {
+        let read = reader
+            .read(&mut chunk)
+            .map_err(|e| format!("failed to read from stdin: {}", e))?;
+        if read == 0 {
+            break;
+        }
+        let mut bytes = &chunk[..read];
+
+        let to_head = bytes.len().min(head_limit - head.len());
+        head.extend_from_slice(&bytes[..to_head]);
+        bytes = &bytes[to_head..];
+
+        if tail_limit == 0 {
+            // head only, keep reading so the line count is right and the writer isn't cut off
+            dropped_any |= !bytes.is_empty();
+            dropped_lines += count_lines(bytes.iter().copied());
+            continue;
+        }
+        tail.extend(bytes);
+        if tail.len() > tail_limit {
+            let excess = tail.len() - tail_limit;
+            dropped_any = true;
+            dropped_lines += count_lines(tail.drain(..excess));
+        }
+    }
+
+    if !dropped_any {
+        head.extend(tail);
+        return Ok(head);
+    }
+
+    trim_partial_end(&mut head);
+    trim_partial_start(&mut tail);
+    let mut kept = head;
+    if !kept.is_empty() && !kept.ends_with(b"\n") {
+        kept.push(b'\n');
+    }
+    kept.extend_from_slice(marker(dropped_lines, "byte").as_bytes());
+    if !tail.is_empty() {
+        kept.push(b'\n');
+    }
+    kept.extend(tail);
+    Ok(kept)
+}
+
+/// Byte offsets of the start and end of each token, matching [count_tokens]
+fn token_spans(text: &str) -> Vec<(usize, usize)> {
+    let re = Regex::new(r"\w+|[^\w\s]").unwrap();
+    re.find_iter(text).map(|m| (m.start(), m.end())).collect()
+}
+
+/// Cuts the text to its first and last tokens, joined by a marker
+fn cut_tokens(text: &str, head_tokens: usize, tail_tokens: usize) -> String {
+    let spans = token_spans(text);
+    if head_tokens + tail_tokens >= spans.len() {
+        return text.to_string();
+    }
+    let head_end = if head_tokens == 0 {
+        0
+    } else {
+        spans[head_tokens - 1].1
+    };
+    let tail_start = if tail_tokens == 0 {
+        text.len()
+    } else {
+        spans[spans.len() - tail_tokens].0
+    };
+    let omitted = text[head_end..tail_start].lines().count().max(1);
+
+    let mut cut = text[..head_end].to_string();
+    if !cut.is_empty() {
+        cut.push('\n');
+    }
+    cut.push_str(&marker(omitted, "token"));
+    if tail_start < text.len() {
+        cut.push('\n');
+        cut.push_str(&text[tail_start..]);
+    }
+    cut
+}
+
+/// Replaces runs of identical lines with one copy and a count
+fn collapse_repeats(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut previous: Option<&str> = None;
+    let mut repeats = 0;
+    for line in text.lines() {
+        if previous == Some(line) {
+            repeats += 1;
+            continue;
+        }
+        if repeats > 0 {
+            lines.push(format!("[previous line repeated {} more times]", repeats));
+        }
+        lines.push(line.to_string());
+        previous = Some(line);
+        repeats = 0;
+    }
+    if repeats > 0 {
+        lines.push(format!("[previous line repeated {} more times]", repeats));
+    }
+    lines
+}
+
+/// Keeps whole lines from the start and end, a third of the budget for the start
+fn smart_cut(text: &str, budget: usize) -> String {
+    let lines = collapse_repeats(text);
+    let collapsed = lines.join("\n");
+    if count_tokens(&collapsed) <= budget {
+        return collapsed;
+    }
+    let budget = budget.saturating_sub(MARKER_TOKENS);
+
+    let costs: Vec<usize> = lines.iter().map(|line| count_tokens(line)).collect();
+    let mut head_budget = budget / 3;
+    let mut head = 0;
+    while head < lines.len() && costs[head] <= head_budget {
+        head_budget -= costs[head];
+        head += 1;
+    }
+    // whatever the start didn't use goes to the end
+    let mut tail_budget = budget - budget / 3 + head_budget;
+    let mut tail = lines.len();
+    while tail > head && costs[tail - 1] <= tail_budget {
+        tail_budget -= costs[tail - 1];
+        tail -= 1;
+    }
+
+    if head == 0 && tail == lines.len() {
+        // a single line bigger than the budget, so cut inside it
+        return cut_tokens(&collapsed, budget / 3, budget - budget / 3);
+    }
+
+    let mut kept: Vec<String> = lines[..head].to_vec();
+    kept.push(marker(tail - head, "token"));
+    kept.extend_from_slice(&lines[tail..]);
+    kept.join("\n")
+}
+
+/// # Truncate To Token Budget
+///
+/// Cuts the input down to roughly `budget` tokens, as estimated by [count_tokens], using the
+/// chosen strategy. A marker shows where text was removed. Input already within budget is
+/// returned unchanged.
+pub fn truncate_to_tokens(input: &str, budget: usize, strategy: Truncate) -> String {
+    if count_tokens(input) <= budget {
+        return input.to_string();
+    }
+    let text_budget = budget.saturating_sub(MARKER_TOKENS);
+    match strategy {
+        Truncate::Head => cut_tokens(input, text_budget, 0),
+        Truncate::Tail => cut_tokens(input, 0, text_budget),
+        Truncate::Middle => cut_tokens(input, text_budget / 2, text_budget - text_budget / 2),
+        // collapsing repeats may be enough, so it reserves room for a marker itself
+        Truncate::Smart => smart_cut(input, budget),
+    }
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    fn numbered_lines(count: usize) -> String {
+        (1..=count)
+            .map(|n| format!("line {}", n))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Test each strategy keeps the expected part and fits the budget
+    #[cfg_attr(not(doc), test)]
+    fn test_truncate_to_tokens() {
+        // each line is two tokens
+        let input = numbered_lines(100);
+
+        let head = truncate_to_tokens(&input, 36, Truncate::Head);
+        assert!(head.starts_with("line 1\nline 2\n"));
+        assert!(head.ends_with("lines omitted to fit the token limit ...]"));
+        assert!(count_tokens(&head) <= 36);
+
+        let tail = truncate_to_tokens(&input, 36, Truncate::Tail);
+        assert!(tail.starts_with("[... "));
+        assert!(tail.ends_with("line 99\nline 100"));
+        assert!(count_tokens(&tail) <= 36);
+
+        let middle = truncate_to_tokens(&input, 36, Truncate::Middle);
+        assert!(middle.starts_with("line 1\n"));
+        assert!(middle.ends_with("line 100"));
+        assert!(count_tokens(&middle) <= 36);
+
+        let smart = truncate_to_tokens(&input, 46, Truncate::Smart);
+        assert!(smart.starts_with("line 1\nline 2\nline 3\nline 4\nline 5\n[... 85 lines"));
+        assert!(smart.ends_with("line 100"));
+        assert!(count_tokens(&smart) <= 46);
+
+        assert_eq!(truncate_to_tokens("short", 36, Truncate::Head), "short");
+    }
+
+    /// Test that smart truncation collapses repeated lines before cutting anything
+    #[cfg_attr(not(doc), test)]
+    fn test_smart_collapses_repeats() {
+        let input = format!("start\n{}end", "retrying connection\n".repeat(50));
+        assert_eq!(
+            truncate_to_tokens(&input, 20, Truncate::Smart),
+            "start\nretrying connection\n[previous line repeated 49 more times]\nend"
+        );
+    }
+
+    /// Test that streamed input keeps the right bytes and never splits a character
+    #[cfg_attr(not(doc), test)]
+    fn test_read_truncated() {
+        let input = "a\nb\nc\nd\ne\nf\n";
+
+        assert_eq!(
+            read_truncated(input.as_bytes(), 100, Truncate::Tail).unwrap(),
+            input.as_bytes()
+        );
+        assert_eq!(
+            String::from_utf8(read_truncated(input.as_bytes(), 4, Truncate::Head).unwrap())
+                .unwrap(),
+            "a\nb\n[... 4 lines omitted to fit the byte limit ...]"
+        );
+        assert_eq!(
+            String::from_utf8(read_truncated(input.as_bytes(), 4, Truncate::Tail).unwrap())
+                .unwrap(),
+            "[... 4 lines omitted to fit the byte limit ...]\ne\nf\n"
+        );
+        assert_eq!(
+            String::from_utf8(read_truncated(input.as_bytes(), 4, Truncate::Smart).unwrap())
+                .unwrap(),
+            "a\n[... 4 lines omitted to fit the byte limit ...]\nf\n"
+        );
+
+        let kept = read_truncated("ééééé".as_bytes(), 5, Truncate::Middle).unwrap();
+        assert_eq!(
+            String::from_utf8(kept).unwrap(),
+            "é\n[... 0 lines omitted to fit the byte limit ...]\né"
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use log::*; // logging
+use notify::{EventKind, RecursiveMode, Watcher};
+use similar::TextDiff;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::api::openai::{count_tokens, send_to_gpt4, AssistantPurpose};
+use crate::cli::cancel::cancellable;
+use crate::cli::index::{collect_files, SKIPPED_DIRS};
+use crate::cli::input::{decode_text, is_binary};
+use crate::cli::output::{fail, markdown_plaintext_or_error};
+use crate::cli::parse::{build_request, token_limit};
+use crate::cli::truncate::{truncate_to_tokens, Truncate};
+
+/// Files larger than this are not sent when they change
+const MAX_WATCHED_FILE_BYTES: u64 = 1024 * 1024;
+
+/// Tokens kept free for the label and fence around the changed file
+const FORMATTING_TOKENS: usize = 64;
+
+/// # Define Watch Subcommand
+///
+/// `pipe-gpt watch src/ -p "Review this change"` sends each file as it changes
+pub fn watch_command() -> Command {
+    Command::new("watch")
+        .about("Watch files and directories, sending each file that changes with the prompt and printing the reply")
+        .arg(
+            Arg::new("paths")
+                .value_name("PATHS")
+                .help("Files and directories to watch. Directories are watched recursively")
+                .required(true)
+                .num_args(1..)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("debounce")
+                .long("debounce")
+                .value_name("milliseconds")
+                .help("How long to wait for changes to stop before sending them")
+                .required(false)
+                .default_value("500")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("diff")
+                .long("diff")
+                .help("Send a diff against the version last sent, or seen at startup, instead of the whole file")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+}
+
+/// # Is Ignored
+///
+/// Hidden files and directories, build and dependency directories and editor backup files,
+/// which change often and are never worth sending
+pub fn is_ignored(path: &Path) -> bool {
+    let hidden_or_skipped = path.components().any(|component| match component {
+        Component::Normal(name) => {
+            let name = name.to_string_lossy();
+            name.starts_with('.') || SKIPPED_DIRS.contains(&name.as_ref())
+        },
+        _ => false,
+    });
+    let backup = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.ends_with('~') || name.ends_with(".orig"));
+    hidden_or_skipped || backup
+}
+
+/// Reads a file that is worth sending, or `None` if it is missing, binary or too large
+fn read_text(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_file() || metadata.len() > MAX_WATCHED_FILE_BYTES {
+        return None;
+    }
+    let bytes = fs::read(path).ok()?;
+    if is_binary(&bytes) {
+        return None;
+    }
+    Some(decode_text(&bytes).0)
+}
+
+/// # Change
+///
+/// What is sent for a changed file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// The whole file
+    Contents(String),
+    /// A unified diff against the version last sent
+    Diff(String),
+}
+
+/// # Snapshots
+///
+/// The contents of each watched file as last sent, so saves that change nothing are skipped and
+/// diffs can be made
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    files: HashMap<PathBuf, String>,
+}
+
+impl Snapshots {
+    /// Reads every file under the paths, as the starting point for diffs
+    pub fn new(paths: &[PathBuf]) -> Result<Self, String> {
+        let mut snapshots = Snapshots::default();
+        for path in paths {
+            let files = if path.is_dir() {
+                collect_files(path)?
+            } else {
+                vec![path.clone()]
+            };
+            for file in files {
+                if let Some(text) = read_text(&file) {
+                    snapshots.files.insert(file, text);
+                }
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// The number of files being tracked
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether no files are being tracked
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// # Update Snapshot
+    ///
+    /// Records the file's current contents and returns what to send, or `None` if the file is
+    /// unchanged, gone or not text
+    pub fn update(&mut self, path: &Path, diff: bool) -> Option<Change> {
+        let Some(text) = read_text(path) else {
+            if self.files.remove(path).is_some() {
+                debug!("{:?} was removed", path);
+            }
+            return None;
+        };
+        match self.files.insert(path.to_path_buf(), text.clone()) {
+            Some(previous) if previous == text => None,
+            Some(previous) if diff => {
+                let name = path.display().to_string();
+                Some(Change::Diff(
+                    TextDiff::from_lines(&previous, &text)
+                        .unified_diff()
+                        .context_radius(3)
+                        .header(&name, &name)
+                        .to_string(),
+                ))
+            },
+            _ => Some(Change::Contents(text)),
+        }
+    }
+}
+
+/// # Start Watcher
+///
+/// Watches directories recursively and files through their parent directory, so files that
+/// editors replace rather than write in place are still seen. Changed paths are sent down the
+/// channel. The watcher stops when it is dropped.
+pub fn start_watcher(
+    paths: &[PathBuf],
+) -> Result<(notify::RecommendedWatcher, UnboundedReceiver<PathBuf>), String> {
+    let (sender, receiver) = unbounded_channel();
+    let files: HashSet<PathBuf> = paths
+        .iter()
+        .filter(|path| path.is_file())
+        .cloned()
+        .collect();
+    let dirs: Vec<PathBuf> = paths.iter().filter(|path| path.is_dir()).cloned().collect();
+
+    let filter_files = files.clone();
+    let filter_dirs = dirs.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+                for path in event.paths {
+                    let watched = filter_files.contains(&path)
+                        || filter_dirs.iter().any(|dir| {
+                            path.strip_prefix(dir)
+                                .is_ok_and(|relative| !is_ignored(relative))
+                        });
+                    if watched {
+                        // the receiver is only gone once watching has stopped
+                        let _ = sender.send(path);
+                    }
+                }
+            },
+            Err(e) => warn!("watch error: {}", e),
+        })
+        .map_err(|e| format!("could not start watching: {}", e))?;
+
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .map_err(|e| format!("could not watch {:?}: {}", dir, e))?;
+    }
+    let parents: HashSet<&Path> = files.iter().filter_map(|file| file.parent()).collect();
+    for parent in parents {
+        watcher
+            .watch(parent, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("could not watch {:?}: {}", parent, e))?;
+    }
+    Ok((watcher, receiver))
+}
+
+/// # Next Batch
+///
+/// Waits for a changed path, then keeps collecting until no change has arrived for `debounce`,
+/// so a save that writes several times, or a tool that touches many files, is sent once.
+/// Returns `None` once the channel is closed.
+pub async fn next_batch(
+    events: &mut UnboundedReceiver<PathBuf>,
+    debounce: Duration,
+) -> Option<BTreeSet<PathBuf>> {
+    let mut paths = BTreeSet::from([events.recv().await?]);
+    while let Ok(Some(path)) = tokio::time::timeout(debounce, events.recv()).await {
+        paths.insert(path);
+    }
+    Some(paths)
+}
+
+/// The path relative to the working directory where it is under it, for display
+fn display_path(path: &Path, cwd: &Path) -> String {
+    path.strip_prefix(cwd).unwrap_or(path).display().to_string()
+}
+
+/// # Run Watch Subcommand
+///
+/// - Reads the watched files to have something to compare changes with
+/// - Waits for changes, debounced, and skips files whose contents didn't change
+/// - Sends each changed file, or its diff with `--diff`, with the prompt and prints the reply
+/// - Carries on after a failed request, until Ctrl-C
+///
+/// Replies are rendered as markdown when `render_markdown` is set, as `--markdown` does for a
+/// single request.
+pub async fn watch(matches: &ArgMatches, render_markdown: bool) {
+    let debounce = Duration::from_millis(
+        *matches
+            .get_one::<u64>("debounce")
+            .expect("debounce has a default"),
+    );
+    let diff = *matches.get_one::<bool>("diff").unwrap_or(&false);
+    let prompt = matches
+        .get_one::<String>("prepend")
+        .cloned()
+        .unwrap_or_else(|| fail("pass -p with what to ask about each change".to_string()));
+
+    // notify reports absolute paths, so watch those for the snapshots to match
+    let paths: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("paths")
+        .expect("paths are required")
+        .map(|path| {
+            path.canonicalize()
+                .unwrap_or_else(|e| fail(format!("could not watch {:?}: {}", path, e)))
+        })
+        .collect();
+    let cwd = std::env::current_dir()
+        .and_then(|cwd| cwd.canonicalize())
+        .unwrap_or_default();
+
+    let mut snapshots = Snapshots::new(&paths).unwrap_or_else(|e| fail(e));
+    let (_watcher, mut events) = start_watcher(&paths).unwrap_or_else(|e| fail(e));
+    eprintln!("Watching {} files. Press Ctrl-C to stop.", snapshots.len());
+
+    let budget = token_limit(matches).saturating_sub(
+        count_tokens(&format!("{}{}", AssistantPurpose::Default, prompt)) + FORMATTING_TOKENS,
+    );
+    cancellable(async {
+        while let Some(batch) = next_batch(&mut events, debounce).await {
+            for path in batch {
+                let Some(change) = snapshots.update(&path, diff) else {
+                    continue;
+                };
+                let name = display_path(&path, &cwd);
+                let content = match change {
+                    Change::Contents(text) => format!(
+                        "{}:\n```\n{}\n```",
+                        name,
+                        truncate_to_tokens(&text, budget, Truncate::Smart)
+                    ),
+                    Change::Diff(diff) => format!(
+                        "Changes to {} since the last version:\n```diff\n{}\n```",
+                        name,
+                        truncate_to_tokens(&diff, budget, Truncate::Smart)
+                    ),
+                };
+                eprintln!("\n--- {} changed ---", name);
+                let chat_request = build_request(
+                    &format!("{}\n\n{}", prompt, content),
+                    "",
+                    &[],
+                    matches,
+                    &AssistantPurpose::Default,
+                    None,
+                    None,
+                );
+                // errors are printed without exiting, as the next save may well work
+                let response = send_to_gpt4(&chat_request).await.map_err(|e| e.to_string());
+                markdown_plaintext_or_error(response, render_markdown);
+            }
+        }
+    })
+    .await;
+}
+
+#[cfg(any(test, doc))]
+mod tests {
+    use super::*;
+
+    /// Test that hidden, build and backup paths are ignored
+    #[cfg_attr(not(doc), test)]
+    fn test_is_ignored() {
+        assert!(!is_ignored(Path::new("src/main.rs")));
+        assert!(is_ignored(Path::new(".git/index")));
+        assert!(is_ignored(Path::new("src/.main.rs.swp")));
+        assert!(is_ignored(Path::new("target/debug/pipe-gpt")));
+        assert!(is_ignored(Path::new("web/node_modules/x/index.js")));
+        assert!(is_ignored(Path::new("src/main.rs~")));
+        assert!(is_ignored(Path::new("src/main.rs.orig")));
+    }
+
+    /// Test that only real changes are returned, as whole files or diffs
+    #[cfg_attr(not(doc), test)]
+    fn test_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("notes.md");
+        fs::write(&file, "one\ntwo\nthree\n").unwrap();
+        fs::write(dir.path().join("image.bin"), b"\x7FELF\0\0\0").unwrap();
+
+        let mut snapshots = Snapshots::new(&[dir.path().to_path_buf()]).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots.update(&file, true), None);
+
+        fs::write(&file, "one\n2\nthree\n").unwrap();
+        let name = file.display().to_string();
+        assert_eq!(
+            snapshots.update(&file, true),
+            Some(Change::Diff(format!(
+                "--- {}\n+++ {}\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n",
+                name, name
+            )))
+        );
+        fs::write(&file, "one\n2\nthree\nfour\n").unwrap();
+        assert_eq!(
+            snapshots.update(&file, false),
+            Some(Change::Contents("one\n2\nthree\nfour\n".to_string()))
+        );
+
+        // new files have nothing to diff against so are sent whole
+        let new_file = dir.path().join("new.md");
+        fs::write(&new_file, "hello\n").unwrap();
+        assert_eq!(
+            snapshots.update(&new_file, true),
+            Some(Change::Contents("hello\n".to_string()))
+        );
+
+        fs::remove_file(&file).unwrap();
+        assert_eq!(snapshots.update(&file, true), None);
+        assert_eq!(snapshots.len(), 1);
+    }
+
+    /// Test that changes arriving close together are sent as one batch
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_next_batch() {
+        let (sender, mut receiver) = unbounded_channel();
+        sender.send(PathBuf::from("a.rs")).unwrap();
+        sender.send(PathBuf::from("b.rs")).unwrap();
+        sender.send(PathBuf::from("a.rs")).unwrap();
+
+        let batch = next_batch(&mut receiver, Duration::from_millis(20))
+            .await
+            .unwrap();
+        assert_eq!(
+            batch,
+            BTreeSet::from([PathBuf::from("a.rs"), PathBuf::from("b.rs")])
+        );
+
+        drop(sender);
+        assert_eq!(
+            next_batch(&mut receiver, Duration::from_millis(20)).await,
+            None
+        );
+    }
+
+    /// Test that writes under a watched directory are reported and hidden files are not
+    #[cfg_attr(not(doc), tokio::test)]
+    async fn test_start_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+
+        let (_watcher, mut events) = start_watcher(std::slice::from_ref(&root)).unwrap();
+        fs::write(root.join(".hidden"), "ignored").unwrap();
+        fs::write(root.join("src/lib.rs"), "pub fn answer() -> u32 { 42 }").unwrap();
+
+        let batch = tokio::time::timeout(
+            Duration::from_secs(5),
+            next_batch(&mut events, Duration::from_millis(200)),
+        )
+        .await
+        .expect("no change was reported")
+        .unwrap();
+        assert!(batch.contains(&root.join("src/lib.rs")));
+        assert!(!batch.contains(&root.join(".hidden")));
+    }
+}
@@ -1,20 +1,210 @@
+use log::*; // logging
 use memoize::memoize;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
+use crate::api::tools::builtin_tools;
+
 fn default_api_url() -> String {
     "https://api.openai.com/v1/".to_string()
 }
 fn default_model() -> String {
     "gpt-4o".to_string()
 }
+fn default_embedding_model() -> String {
+    "text-embedding-3-small".to_string()
+}
 fn default_max_tokens() -> i32 {
     8192
 }
 fn default_temperature() -> f32 {
     0.6
 }
+fn default_top_p() -> f32 {
+    0.95
+}
+fn default_max_input_bytes() -> usize {
+    20 * 1024 * 1024
+}
+fn default_agent_max_steps() -> usize {
+    10
+}
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+fn default_read_timeout_secs() -> u64 {
+    60
+}
+fn default_timeout_secs() -> u64 {
+    300
+}
+
+fn default_tool_parameters() -> Value {
+    json!({"type": "object", "properties": {}})
+}
+
+/// # Custom Tool
+///
+/// A project script the model can call in agent mode. `command` is split into words like a shell
+/// would, then each `{name}` placeholder is replaced with that argument, so arguments can never
+/// add words or shell syntax.
+///
+/// ```yaml
+/// tools:
+///   - name: query_db
+///     description: Run a read-only SQL query against the dev database
+///     parameters:
+///       type: object
+///       properties:
+///         sql: { type: string }
+///       required: [sql]
+///     command: ./scripts/query-db.sh --sql {sql}
+/// ```
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct ToolConfig {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema for the arguments, which must be an object
+    #[serde(default = "default_tool_parameters")]
+    pub parameters: Value,
+    pub command: String,
+}
+
+/// Splits a command into words, honouring single and double quotes
+fn split_words(command: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            },
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            },
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            },
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unclosed quote in command '{}'", command));
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+impl ToolConfig {
+    /// Names of the `{placeholders}` in the command
+    pub fn placeholders(&self) -> Vec<String> {
+        let re = Regex::new(r"\{([A-Za-z0-9_]+)\}").unwrap();
+        re.captures_iter(&self.command)
+            .map(|captures| captures[1].to_string())
+            .collect()
+    }
+
+    /// # Render Command
+    ///
+    /// The program and arguments to run, with placeholders filled from the model's arguments.
+    /// Missing arguments become empty strings, non-string arguments their JSON text.
+    pub fn render(&self, args: &Value) -> Result<Vec<String>, String> {
+        let re = Regex::new(r"\{([A-Za-z0-9_]+)\}").unwrap();
+        let words = split_words(&self.command)?;
+        Ok(words
+            .iter()
+            .map(|word| {
+                re.replace_all(word, |captures: &regex::Captures| {
+                    match &args[&captures[1]] {
+                        Value::String(value) => value.clone(),
+                        Value::Null => String::new(),
+                        value => value.to_string(),
+                    }
+                })
+                .to_string()
+            })
+            .collect())
+    }
+
+    /// Checks the name, parameter schema and command template
+    fn validate(&self) -> Result<(), String> {
+        let name_pattern = Regex::new(r"^[A-Za-z0-9_-]{1,64}$").unwrap();
+        if !name_pattern.is_match(&self.name) {
+            return Err(format!(
+                "tool name '{}' must be 1 to 64 letters, digits, _ or -",
+                self.name
+            ));
+        }
+        if builtin_tools().iter().any(|tool| tool.name() == self.name) {
+            return Err(format!(
+                "tool name '{}' is already used by a built in tool",
+                self.name
+            ));
+        }
+        if self.parameters["type"] != "object" {
+            return Err(format!(
+                "parameters of tool '{}' must be a JSON Schema with type: object",
+                self.name
+            ));
+        }
+        jsonschema::validator_for(&self.parameters).map_err(|e| {
+            format!(
+                "parameters of tool '{}' are not a valid JSON Schema: {}",
+                self.name, e
+            )
+        })?;
+        let words = split_words(&self.command)?;
+        if words.is_empty() {
+            return Err(format!("command of tool '{}' is empty", self.name));
+        }
+        for placeholder in self.placeholders() {
+            if self.parameters["properties"].get(&placeholder).is_none() {
+                return Err(format!(
+                    "command of tool '{}' uses {{{}}} which is not in its parameters",
+                    self.name, placeholder
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// # MCP Server
+///
+/// A Model Context Protocol server started over stdio in agent mode. Its tools and resources are
+/// offered to the model, prefixed with the server name.
+///
+/// ```yaml
+/// mcp_servers:
+///   - name: github
+///     command: npx
+///     args: ["-y", "@modelcontextprotocol/server-github"]
+///     env:
+///       GITHUB_PERSONAL_ACCESS_TOKEN: "..."
+/// ```
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct McpServerConfig {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct AppConfig {
@@ -22,10 +212,118 @@ pub struct AppConfig {
     pub api_url: String,
     #[serde(default = "default_model")]
     pub model: String,
+    #[serde(default = "default_embedding_model")]
+    pub embedding_model: String,
     #[serde(default = "default_max_tokens")]
     pub max_tokens: i32,
     #[serde(default = "default_temperature")]
     pub temperature: f32,
+    #[serde(default = "default_top_p")]
+    pub top_p: f32,
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    pub logit_bias: Option<HashMap<String, i32>>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub seed: Option<i64>,
+    #[serde(default)]
+    pub response_format: Option<String>,
+    #[serde(default = "default_max_input_bytes")]
+    pub max_input_bytes: usize,
+    #[serde(default = "default_agent_max_steps")]
+    pub agent_max_steps: usize,
+    #[serde(default)]
+    pub allowed_commands: Vec<String>,
+    #[serde(default)]
+    pub tools: Vec<ToolConfig>,
+    #[serde(default)]
+    pub mcp_servers: Vec<McpServerConfig>,
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    #[serde(default = "default_read_timeout_secs")]
+    pub read_timeout_secs: u64,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl AppConfig {
+    /// # Remove Invalid Entries
+    ///
+    /// Checks what serde can't, so mistakes are reported when the config is loaded rather than
+    /// when the model first calls a tool. Invalid tools and MCP servers are dropped and zero
+    /// timeouts reset to their defaults, so one mistake doesn't throw away the rest of the
+    /// config. Returns what was wrong with each.
+    pub fn remove_invalid(&mut self) -> Vec<String> {
+        let mut errors = vec![];
+        for (name, secs, default) in [
+            (
+                "connect_timeout_secs",
+                &mut self.connect_timeout_secs,
+                default_connect_timeout_secs(),
+            ),
+            (
+                "read_timeout_secs",
+                &mut self.read_timeout_secs,
+                default_read_timeout_secs(),
+            ),
+            (
+                "timeout_secs",
+                &mut self.timeout_secs,
+                default_timeout_secs(),
+            ),
+        ] {
+            if *secs == 0 {
+                errors.push(format!("{} must be at least 1", name));
+                *secs = default;
+            }
+        }
+        let mut tools: Vec<ToolConfig> = vec![];
+        for tool in std::mem::take(&mut self.tools) {
+            match tool.validate() {
+                Err(e) => errors.push(e),
+                Ok(()) if tools.iter().any(|other| other.name == tool.name) => {
+                    errors.push(format!("tool name '{}' is used more than once", tool.name))
+                },
+                Ok(()) => tools.push(tool),
+            }
+        }
+        self.tools = tools;
+        let name_pattern = Regex::new(r"^[A-Za-z0-9-]{1,32}$").unwrap();
+        let mut servers: Vec<McpServerConfig> = vec![];
+        for server in std::mem::take(&mut self.mcp_servers) {
+            if !name_pattern.is_match(&server.name) {
+                errors.push(format!(
+                    "MCP server name '{}' must be 1 to 32 letters, digits or -",
+                    server.name
+                ));
+            } else if servers.iter().any(|other| other.name == server.name) {
+                errors.push(format!(
+                    "MCP server name '{}' is used more than once",
+                    server.name
+                ));
+            } else {
+                servers.push(server);
+            }
+        }
+        self.mcp_servers = servers;
+        errors
+    }
+
+    /// # Validate Config
+    ///
+    /// The first problem [AppConfig::remove_invalid] would fix, if any
+    pub fn validate(&self) -> Result<(), String> {
+        match self.clone().remove_invalid().into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 }
 
 impl Default for AppConfig {
@@ -33,17 +331,40 @@ impl Default for AppConfig {
         AppConfig {
             api_url: default_api_url(),
             model: default_model(),
+            embedding_model: default_embedding_model(),
             max_tokens: default_max_tokens(),
             temperature: default_temperature(),
+            top_p: default_top_p(),
+            stop: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            user: None,
+            seed: None,
+            response_format: None,
+            max_input_bytes: default_max_input_bytes(),
+            agent_max_steps: default_agent_max_steps(),
+            allowed_commands: vec![],
+            tools: vec![],
+            mcp_servers: vec![],
+            connect_timeout_secs: default_connect_timeout_secs(),
+            read_timeout_secs: default_read_timeout_secs(),
+            timeout_secs: default_timeout_secs(),
         }
     }
 }
 
 fn get_config(config_path: &PathBuf) -> AppConfig {
     match fs::read_to_string(config_path) {
-        Ok(content) => match serde_yaml::from_str(&content) {
-            Ok(config) => {
-                println!("Configuration loaded and merged from: {:?}", config_path);
+        Ok(content) => match serde_yaml::from_str::<AppConfig>(&content) {
+            Ok(mut config) => {
+                for e in config.remove_invalid() {
+                    eprintln!(
+                        "Error in config file {:?}: {}. Ignoring it.",
+                        config_path, e
+                    );
+                }
+                info!("Configuration loaded and merged from: {:?}", config_path);
                 config
             },
             Err(e) => {
@@ -74,7 +395,7 @@ pub fn load_config() -> AppConfig {
         },
     };
 
-    println!("config_path: {:?}", config_path);
+    debug!("config_path: {:?}", config_path);
 
     config_path.push("pipe-gpt");
     config_path.push("config.yaml");
@@ -130,6 +451,199 @@ temperature: 0.8
         teardown_temp_config(temp_dir);
     }
 
+    #[test]
+    fn test_load_config_sampling_parameters() {
+        let config_content = r#"
+top_p: 0.5
+stop: ["END", "STOP"]
+presence_penalty: -1.5
+frequency_penalty: 1.0
+logit_bias:
+  "50256": -100
+user: "ci-pipeline"
+seed: 42
+response_format: json_object
+connect_timeout_secs: 5
+read_timeout_secs: 30
+timeout_secs: 120
+        "#;
+        let (config_app_dir, temp_dir) = setup_temp_config_env();
+        write_config_to_temp_file(config_app_dir.clone(), config_content);
+
+        let loaded_config = get_config(&config_app_dir);
+
+        assert_eq!(loaded_config.top_p, 0.5);
+        assert_eq!(
+            loaded_config.stop,
+            Some(vec!["END".to_string(), "STOP".to_string()])
+        );
+        assert_eq!(loaded_config.presence_penalty, Some(-1.5));
+        assert_eq!(loaded_config.frequency_penalty, Some(1.0));
+        assert_eq!(
+            loaded_config.logit_bias,
+            Some(HashMap::from([("50256".to_string(), -100)]))
+        );
+        assert_eq!(loaded_config.user.as_deref(), Some("ci-pipeline"));
+        assert_eq!(loaded_config.seed, Some(42));
+        assert_eq!(
+            loaded_config.response_format.as_deref(),
+            Some("json_object")
+        );
+        assert_eq!(loaded_config.connect_timeout_secs, 5);
+        assert_eq!(loaded_config.read_timeout_secs, 30);
+        assert_eq!(loaded_config.timeout_secs, 120);
+
+        teardown_temp_config(temp_dir);
+    }
+
+    #[test]
+    fn test_load_config_tools() {
+        let config_content = r#"
+tools:
+  - name: query_db
+    description: Run a read-only SQL query
+    parameters:
+      type: object
+      properties:
+        sql: { type: string }
+      required: [sql]
+    command: ./scripts/query-db.sh --sql {sql} "--label=db {sql}"
+        "#;
+        let (config_app_dir, temp_dir) = setup_temp_config_env();
+        write_config_to_temp_file(config_app_dir.clone(), config_content);
+
+        let loaded_config = get_config(&config_app_dir);
+
+        assert_eq!(loaded_config.tools.len(), 1);
+        let tool = &loaded_config.tools[0];
+        assert_eq!(tool.placeholders(), vec!["sql", "sql"]);
+        assert_eq!(
+            tool.render(&json!({"sql": "select 1; drop table users"}))
+                .unwrap(),
+            vec![
+                "./scripts/query-db.sh",
+                "--sql",
+                "select 1; drop table users",
+                "--label=db select 1; drop table users"
+            ]
+        );
+
+        teardown_temp_config(temp_dir);
+    }
+
+    #[test]
+    fn test_load_config_rejects_invalid_tools() {
+        let tool = |name: &str, parameters: Value, command: &str| ToolConfig {
+            name: name.to_string(),
+            description: "test".to_string(),
+            parameters,
+            command: command.to_string(),
+        };
+        let config = |tools: Vec<ToolConfig>| AppConfig {
+            tools,
+            ..AppConfig::default()
+        };
+        let params = default_tool_parameters();
+
+        assert!(config(vec![tool("ok", params.clone(), "true")])
+            .validate()
+            .is_ok());
+        assert!(config(vec![tool("has space", params.clone(), "true")])
+            .validate()
+            .is_err());
+        assert!(config(vec![tool("grep", params.clone(), "true")])
+            .validate()
+            .is_err());
+        assert!(config(vec![tool("t", json!({"type": "string"}), "true")])
+            .validate()
+            .is_err());
+        assert!(config(vec![tool("t", params.clone(), "echo {missing}")])
+            .validate()
+            .unwrap_err()
+            .contains("{missing}"));
+        assert!(config(vec![tool("t", params.clone(), "echo 'open")])
+            .validate()
+            .is_err());
+        assert!(config(vec![
+            tool("t", params.clone(), "true"),
+            tool("t", params.clone(), "false")
+        ])
+        .validate()
+        .unwrap_err()
+        .contains("more than once"));
+    }
+
+    #[test]
+    fn test_load_config_mcp_servers() {
+        let config_content = r#"
+mcp_servers:
+  - name: github
+    command: npx
+    args: ["-y", "@modelcontextprotocol/server-github"]
+    env:
+      GITHUB_PERSONAL_ACCESS_TOKEN: secret
+  - name: files
+    command: mcp-files
+        "#;
+        let (config_app_dir, temp_dir) = setup_temp_config_env();
+        write_config_to_temp_file(config_app_dir.clone(), config_content);
+
+        let loaded_config = get_config(&config_app_dir);
+
+        assert_eq!(loaded_config.mcp_servers.len(), 2);
+        assert_eq!(loaded_config.mcp_servers[0].args.len(), 2);
+        assert_eq!(
+            loaded_config.mcp_servers[0].env["GITHUB_PERSONAL_ACCESS_TOKEN"],
+            "secret"
+        );
+        assert!(loaded_config.mcp_servers[1].args.is_empty());
+
+        let mut duplicate = loaded_config.clone();
+        duplicate.mcp_servers[1].name = "github".to_string();
+        assert!(duplicate.validate().is_err());
+        let mut bad_name = loaded_config;
+        bad_name.mcp_servers[0].name = "git_hub".to_string();
+        assert!(bad_name.validate().is_err());
+
+        teardown_temp_config(temp_dir);
+    }
+
+    #[test]
+    fn test_load_config_keeps_valid_entries() {
+        let config_content = r#"
+model: custom-model
+timeout_secs: 0
+read_timeout_secs: 30
+tools:
+  - name: has space
+    description: Invalid name
+    command: "true"
+  - name: query_db
+    description: Run a read-only SQL query
+    command: ./scripts/query-db.sh
+mcp_servers:
+  - name: git_hub
+    command: npx
+  - name: files
+    command: mcp-files
+        "#;
+        let (config_app_dir, temp_dir) = setup_temp_config_env();
+        write_config_to_temp_file(config_app_dir.clone(), config_content);
+
+        let loaded_config = get_config(&config_app_dir);
+
+        assert_eq!(loaded_config.model, "custom-model");
+        assert_eq!(loaded_config.timeout_secs, default_timeout_secs());
+        assert_eq!(loaded_config.read_timeout_secs, 30);
+        assert_eq!(loaded_config.tools.len(), 1);
+        assert_eq!(loaded_config.tools[0].name, "query_db");
+        assert_eq!(loaded_config.mcp_servers.len(), 1);
+        assert_eq!(loaded_config.mcp_servers[0].name, "files");
+        assert!(loaded_config.validate().is_ok());
+
+        teardown_temp_config(temp_dir);
+    }
+
     #[test]
     fn test_load_config_partial_file_merges_defaults() {
         let config_content = r#"
@@ -0,0 +1,28 @@
+//! Send conversations to GPT from Rust, with the same config file as the `pipe-gpt` command.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), pipe_gpt::Error> {
+//! use pipe_gpt::{Client, Request};
+//!
+//! let client = Client::from_config()?;
+//! let reply = Request::new()
+//!     .system("You are a code reviewer.")
+//!     .user("fn main() { let x = 1; }")
+//!     .send(&client)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [Client] and [Request] are the stable API. The `api` and `config` modules are public for
+//! tools that need more control, such as agent mode or schema validation.
+//!
+//! The `cli` feature, on by default, builds the command line binary and its `cli` module. Library
+//! users can drop it, along with clap and termimad, with `default-features = false`.
+
+pub mod api;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod config;
+
+pub use crate::api::client::{Client, Error, Request};
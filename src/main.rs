@@ -27,16 +27,25 @@
 
 use atty::Stream; // atty to determine if data is piped in or not
 use log::*; // logging
-use std::io::{self, Read}; // std io
+use std::io; // std io
+use std::sync::Arc;
 
-mod api;
-mod cli;
-mod config;
-
-use crate::api::openai::send_to_gpt4;
-use crate::cli::{
-    output::markdown_plaintext_or_error,
-    parse::{parse_arguments, setup_arguments},
+use pipe_gpt::api::{openai::send_to_gpt4, schema::send_with_schema, tools::send_with_tools};
+use pipe_gpt::cli::{
+    batch::batch,
+    cancel::cancellable,
+    cmd::cmd,
+    commit::{commit_msg, hook},
+    diagnostics::explain,
+    edit::edit,
+    index::{ask, embed, index},
+    input::{read_piped_input, PipedInput},
+    output::{extract, extract_or_error, markdown_plaintext_or_error, write_or_error},
+    parse::{input_settings, parse_arguments, setup_arguments},
+    pr::pr_describe,
+    translate::translate,
+    triage::triage,
+    watch::watch,
 };
 
 /// # Entry Point for Application
@@ -44,6 +53,7 @@ use crate::cli::{
 /// - Initializes the application
 /// - Initializes logging
 /// - Makes calls to parse command-line arguments
+/// - Hands off to a subcommand if one was given
 /// - Checks for piped input
 /// - Calls fn to send request to API
 /// - Outputs result
@@ -56,20 +66,66 @@ async fn main() {
     // enable logging
     env_logger::init();
 
+    let matches = setup_arguments().get_matches();
+    let render_markdown = *matches.get_one::<bool>("markdown").unwrap_or(&false);
+    match matches.subcommand() {
+        Some(("edit", sub_matches)) => return edit(sub_matches).await,
+        Some(("batch", sub_matches)) => return batch(sub_matches).await,
+        Some(("embed", sub_matches)) => return embed(sub_matches).await,
+        Some(("index", sub_matches)) => return index(sub_matches).await,
+        Some(("ask", sub_matches)) => return ask(sub_matches).await,
+        Some(("cmd", sub_matches)) => return cmd(sub_matches).await,
+        Some(("commit-msg", sub_matches)) => return commit_msg(sub_matches).await,
+        Some(("hook", sub_matches)) => return hook(sub_matches).await,
+        Some(("pr-describe", sub_matches)) => return pr_describe(sub_matches).await,
+        Some(("triage", sub_matches)) => return triage(sub_matches).await,
+        Some(("explain", sub_matches)) => return explain(sub_matches).await,
+        Some(("watch", sub_matches)) => return watch(sub_matches, render_markdown).await,
+        Some(("translate", sub_matches)) => return translate(sub_matches).await,
+        _ => {},
+    }
+
     let mut input = String::new();
+    let mut piped_images = vec![];
     // if data is being piped in
     // this check is necessary or we hang the whole program waiting for stdin when none arrives
     if !atty::is(Stream::Stdin) {
         debug!("Attempt: read from stdin");
-        io::stdin()
-            .read_to_string(&mut input)
-            .expect("Failed to read from stdin");
+        let (max_input_bytes, binary, truncate) = input_settings(&matches);
+        match read_piped_input(io::stdin().lock(), max_input_bytes, binary, truncate) {
+            Ok(PipedInput::Text(text)) => input = text,
+            Ok(PipedInput::Image(url)) => piped_images.push(url),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            },
+        }
         debug!("Success: read from stdin");
     }
 
-    let (chat_body, render_markdown) = parse_arguments(&input, setup_arguments());
+    let (chat_body, options) = parse_arguments(&input, piped_images, &matches);
 
-    markdown_plaintext_or_error(send_to_gpt4(chat_body).await, render_markdown);
+    let response = cancellable(async {
+        match (&options.schema, &options.toolbox) {
+            // only the validated document is output so it can go straight into a file
+            (Some(schema), _) => send_with_schema(chat_body, schema).await.map(|document| {
+                serde_json::to_string_pretty(&document).expect("Failed to serialise document")
+            }),
+            (None, Some(toolbox)) => {
+                send_with_tools(chat_body, Arc::clone(toolbox), options.max_steps).await
+            },
+            (None, None) => send_to_gpt4(&chat_body).await.map_err(|e| e.to_string()),
+        }
+    })
+    .await;
+    match (&options.output, &options.extract) {
+        (Some(path), Some(mode)) => {
+            write_or_error(response.and_then(|markdown| extract(&markdown, mode)), path)
+        },
+        (Some(path), None) => write_or_error(response, path),
+        (None, Some(mode)) => extract_or_error(response, mode),
+        (None, None) => markdown_plaintext_or_error(response, options.render_markdown),
+    }
     debug!("end of program");
 }
 
@@ -95,6 +151,31 @@ mod tests {
         );
     }
     #[test]
+    fn test_app_refuses_to_overwrite_input_file() {
+        use std::process::Command;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.json");
+        std::fs::write(&path, "{\"keep\": true}").unwrap();
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "target/debug/pipe-gpt -p \"Sort the keys\" -o {0} < {0}",
+                path.display()
+            ))
+            .output()
+            .expect("Failed to execute command");
+
+        println!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"keep\": true}",
+            "Expected the input file to be left untouched"
+        );
+    }
+    #[test]
     fn test_app_exits_with_1_when_too_many_tokens() {
         use std::process::Command;
         let output = Command::new("sh")
@@ -0,0 +1,82 @@
+#!/usr/bin/env python3
+"""A tiny MCP server over stdio, used by the MCP client tests.
+
+Offers an `echo` tool, a `fail` tool that always returns an error result, a `files.search` tool
+whose name the chat API won't accept, and one text resource.
+Tools are listed over two pages to exercise cursors.
+"""
+import json
+import sys
+
+TOOLS = [
+    {
+        "name": "echo",
+        "description": "Echoes the text back",
+        "inputSchema": {
+            "type": "object",
+            "properties": {"text": {"type": "string"}},
+            "required": ["text"],
+        },
+    },
+    {
+        "name": "fail",
+        "description": "Always fails",
+        "inputSchema": {"type": "object", "properties": {}},
+    },
+    {
+        "name": "files.search",
+        "description": "Never offered to the model",
+        "inputSchema": {"type": "object", "properties": {}},
+    },
+]
+
+
+def result(request, value):
+    return {"jsonrpc": "2.0", "id": request["id"], "result": value}
+
+
+def error(request, message):
+    return {"jsonrpc": "2.0", "id": request["id"], "error": {"code": -32602, "message": message}}
+
+
+def handle(request):
+    method = request.get("method")
+    params = request.get("params", {})
+    if method == "initialize":
+        return result(request, {
+            "protocolVersion": params["protocolVersion"],
+            "capabilities": {"tools": {}, "resources": {}},
+            "serverInfo": {"name": "fixture", "version": "0.1.0"},
+        })
+    if method == "tools/list":
+        if params.get("cursor") == "page-2":
+            return result(request, {"tools": TOOLS[1:]})
+        return result(request, {"tools": TOOLS[:1], "nextCursor": "page-2"})
+    if method == "tools/call":
+        if params["name"] == "echo":
+            text = "echo: " + params["arguments"]["text"]
+            return result(request, {"content": [{"type": "text", "text": text}]})
+        if params["name"] == "fail":
+            return result(request, {
+                "content": [{"type": "text", "text": "something went wrong"}],
+                "isError": True,
+            })
+        return error(request, "unknown tool " + params["name"])
+    if method == "resources/list":
+        return result(request, {"resources": [{"uri": "file:///notes.txt", "name": "notes"}]})
+    if method == "resources/read":
+        return result(request, {
+            "contents": [{"uri": params["uri"], "mimeType": "text/plain", "text": "remember the milk"}],
+        })
+    return error(request, "unknown method " + str(method))
+
+
+for line in sys.stdin:
+    message = json.loads(line)
+    # notifications have no id and get no response
+    if "id" not in message:
+        continue
+    # a notification and a log line before each response, which the client must skip
+    print(json.dumps({"jsonrpc": "2.0", "method": "notifications/message", "params": {}}))
+    print("fixture handling " + str(message.get("method")), file=sys.stderr)
+    print(json.dumps(handle(message)), flush=True)